@@ -13,15 +13,20 @@ use async_channel::{Receiver, Sender};
 use async_io_bufpool::pooled_read;
 use async_trait::async_trait;
 
+use dashmap::DashMap;
 use futures_util::{AsyncRead, AsyncReadExt as _, AsyncWrite};
-use geph5_misc_rpc::bridge::{B2eMetadata, BridgeControlProtocol, BridgeControlService};
+use geph5_broker_protocol::Mac;
+use geph5_misc_rpc::bridge::{
+    B2eMetadata, BridgeControlProtocol, BridgeControlService, BridgeHealth, ForwardInfo,
+    ForwardKind,
+};
 use moka::future::Cache;
 use once_cell::sync::Lazy;
 use picomux::{PicoMux, Stream};
 use rand::Rng;
 use sillad::{Pipe, dialer::Dialer, listener::Listener, tcp::TcpListener};
 use smol::future::FutureExt as _;
-use smol::io::AsyncWriteExt;
+use smol::{io::AsyncWriteExt, net::UdpSocket};
 use smol_timeout2::TimeoutExt;
 use stdcode::StdcodeSerializeExt;
 use tap::Tap;
@@ -34,36 +39,149 @@ pub async fn listen_forward_loop(my_ip: IpAddr, listener: impl Listener) -> anyh
     Ok(())
 }
 
-#[allow(clippy::type_complexity)]
+/// What a forward forwards: its kind, where to, and the metadata for the exit.
+type ForwardKey = (ForwardKind, SocketAddr, Mac<B2eMetadata>);
+
+/// All the forwards, keyed by what they forward, forgotten after an hour without being requested again.
+static MAPPING: LazyLock<Cache<ForwardKey, Arc<Forward>>> = LazyLock::new(|| {
+    Cache::builder()
+        .time_to_idle(Duration::from_secs(3600))
+        .build()
+});
+
+struct Forward {
+    listen: SocketAddr,
+    stats: Arc<ForwardStats>,
+    /// Closed on teardown, which ends every connection of the forward. Connections hold clones of the sender, so that merely forgetting the forward leaves them alone.
+    closer: Sender<()>,
+    _task: smol::Task<anyhow::Result<()>>,
+}
+
+#[derive(Default)]
+struct ForwardStats {
+    active: AtomicU64,
+    total: AtomicU64,
+    bytes: AtomicU64,
+}
+
 struct State {
-    // b2e_dest => (metadata, task)
     my_ip: IpAddr,
 }
 
+impl State {
+    async fn forward(
+        &self,
+        kind: ForwardKind,
+        b2e_dest: SocketAddr,
+        metadata: Mac<B2eMetadata>,
+    ) -> SocketAddr {
+        MAPPING
+            .get_with((kind, b2e_dest, metadata.clone()), async {
+                let stats = Arc::new(ForwardStats::default());
+                let (closer, closed) = async_channel::bounded(1);
+                let (listen, task) = match kind {
+                    ForwardKind::Tcp => {
+                        let listener = random_tcp_listener().await;
+                        let listen = listener.local_addr().await;
+                        let task = smolscale::spawn(handle_one_listener(
+                            listener,
+                            b2e_dest,
+                            metadata,
+                            stats.clone(),
+                            (closer.clone(), closed),
+                        ));
+                        (listen, task)
+                    }
+                    ForwardKind::Udp => {
+                        let socket = random_udp_socket().await;
+                        let listen = socket.local_addr().unwrap();
+                        let task = smolscale::spawn(handle_one_udp_socket(
+                            socket,
+                            b2e_dest,
+                            metadata,
+                            stats.clone(),
+                            (closer.clone(), closed),
+                        ));
+                        (listen, task)
+                    }
+                };
+                Arc::new(Forward {
+                    listen: listen.tap_mut(|s| s.set_ip(self.my_ip)),
+                    stats,
+                    closer,
+                    _task: task,
+                })
+            })
+            .await
+            .listen
+    }
+}
+
 #[async_trait]
 impl BridgeControlProtocol for State {
-    async fn tcp_forward(&self, b2e_dest: SocketAddr, metadata: B2eMetadata) -> SocketAddr {
-        static MAPPING: LazyLock<
-            Cache<(SocketAddr, B2eMetadata), (SocketAddr, Arc<smol::Task<anyhow::Result<()>>>)>,
-        > = LazyLock::new(|| {
-            Cache::builder()
-                .time_to_idle(Duration::from_secs(3600))
-                .build()
-        });
+    async fn tcp_forward(&self, b2e_dest: SocketAddr, metadata: Mac<B2eMetadata>) -> SocketAddr {
+        self.forward(ForwardKind::Tcp, b2e_dest, metadata).await
+    }
+
+    async fn udp_forward(&self, b2e_dest: SocketAddr, metadata: Mac<B2eMetadata>) -> SocketAddr {
+        self.forward(ForwardKind::Udp, b2e_dest, metadata).await
+    }
 
+    async fn teardown_forward(&self, listen: SocketAddr) -> bool {
+        let Some((key, forward)) = MAPPING.iter().find(|(_, forward)| forward.listen == listen)
+        else {
+            return false;
+        };
+        MAPPING.invalidate(key.as_ref()).await;
+        forward.closer.close();
+        tracing::debug!(listen = display(listen), "tore down a forward");
+        true
+    }
+
+    async fn list_forwards(&self) -> Vec<ForwardInfo> {
         MAPPING
-            .get_with((b2e_dest, metadata.clone()), async {
-                let listener = random_tcp_listener().await;
-                let addr = listener
-                    .local_addr()
-                    .await
-                    .tap_mut(|s| s.set_ip(self.my_ip));
-                let task = smolscale::spawn(handle_one_listener(listener, b2e_dest, metadata));
-                (addr, Arc::new(task))
+            .iter()
+            .map(|(key, forward)| {
+                let (kind, b2e_dest, metadata) = key.as_ref().clone();
+                ForwardInfo {
+                    kind,
+                    listen: forward.listen,
+                    b2e_dest,
+                    metadata: metadata.inner,
+                    active_connections: forward.stats.active.load(Ordering::Relaxed),
+                    total_connections: forward.stats.total.load(Ordering::Relaxed),
+                    byte_count: forward.stats.bytes.load(Ordering::Relaxed),
+                }
             })
-            .await
-            .0
+            .collect()
     }
+
+    async fn health(&self) -> BridgeHealth {
+        MAPPING.run_pending_tasks().await;
+        BridgeHealth {
+            load: load_average().unwrap_or_else(|err| {
+                tracing::warn!(err = debug(err), "cannot read load average");
+                0.0
+            }),
+            listener_count: MAPPING.entry_count(),
+            active_connections: MAPPING
+                .iter()
+                .map(|(_, forward)| forward.stats.active.load(Ordering::Relaxed))
+                .sum(),
+            byte_count: BYTE_COUNT.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The one-minute load average, normalized by the number of CPUs.
+fn load_average() -> anyhow::Result<f64> {
+    let loadavg = std::fs::read_to_string("/proc/loadavg")?;
+    let one_minute: f64 = loadavg
+        .split_whitespace()
+        .next()
+        .context("empty loadavg")?
+        .parse()?;
+    Ok(one_minute / std::thread::available_parallelism()?.get() as f64)
 }
 
 async fn random_tcp_listener() -> TcpListener {
@@ -79,10 +197,25 @@ async fn random_tcp_listener() -> TcpListener {
     }
 }
 
+async fn random_udp_socket() -> UdpSocket {
+    loop {
+        let rando = rand::rng().random_range(2048u16..65535);
+        match UdpSocket::bind(("0.0.0.0", rando)).await {
+            Ok(socket) => return socket,
+            Err(err) => {
+                smol::Timer::after(Duration::from_millis(100)).await;
+                tracing::warn!(rando, err = debug(err), "retrying a UDP bind...")
+            }
+        }
+    }
+}
+
 async fn handle_one_listener(
     mut listener: impl Listener,
     b2e_dest: SocketAddr,
-    metadata: B2eMetadata,
+    metadata: Mac<B2eMetadata>,
+    stats: Arc<ForwardStats>,
+    (closer, closed): (Sender<()>, Receiver<()>),
 ) -> anyhow::Result<()> {
     static COUNT: AtomicUsize = AtomicUsize::new(0);
 
@@ -101,8 +234,15 @@ async fn handle_one_listener(
             "handled a connection"
         );
         let metadata = metadata.clone();
+        let stats = stats.clone();
+        let closer = closer.clone();
+        let closed = closed.clone();
+        stats.total.fetch_add(1, Ordering::Relaxed);
+        stats.active.fetch_add(1, Ordering::Relaxed);
         smolscale::spawn(async move {
+            let _closer = closer;
             scopeguard::defer!({
+                stats.active.fetch_sub(1, Ordering::Relaxed);
                 let count = COUNT.fetch_sub(1, Ordering::Relaxed);
                 tracing::debug!(
                     count,
@@ -120,14 +260,20 @@ async fn handle_one_listener(
                 exit_read,
                 client_write,
                 remote_asn,
+                &stats.bytes,
                 Duration::from_secs(1800),
             )
             .race(io_copy_with_timeout(
                 client_read,
                 exit_write,
                 remote_asn,
+                &stats.bytes,
                 Duration::from_secs(1800),
             ))
+            .race(async {
+                let _ = closed.recv().await;
+                Ok(())
+            })
             .await?;
             anyhow::Ok(())
         })
@@ -135,11 +281,106 @@ async fn handle_one_listener(
     }
 }
 
+/// Relays the datagrams from every source over a b2e stream of its own, prefixing each datagram with its length.
+async fn handle_one_udp_socket(
+    socket: UdpSocket,
+    b2e_dest: SocketAddr,
+    metadata: Mac<B2eMetadata>,
+    stats: Arc<ForwardStats>,
+    (closer, closed): (Sender<()>, Receiver<()>),
+) -> anyhow::Result<()> {
+    let sessions: Arc<DashMap<SocketAddr, Sender<Vec<u8>>>> = Default::default();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (n, src) = socket.recv_from(&mut buf).await?;
+        let datagram = buf[..n].to_vec();
+        if let Some(session) = sessions.get(&src) {
+            // like any other hop, drop datagrams instead of queueing them without bound
+            let _ = session.try_send(datagram);
+            continue;
+        }
+        let (send, recv) = async_channel::bounded(100);
+        let _ = send.try_send(datagram);
+        sessions.insert(src, send);
+
+        let socket = socket.clone();
+        let metadata = metadata.clone();
+        let stats = stats.clone();
+        let sessions = sessions.clone();
+        let closer = closer.clone();
+        let closed = closed.clone();
+        stats.total.fetch_add(1, Ordering::Relaxed);
+        stats.active.fetch_add(1, Ordering::Relaxed);
+        smolscale::spawn(async move {
+            let _closer = closer;
+            scopeguard::defer!({
+                stats.active.fetch_sub(1, Ordering::Relaxed);
+                sessions.remove(&src);
+                tracing::debug!(
+                    src = display(src),
+                    b2e_dest = debug(b2e_dest),
+                    "closing a UDP session"
+                );
+            });
+            let remote_asn = asn_count::ip_to_asn(src.ip()).await?;
+            let exit_conn = dial_pooled(b2e_dest, &metadata.stdcode())
+                .await
+                .inspect_err(|e| tracing::warn!("cannot dial pooled: {:?}", e))?;
+            let (exit_read, mut exit_write) = exit_conn.split();
+            let upload = async {
+                while let Some(Ok(datagram)) = recv.recv().timeout(UDP_SESSION_TIMEOUT).await {
+                    exit_write
+                        .write_all(&(datagram.len() as u16).to_be_bytes())
+                        .await?;
+                    exit_write.write_all(&datagram).await?;
+                    count_bytes(remote_asn, &stats.bytes, datagram.len());
+                }
+                anyhow::Ok(())
+            };
+            let download = udp_download(exit_read, &socket, src, remote_asn, &stats.bytes);
+            upload
+                .race(download)
+                .race(async {
+                    let _ = closed.recv().await;
+                    Ok(())
+                })
+                .await
+        })
+        .detach();
+    }
+}
+
+/// Sends the datagrams that come back from the exit to the source.
+async fn udp_download(
+    mut exit_read: impl AsyncRead + Unpin,
+    socket: &UdpSocket,
+    src: SocketAddr,
+    asn: u32,
+    forward_bytes: &AtomicU64,
+) -> anyhow::Result<()> {
+    loop {
+        let mut len = [0u8; 2];
+        exit_read
+            .read_exact(&mut len)
+            .timeout(UDP_SESSION_TIMEOUT)
+            .await
+            .context("UDP session timed out")??;
+        let mut datagram = vec![0u8; u16::from_be_bytes(len) as usize];
+        exit_read.read_exact(&mut datagram).await?;
+        socket.send_to(&datagram, src).await?;
+        count_bytes(asn, forward_bytes, datagram.len());
+    }
+}
+
+/// How long a UDP source can stay quiet before its session is closed.
+const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(600);
+
 /// Copies data between a reader and a writer with a timeout.
 pub async fn io_copy_with_timeout<R, W>(
     mut reader: R,
     mut writer: W,
     asn: u32,
+    forward_bytes: &AtomicU64,
     timeout: Duration,
 ) -> std::io::Result<()>
 where
@@ -153,8 +394,7 @@ where
                     return Ok(());
                 }
                 writer.write_all(&buf).await?;
-                BYTE_COUNT.fetch_add(buf.len() as u64, Ordering::Relaxed);
-                incr_bytes_asn(asn, buf.len() as u64);
+                count_bytes(asn, forward_bytes, buf.len());
             }
             Some(Err(err)) => return Err(err),
             None => return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timeout")),
@@ -162,6 +402,13 @@ where
     }
 }
 
+fn count_bytes(asn: u32, forward_bytes: &AtomicU64, n: usize) {
+    BYTE_COUNT.fetch_add(n as u64, Ordering::Relaxed);
+    forward_bytes.fetch_add(n as u64, Ordering::Relaxed);
+    incr_bytes_asn(asn, n as u64);
+}
+
+/// Bytes relayed since the bridge started.
 pub static BYTE_COUNT: AtomicU64 = AtomicU64::new(0);
 
 async fn dial_pooled(b2e_dest: SocketAddr, metadata: &[u8]) -> anyhow::Result<picomux::Stream> {
@@ -121,7 +121,7 @@ async fn broker_loop(control_listen: SocketAddr, control_cookie: String) {
     let bridge_key = format!("bridges.{pool}");
 
     let broker_rpc = Arc::new(geph5_broker_protocol::BrokerClient(
-        nanorpc_sillad::DialerTransport(
+        nanorpc_sillad::PooledTransport::new(
             TcpDialer {
                 dest_addr: broker_addr,
             }
@@ -166,15 +166,18 @@ async fn broker_loop(control_listen: SocketAddr, control_cookie: String) {
     };
 
     let stats_loop = async {
+        let mut last_byte_count = 0;
         loop {
             tracing::info!(auth_token, broker_addr = display(broker_addr), "stats...");
             let res = async {
-                let byte_count = BYTE_COUNT.swap(0, std::sync::atomic::Ordering::Relaxed);
+                let total_byte_count = BYTE_COUNT.load(std::sync::atomic::Ordering::Relaxed);
+                let byte_count = total_byte_count - last_byte_count;
                 broker_rpc
                     .incr_stat(format!("{bridge_key}.byte_count"), byte_count as _)
                     .timeout(Duration::from_secs(2))
                     .await
                     .context("incrementing bytes timed out")??;
+                last_byte_count = total_byte_count;
 
                 let asn_bytes: Vec<(u32, u64)> = ASN_BYTES
                     .iter()
@@ -1,6 +1,6 @@
 use argon2::{Argon2, PasswordHash, PasswordVerifier, password_hash::Encoding};
 
-use cached::proc_macro::cached;
+use cached::{Cached as _, proc_macro::cached};
 use geph5_broker_protocol::{AccountLevel, AuthError, Credential, UserInfo};
 
 use std::{
@@ -14,7 +14,7 @@ use moka::future::Cache;
 use rand::Rng as _;
 use sqlx::types::chrono::Utc;
 
-use crate::{database::POSTGRES, log_error};
+use crate::{CONFIG_FILE, database::POSTGRES, devices::validate_device, log_error};
 
 pub async fn register_secret(user_id: Option<i32>) -> anyhow::Result<String> {
     let mut txn = POSTGRES.begin().await?;
@@ -45,10 +45,7 @@ pub async fn register_secret(user_id: Option<i32>) -> anyhow::Result<String> {
         txn.commit().await?;
         Ok(secret)
     } else {
-        let secret = (0..23)
-            .map(|_| rand::rng().random_range(0..9))
-            .fold(String::new(), |a, b| format!("{a}{b}"));
-        let secret = format!("9{secret}");
+        let secret = generate_secret();
 
         sqlx::query(
             r#"
@@ -66,10 +63,110 @@ pub async fn register_secret(user_id: Option<i32>) -> anyhow::Result<String> {
     }
 }
 
-#[cached(time = 60, result = true, sync_writes = true)]
 pub async fn validate_credential(credential: Credential) -> Result<i32, AuthError> {
     match credential {
-        Credential::TestDummy => Err(AuthError::Forbidden),
+        // device challenges work only once, so they must never hit the cache
+        Credential::Device(proof) => validate_device(&proof).await,
+        credential => validate_bearer_credential(credential).await,
+    }
+}
+
+fn generate_secret() -> String {
+    let secret = (0..23)
+        .map(|_| rand::rng().random_range(0..9))
+        .fold(String::new(), |a, b| format!("{a}{b}"));
+    format!("9{secret}")
+}
+
+/// Replaces the secret of a user with a new one, logging out everything that used the old secret. Other broker instances may keep accepting the old secret until their cached validation results expire.
+pub async fn regenerate_secret(user_id: i32) -> anyhow::Result<String> {
+    let mut txn = POSTGRES.begin().await?;
+    let old_secret: Option<(String,)> =
+        sqlx::query_as("SELECT secret FROM auth_secret WHERE id = $1 FOR UPDATE")
+            .bind(user_id)
+            .fetch_optional(&mut *txn)
+            .await?;
+    let secret = generate_secret();
+    sqlx::query(
+        r#"
+        INSERT INTO auth_secret (id, secret)
+        VALUES ($1, $2)
+        ON CONFLICT (id)
+        DO UPDATE SET secret = EXCLUDED.secret
+        "#,
+    )
+    .bind(user_id)
+    .bind(&secret)
+    .execute(&mut *txn)
+    .await?;
+    sqlx::query("DELETE FROM auth_tokens WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *txn)
+        .await?;
+    txn.commit().await?;
+
+    if let Some((old_secret,)) = old_secret {
+        forget_credential(&Credential::Secret(old_secret)).await;
+    }
+    Ok(secret)
+}
+
+/// Deletes a user along with everything that refers to them, including their subscription. The credential used to authorize the deletion stops working right away.
+pub async fn delete_account(user_id: i32, credential: &Credential) -> anyhow::Result<()> {
+    let mut txn = POSTGRES.begin().await?;
+    let old_secret: Option<(String,)> =
+        sqlx::query_as("SELECT secret FROM auth_secret WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(&mut *txn)
+            .await?;
+    let auth_tokens: Vec<(String,)> =
+        sqlx::query_as("SELECT token FROM auth_tokens WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_all(&mut *txn)
+            .await?;
+    // the device and login tables are created at startup, but user_tiers only exists on brokers with custom tiers
+    let mut queries = vec![
+        "DELETE FROM auth_tokens WHERE user_id = $1",
+        "DELETE FROM auth_password WHERE user_id = $1",
+        "DELETE FROM device_keys WHERE user_id = $1",
+        "DELETE FROM login_history WHERE user_id = $1",
+        "DELETE FROM auth_secret WHERE id = $1",
+        "DELETE FROM last_login WHERE id = $1",
+    ];
+    if !CONFIG_FILE.wait().tiers.is_empty() {
+        queries.push("DELETE FROM user_tiers WHERE id = $1");
+    }
+    queries.push("DELETE FROM subscriptions WHERE id = $1");
+    queries.push("DELETE FROM users WHERE id = $1");
+    for query in queries {
+        sqlx::query(query).bind(user_id).execute(&mut *txn).await?;
+    }
+    txn.commit().await?;
+    tracing::info!(user_id, "deleted account");
+
+    forget_credential(credential).await;
+    if let Some((old_secret,)) = old_secret {
+        forget_credential(&Credential::Secret(old_secret)).await;
+    }
+    let mut valid_tokens = VALID_AUTH_TOKEN.lock().await;
+    for (token,) in auth_tokens {
+        valid_tokens.cache_remove(&token);
+    }
+    Ok(())
+}
+
+/// Drops a credential from the validation cache, so that changes to it take effect right away.
+async fn forget_credential(credential: &Credential) {
+    VALIDATE_BEARER_CREDENTIAL
+        .lock()
+        .await
+        .cache_remove(credential);
+}
+
+#[cached(time = 60, result = true, sync_writes = true)]
+async fn validate_bearer_credential(credential: Credential) -> Result<i32, AuthError> {
+    match credential {
+        Credential::TestDummy | Credential::Device(_) => Err(AuthError::Forbidden),
         Credential::LegacyUsernamePassword { username, password } => {
             Ok(validate_username_pwd(&username, &password).await?)
         }
@@ -151,7 +248,9 @@ pub async fn valid_auth_token(token: String) -> anyhow::Result<Option<(i32, Acco
         let expiry = get_subscription_expiry(user_id).await?;
         tracing::trace!(user_id, expiry = debug(expiry), "valid auth token");
         record_auth(user_id).await?;
-        if expiry.is_none() {
+        if let Some(tier) = get_user_tier(user_id).await? {
+            Ok(Some((user_id, AccountLevel::Tier(tier))))
+        } else if expiry.is_none() {
             Ok(Some((user_id, AccountLevel::Free)))
         } else {
             Ok(Some((user_id, AccountLevel::Plus)))
@@ -167,13 +266,52 @@ pub async fn get_user_info(user_id: i32) -> Result<Option<UserInfo>, AuthError>
         .await
         .map_err(|_| AuthError::RateLimited)?
         .map(|u| u as u64);
+    let tier = get_user_tier(user_id)
+        .await
+        .map_err(|_| AuthError::RateLimited)?;
 
     Ok(Some(UserInfo {
         user_id: user_id as _,
         plus_expires_unix,
+        tier,
     }))
 }
 
+/// Gets the custom tier of a user, if any. Assignments to tiers that are not configured are ignored, and the `user_tiers` table is only consulted when custom tiers are configured. If it cannot be read, users fall back to their standard level.
+pub async fn get_user_tier(user_id: i32) -> anyhow::Result<Option<String>> {
+    static ALL_TIERS_CACHE: LazyLock<Cache<(), Arc<BTreeMap<i32, String>>>> = LazyLock::new(|| {
+        Cache::builder()
+            .time_to_live(Duration::from_secs(30))
+            .build()
+    });
+
+    let tiers = &CONFIG_FILE.wait().tiers;
+    if tiers.is_empty() {
+        return Ok(None);
+    }
+    let all_tiers = ALL_TIERS_CACHE
+        .try_get_with((), async {
+            let all_tiers: Vec<(i32, String)> = sqlx::query_as("SELECT id, tier FROM user_tiers")
+                .fetch_all(POSTGRES.deref())
+                .await?;
+            anyhow::Ok(Arc::new(all_tiers.into_iter().collect()))
+        })
+        .await;
+    // a broken tiers table should not lock everyone out, so users get their standard level instead
+    let all_tiers = match all_tiers {
+        Ok(all_tiers) => all_tiers,
+        Err(err) => {
+            tracing::error!(err = debug(err), "could not read user tiers");
+            return Ok(None);
+        }
+    };
+
+    Ok(all_tiers
+        .get(&user_id)
+        .filter(|tier| tiers.iter().any(|t| t.name == **tier))
+        .cloned())
+}
+
 pub async fn get_subscription_expiry(user_id: i32) -> anyhow::Result<Option<i64>> {
     static ALL_SUBSCRIPTIONS_CACHE: LazyLock<Cache<(), Arc<BTreeMap<i32, i64>>>> =
         LazyLock::new(|| {
@@ -197,6 +335,32 @@ pub async fn get_subscription_expiry(user_id: i32) -> anyhow::Result<Option<i64>
     Ok(all_subscriptions.get(&user_id).cloned())
 }
 
+/// Records that a user logged in, in the `login_history (user_id INTEGER NOT NULL, login_time TIMESTAMP NOT NULL)` table.
+pub async fn record_login(user_id: i32) -> anyhow::Result<()> {
+    sqlx::query("INSERT INTO login_history (user_id, login_time) VALUES ($1, NOW())")
+        .bind(user_id)
+        .execute(POSTGRES.deref())
+        .await?;
+    Ok(())
+}
+
+/// Gets the times of the most recent logins of a user, newest first.
+pub async fn get_login_history(user_id: i32) -> anyhow::Result<Vec<u64>> {
+    let history: Vec<(i64,)> = sqlx::query_as(
+        r#"
+        SELECT EXTRACT(EPOCH FROM login_time)::bigint
+        FROM login_history
+        WHERE user_id = $1
+        ORDER BY login_time DESC
+        LIMIT 100
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(POSTGRES.deref())
+    .await?;
+    Ok(history.into_iter().map(|(t,)| t as u64).collect())
+}
+
 pub async fn record_auth(user_id: i32) -> anyhow::Result<()> {
     let now = Utc::now().naive_utc();
 
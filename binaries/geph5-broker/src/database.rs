@@ -34,6 +34,23 @@ pub static POSTGRES: LazyLock<PgPool> = LazyLock::new(|| {
     .unwrap()
 });
 
+/// Tables that newer brokers need on top of the tables they have always used. Brokers create them at startup, so that existing deployments keep working without migrating by hand.
+const NEWER_TABLES: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS device_keys (public_key BYTEA PRIMARY KEY, user_id INTEGER NOT NULL, name TEXT NOT NULL, enrolled TIMESTAMP NOT NULL, last_used TIMESTAMP)",
+    "CREATE INDEX IF NOT EXISTS device_keys_user_id ON device_keys (user_id)",
+    "CREATE TABLE IF NOT EXISTS used_device_challenges (challenge TEXT PRIMARY KEY, used TIMESTAMP NOT NULL)",
+    "CREATE TABLE IF NOT EXISTS login_history (user_id INTEGER NOT NULL, login_time TIMESTAMP NOT NULL)",
+    "CREATE INDEX IF NOT EXISTS login_history_user_id ON login_history (user_id, login_time)",
+];
+
+/// Creates the tables in [NEWER_TABLES] that do not exist yet.
+pub async fn create_newer_tables() -> anyhow::Result<()> {
+    for statement in NEWER_TABLES {
+        sqlx::query(statement).execute(POSTGRES.deref()).await?;
+    }
+    Ok(())
+}
+
 /// This loop is used for garbage-collecting stale data from the database.
 #[tracing::instrument]
 pub async fn database_gc_loop() -> anyhow::Result<()> {
@@ -50,6 +67,24 @@ pub async fn database_gc_loop() -> anyhow::Result<()> {
             .execute(POSTGRES.deref())
             .await?;
         tracing::debug!(rows_affected = res.rows_affected(), "cleaned up bridges");
+        // used challenges only need to be remembered until they expire anyway
+        let res = sqlx::query(
+            "delete from used_device_challenges where used < now() - interval '1 hour'",
+        )
+        .execute(POSTGRES.deref())
+        .await?;
+        tracing::debug!(
+            rows_affected = res.rows_affected(),
+            "cleaned up device challenges"
+        );
+        let res =
+            sqlx::query("delete from login_history where login_time < now() - interval '90 days'")
+                .execute(POSTGRES.deref())
+                .await?;
+        tracing::debug!(
+            rows_affected = res.rows_affected(),
+            "cleaned up login history"
+        );
     }
 }
 
@@ -62,19 +97,23 @@ pub struct ExitRow {
     pub city: String,
     pub load: f32,
     pub expiry: i64,
+    /// The JSON-encoded exit metadata, stored in a `metadata TEXT NOT NULL DEFAULT '{}'` column.
+    #[sqlx(default)]
+    pub metadata: String,
 }
 
 pub async fn insert_exit(exit: &ExitRow) -> anyhow::Result<()> {
     sqlx::query(
-        r"INSERT INTO exits_new (pubkey, c2e_listen, b2e_listen, country, city, load, expiry)
-        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        r"INSERT INTO exits_new (pubkey, c2e_listen, b2e_listen, country, city, load, expiry, metadata)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
         ON CONFLICT (pubkey) DO UPDATE 
         SET c2e_listen = EXCLUDED.c2e_listen, 
             b2e_listen = EXCLUDED.b2e_listen, 
             country = EXCLUDED.country, 
             city = EXCLUDED.city, 
             load = EXCLUDED.load, 
-            expiry = EXCLUDED.expiry
+            expiry = EXCLUDED.expiry,
+            metadata = EXCLUDED.metadata
         ",
     )
     .bind(exit.pubkey)
@@ -84,6 +123,7 @@ pub async fn insert_exit(exit: &ExitRow) -> anyhow::Result<()> {
     .bind(&exit.city)
     .bind(exit.load)
     .bind(exit.expiry)
+    .bind(&exit.metadata)
     .execute(POSTGRES.deref())
     .await?;
     Ok(())
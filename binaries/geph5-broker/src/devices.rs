@@ -0,0 +1,157 @@
+use std::{
+    ops::Deref as _,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ed25519_dalek::VerifyingKey;
+use geph5_broker_protocol::{AuthError, DeviceInfo, DeviceProof};
+use once_cell::sync::Lazy;
+use rand::RngCore;
+
+use crate::{MASTER_SECRET, database::POSTGRES, log_error};
+
+/// How long a device challenge stays valid, in seconds.
+const CHALLENGE_LIFETIME: u64 = 300;
+
+/// The key that authenticates device challenges, so that the broker can check them without remembering every challenge it issued.
+static CHALLENGE_KEY: Lazy<[u8; 32]> =
+    Lazy::new(|| blake3::derive_key("geph5-broker device challenge", MASTER_SECRET.as_bytes()));
+
+/// Issues a new challenge, which has the form `<unix time>.<nonce>.<MAC>`.
+pub fn new_device_challenge() -> String {
+    let mut nonce = [0u8; 16];
+    rand::rng().fill_bytes(&mut nonce);
+    let body = format!("{}.{}", unix_now(), hex::encode(nonce));
+    let mac = blake3::keyed_hash(&CHALLENGE_KEY, body.as_bytes());
+    format!("{body}.{}", mac.to_hex())
+}
+
+/// Checks a device proof, consuming its challenge by recording it in the `used_device_challenges (challenge TEXT PRIMARY KEY, used TIMESTAMP NOT NULL)` table. Returns the public key of the device, which may or may not be enrolled.
+async fn consume_proof(proof: &DeviceProof) -> Result<VerifyingKey, AuthError> {
+    let (body, mac) = proof
+        .challenge
+        .rsplit_once('.')
+        .ok_or(AuthError::Forbidden)?;
+    if blake3::keyed_hash(&CHALLENGE_KEY, body.as_bytes())
+        .to_hex()
+        .as_str()
+        != mac
+    {
+        return Err(AuthError::Forbidden);
+    }
+    let issued: u64 = body
+        .split_once('.')
+        .and_then(|(issued, _)| issued.parse().ok())
+        .ok_or(AuthError::Forbidden)?;
+    if unix_now().saturating_sub(issued) > CHALLENGE_LIFETIME || !proof.verify() {
+        return Err(AuthError::Forbidden);
+    }
+
+    // deduplicate on insert, so that every challenge works only once
+    sqlx::query("INSERT INTO used_device_challenges (challenge, used) VALUES ($1, NOW())")
+        .bind(&proof.challenge)
+        .execute(POSTGRES.deref())
+        .await
+        .map_err(|e| {
+            if e.as_database_error()
+                .is_some_and(|e| e.is_unique_violation())
+            {
+                AuthError::Forbidden
+            } else {
+                log_error(&e);
+                AuthError::RateLimited
+            }
+        })?;
+    Ok(proof.public_key)
+}
+
+/// Validates a device credential, returning the user it is enrolled to. Devices live in the `device_keys (public_key BYTEA PRIMARY KEY, user_id INTEGER NOT NULL, name TEXT NOT NULL, enrolled TIMESTAMP NOT NULL, last_used TIMESTAMP)` table.
+pub async fn validate_device(proof: &DeviceProof) -> Result<i32, AuthError> {
+    let public_key = consume_proof(proof).await?;
+    tracing::debug!(
+        public_key = hex::encode(public_key.as_bytes()),
+        "validating device"
+    );
+    let res: Option<(i32,)> = sqlx::query_as(
+        "UPDATE device_keys SET last_used = NOW() WHERE public_key = $1 RETURNING user_id",
+    )
+    .bind(public_key.as_bytes().as_slice())
+    .fetch_optional(POSTGRES.deref())
+    .await
+    .inspect_err(log_error)
+    .map_err(|_| AuthError::RateLimited)?;
+    res.map(|(user_id,)| user_id).ok_or(AuthError::Forbidden)
+}
+
+/// Enrolls a device to a user. Enrolling a device again just renames it, but a device enrolled to somebody else is refused.
+pub async fn enroll_device(user_id: i32, name: &str, proof: &DeviceProof) -> Result<(), AuthError> {
+    let public_key = consume_proof(proof).await?;
+    let res = sqlx::query(
+        r#"
+        INSERT INTO device_keys (public_key, user_id, name, enrolled)
+        VALUES ($1, $2, $3, NOW())
+        ON CONFLICT (public_key)
+        DO UPDATE SET name = EXCLUDED.name
+        WHERE device_keys.user_id = EXCLUDED.user_id
+        "#,
+    )
+    .bind(public_key.as_bytes().as_slice())
+    .bind(user_id)
+    .bind(name)
+    .execute(POSTGRES.deref())
+    .await
+    .inspect_err(log_error)
+    .map_err(|_| AuthError::RateLimited)?;
+    if res.rows_affected() == 0 {
+        return Err(AuthError::Forbidden);
+    }
+    Ok(())
+}
+
+pub async fn list_devices(user_id: i32) -> Result<Vec<DeviceInfo>, AuthError> {
+    let rows: Vec<(Vec<u8>, String, i64, Option<i64>)> = sqlx::query_as(
+        r#"
+        SELECT public_key, name,
+            EXTRACT(EPOCH FROM enrolled)::bigint,
+            EXTRACT(EPOCH FROM last_used)::bigint
+        FROM device_keys
+        WHERE user_id = $1
+        ORDER BY enrolled
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(POSTGRES.deref())
+    .await
+    .inspect_err(log_error)
+    .map_err(|_| AuthError::RateLimited)?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|(public_key, name, enrolled, last_used)| {
+            Some(DeviceInfo {
+                public_key: VerifyingKey::from_bytes(&public_key.try_into().ok()?).ok()?,
+                name,
+                enrolled_unix: enrolled as u64,
+                last_used_unix: last_used.map(|t| t as u64),
+            })
+        })
+        .collect())
+}
+
+/// Revokes a device of a user. Revoking a device that is not enrolled to the user does nothing.
+pub async fn revoke_device(user_id: i32, public_key: &VerifyingKey) -> Result<(), AuthError> {
+    sqlx::query("DELETE FROM device_keys WHERE public_key = $1 AND user_id = $2")
+        .bind(public_key.as_bytes().as_slice())
+        .bind(user_id)
+        .execute(POSTGRES.deref())
+        .await
+        .inspect_err(log_error)
+        .map_err(|_| AuthError::RateLimited)?;
+    Ok(())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
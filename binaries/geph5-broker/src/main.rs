@@ -1,8 +1,10 @@
 use anyhow::Context;
-use axum::{Json, Router, routing::post};
+use axum::{Json, Router, extract::ConnectInfo, http::HeaderMap, routing::post};
 use clap::Parser;
 use database::database_gc_loop;
 use ed25519_dalek::SigningKey;
+use geph5_broker_protocol::AccountLevel;
+use isocountry::CountryCode;
 
 use nanorpc::{JrpcRequest, JrpcResponse, RpcService};
 use once_cell::sync::{Lazy, OnceCell};
@@ -11,11 +13,15 @@ use rpc_impl::WrappedBrokerService;
 use self_stat::self_stat_loop;
 use serde::Deserialize;
 use smolscale::immortal::{Immortal, RespawnStrategy};
-use std::{fmt::Debug, fs, net::SocketAddr, path::PathBuf, sync::LazyLock};
+use std::{
+    collections::HashMap, fmt::Debug, fs, net::SocketAddr, path::PathBuf, sync::LazyLock,
+    time::Duration,
+};
 use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
 
 mod auth;
 mod database;
+mod devices;
 mod news;
 mod payments;
 mod puzzle;
@@ -56,21 +62,50 @@ static FREE_MIZARU_SK: Lazy<mizaru2::SecretKey> = Lazy::new(|| {
     mizaru
 });
 
+/// The mizaru SKs of the custom tiers, by tier name.
+static TIER_MIZARU_SKS: Lazy<HashMap<String, mizaru2::SecretKey>> = Lazy::new(|| {
+    CONFIG_FILE
+        .wait()
+        .tiers
+        .iter()
+        .map(|tier| {
+            let mizaru = load_mizaru_sk(&format!("{}.bin", tier.name));
+            let pk = mizaru.to_public_key().to_bytes();
+            tracing::info!("*** {} Mizaru PK = {} ***", tier.name, hex::encode(pk));
+            (tier.name.clone(), mizaru)
+        })
+        .collect()
+});
+
+/// Gets the mizaru SK of an account level, if the level exists.
+fn mizaru_sk(level: &AccountLevel) -> Option<&'static mizaru2::SecretKey> {
+    match level {
+        AccountLevel::Free => Some(&*FREE_MIZARU_SK),
+        AccountLevel::Plus => Some(&*PLUS_MIZARU_SK),
+        AccountLevel::Tier(name) => TIER_MIZARU_SKS.get(name),
+    }
+}
+
 fn load_mizaru_sk(name: &str) -> mizaru2::SecretKey {
     let mizaru_keys_dir = &CONFIG_FILE.wait().mizaru_keys;
     let plus_file_path = mizaru_keys_dir.join(name);
 
     if plus_file_path.exists() {
-        // If the file exists, read it
+        // If the file exists, read it. Both legacy keys that store every RSA key and seeded keys are accepted, so existing key files keep working unchanged.
         let file_content = fs::read(&plus_file_path).unwrap();
-        stdcode::deserialize(&file_content).unwrap()
+        let key = mizaru2::SecretKey::from_bytes(&file_content).unwrap();
+        if !key.is_seeded() {
+            // a legacy key cannot become seeded without changing its public key, which every exit and client would then reject, so it stays in use as it is
+            tracing::info!(name, "loaded a legacy mizaru key");
+        }
+        key
     } else {
-        // If the file doesn't exist, generate a new secret key and write it to the file
-        let new_key = mizaru2::SecretKey::generate(name);
+        // If the file doesn't exist, generate a new seeded secret key and write it to the file
+        let new_key = mizaru2::SecretKey::generate_seeded(name);
         if let Some(parent) = plus_file_path.parent() {
             fs::create_dir_all(parent).unwrap();
         }
-        fs::write(&plus_file_path, stdcode::serialize(&new_key).unwrap()).unwrap();
+        fs::write(&plus_file_path, new_key.to_bytes()).unwrap();
         new_key
     }
 }
@@ -89,9 +124,26 @@ struct ConfigFile {
     bridge_token: String,
     exit_token: String,
 
+    /// The registration puzzle difficulty when registrations are at their usual rate.
     #[serde(default = "default_puzzle_difficulty")]
     puzzle_difficulty: u16,
 
+    /// The most that the puzzle difficulty may grow to when registrations spike.
+    #[serde(default = "default_puzzle_max_difficulty")]
+    puzzle_max_difficulty: u16,
+
+    /// How many registrations per hour, across all sources, are usual. Past this, puzzles get harder.
+    #[serde(default = "default_puzzle_registrations_per_hour")]
+    puzzle_registrations_per_hour: u64,
+
+    /// How many recent registrations from a single source network are usual. Past this, puzzles for that network get harder.
+    #[serde(default = "default_puzzle_registrations_per_source")]
+    puzzle_registrations_per_source: u64,
+
+    /// A header that carries the real client address, like `X-Forwarded-For`, when the broker sits behind a trusted proxy. The address that the proxy appended last is used.
+    #[serde(default)]
+    source_ip_header: Option<String>,
+
     #[serde(default)]
     statsd_addr: Option<SocketAddr>,
 
@@ -102,12 +154,41 @@ struct ConfigFile {
 
     #[serde(default)]
     payment_support_secret: String,
+
+    /// Custom account tiers beyond Free and Plus. Users are assigned to them through the `user_tiers` table.
+    #[serde(default)]
+    tiers: Vec<TierConfig>,
+}
+
+/// A custom account tier.
+#[derive(Deserialize)]
+struct TierConfig {
+    /// The name of the tier. Its mizaru key is kept as `<name>.bin` in the mizaru key directory.
+    name: String,
+    /// The countries whose exits users of this tier may use. Absent means every exit.
+    #[serde(default)]
+    exit_countries: Option<Vec<CountryCode>>,
+    /// Whether users of this tier may use bridges reserved for Plus users.
+    #[serde(default)]
+    plus_bridges: bool,
 }
 
 fn default_puzzle_difficulty() -> u16 {
     24
 }
 
+fn default_puzzle_max_difficulty() -> u16 {
+    30
+}
+
+fn default_puzzle_registrations_per_hour() -> u64 {
+    1000
+}
+
+fn default_puzzle_registrations_per_source() -> u64 {
+    5
+}
+
 fn default_payment_service() -> String {
     "https://web-backend.geph.io/rpc".to_string()
 }
@@ -145,27 +226,51 @@ async fn main() -> anyhow::Result<()> {
 
     Lazy::force(&PLUS_MIZARU_SK);
     Lazy::force(&FREE_MIZARU_SK);
+    Lazy::force(&TIER_MIZARU_SKS);
     LazyLock::force(&database::POSTGRES);
+    // without the privilege to create tables, they have to be created by hand as in database.rs
+    if let Err(err) = database::create_newer_tables().await {
+        tracing::error!(err = debug(err), "could not create newer database tables");
+    }
 
     let _gc_loop = Immortal::respawn(RespawnStrategy::Immediate, database_gc_loop);
     let _self_stat_loop = Immortal::respawn(RespawnStrategy::Immediate, self_stat_loop);
     let _tcp_loop = Immortal::respawn(RespawnStrategy::Immediate, || async {
-        nanorpc_sillad::rpc_serve(
-            sillad::tcp::TcpListener::bind(CONFIG_FILE.wait().tcp_listen).await?,
-            WrappedBrokerService::new(),
-        )
-        .await?;
+        nanorpc_sillad::RpcServer::new(WrappedBrokerService::new(None))
+            .idle_timeout(Duration::from_secs(300))
+            .max_connections(10000)
+            .serve(sillad::tcp::TcpListener::bind(CONFIG_FILE.wait().tcp_listen).await?)
+            .await?;
         anyhow::Ok(())
     });
 
     let listener = tokio::net::TcpListener::bind(CONFIG_FILE.wait().listen).await?;
     let app = Router::new().route("/", post(rpc));
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
     Ok(())
 }
 
-async fn rpc(Json(payload): Json<JrpcRequest>) -> Json<JrpcResponse> {
-    Json(WrappedBrokerService::new().respond_raw(payload).await)
+async fn rpc(
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<JrpcRequest>,
+) -> Json<JrpcResponse> {
+    let source = match &CONFIG_FILE.wait().source_ip_header {
+        // clients can put anything in the header, so only the entry that the proxy appended last is trusted
+        Some(header) => headers
+            .get_all(header)
+            .iter()
+            .next_back()
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .and_then(|value| value.trim().parse().ok()),
+        None => Some(peer.ip()),
+    };
+    Json(WrappedBrokerService::new(source).respond_raw(payload).await)
 }
 
 fn log_error(e: &impl Debug) {
@@ -1,19 +1,110 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    ops::Deref as _,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context as _;
+use moka::{Expiry, future::Cache};
 use rand::RngCore;
 
-use crate::{CONFIG_FILE, database::POSTGRES};
+use crate::{CONFIG_FILE, MASTER_SECRET, database::POSTGRES, log_error};
+
+/// How long an issued puzzle stays solvable, in seconds. This is long enough for a slow device at the maximum difficulty, but short enough that puzzles hoarded while things are quiet are of little use once a registration flood raises the difficulty.
+const PUZZLE_LIFETIME: u64 = 3600;
+
+/// The key that binds the issue time and difficulty into puzzles, so that clients cannot pick an easier difficulty.
+static PUZZLE_KEY: LazyLock<[u8; 32]> =
+    LazyLock::new(|| blake3::derive_key("geph5-broker puzzle", MASTER_SECRET.as_bytes()));
+
+/// The window over which registrations from a source network are counted, in seconds.
+const SOURCE_WINDOW: u64 = 3600;
+
+/// Registrations seen by this broker instance in the last window, by source network. Entries expire two windows after the last registration, once they could only count zero, and looking them up does not keep them alive.
+static SOURCE_REGISTRATIONS: LazyLock<Cache<IpAddr, Arc<Mutex<WindowCounter>>>> =
+    LazyLock::new(|| {
+        Cache::builder()
+            .expire_after(ExpireAfterWrite(Duration::from_secs(2 * SOURCE_WINDOW)))
+            .max_capacity(100_000)
+            .build()
+    });
+
+struct ExpireAfterWrite(Duration);
+
+impl<K, V> Expiry<K, V> for ExpireAfterWrite {
+    fn expire_after_create(&self, _key: &K, _value: &V, _created_at: Instant) -> Option<Duration> {
+        Some(self.0)
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &K,
+        _value: &V,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(self.0)
+    }
+}
+
+/// Counts events over a sliding window, approximated by counting whole windows and weighting the previous one by how much of it the sliding window still covers.
+#[derive(Default)]
+struct WindowCounter {
+    window: u64,
+    current: u64,
+    previous: u64,
+}
+
+impl WindowCounter {
+    fn record(&mut self, now: u64) {
+        let window = now / SOURCE_WINDOW;
+        match window.saturating_sub(self.window) {
+            0 => {}
+            1 => {
+                self.previous = self.current;
+                self.current = 0;
+            }
+            _ => {
+                self.previous = 0;
+                self.current = 0;
+            }
+        }
+        self.window = window;
+        self.current += 1;
+    }
+
+    fn count(&self, now: u64) -> u64 {
+        let (current, previous) = match (now / SOURCE_WINDOW).saturating_sub(self.window) {
+            0 => (self.current, self.previous),
+            1 => (0, self.current),
+            _ => (0, 0),
+        };
+        current + previous * (SOURCE_WINDOW - now % SOURCE_WINDOW) / SOURCE_WINDOW
+    }
+}
 
-pub async fn new_puzzle() -> String {
-    let mut bts = [0u8; 20];
-    rand::rng().fill_bytes(&mut bts);
-    hex::encode(bts)
+/// Issues a new puzzle for the given source, returning it along with its difficulty.
+pub async fn new_puzzle(source: Option<IpAddr>) -> (String, u16) {
+    let difficulty = puzzle_difficulty(source).await;
+    (
+        issue_puzzle(&PUZZLE_KEY, unix_now(), difficulty),
+        difficulty,
+    )
 }
 
+/// Makes a puzzle of the form `<unix time>.<difficulty>.<nonce>.<MAC>`.
+fn issue_puzzle(key: &[u8; 32], now: u64, difficulty: u16) -> String {
+    let mut nonce = [0u8; 20];
+    rand::rng().fill_bytes(&mut nonce);
+    let body = format!("{now}.{difficulty}.{}", hex::encode(nonce));
+    let mac = blake3::keyed_hash(key, body.as_bytes());
+    format!("{body}.{}", mac.to_hex())
+}
+
+/// Verifies a solution against the difficulty that the puzzle was issued with.
 pub async fn verify_puzzle_solution(puzzle: &str, solution: &str) -> anyhow::Result<()> {
-    geph5_broker_protocol::puzzle::verify_puzzle_solution(
-        puzzle,
-        CONFIG_FILE.wait().puzzle_difficulty,
-        solution,
-    )?;
+    check_puzzle_solution(&PUZZLE_KEY, unix_now(), puzzle, solution)?;
     // deduplicate on insert
     sqlx::query("insert into used_puzzles values ($1)")
         .bind(puzzle)
@@ -21,3 +112,157 @@ pub async fn verify_puzzle_solution(puzzle: &str, solution: &str) -> anyhow::Res
         .await?;
     Ok(())
 }
+
+/// Checks that this broker issued the puzzle, that it has not expired, and that the solution meets its difficulty.
+fn check_puzzle_solution(
+    key: &[u8; 32],
+    now: u64,
+    puzzle: &str,
+    solution: &str,
+) -> anyhow::Result<()> {
+    let (body, mac) = puzzle.rsplit_once('.').context("malformed puzzle")?;
+    anyhow::ensure!(
+        blake3::keyed_hash(key, body.as_bytes()).to_hex().as_str() == mac,
+        "puzzle was not issued by this broker"
+    );
+    let mut fields = body.split('.');
+    let issued: u64 = fields.next().context("malformed puzzle")?.parse()?;
+    let difficulty: u16 = fields.next().context("malformed puzzle")?.parse()?;
+    anyhow::ensure!(
+        now.saturating_sub(issued) <= PUZZLE_LIFETIME,
+        "puzzle expired"
+    );
+    geph5_broker_protocol::puzzle::verify_puzzle_solution(puzzle, difficulty, solution)
+}
+
+/// Notes a successful registration, making puzzles harder for the rest of its source network.
+pub async fn record_registration(source: Option<IpAddr>) {
+    if let Some(source) = source {
+        let network = source_network(source);
+        let counter = SOURCE_REGISTRATIONS
+            .get_with(network, async { Default::default() })
+            .await;
+        counter.lock().unwrap().record(unix_now());
+        // writing the entry again pushes back its expiry
+        SOURCE_REGISTRATIONS.insert(network, counter).await;
+    }
+}
+
+/// Picks the difficulty for a new puzzle. Every doubling of the registration rate past its threshold, globally or from the source network, adds one bit of difficulty, which doubles the work.
+async fn puzzle_difficulty(source: Option<IpAddr>) -> u16 {
+    let config = CONFIG_FILE.wait();
+    let global = recent_registrations().await.unwrap_or_else(|err| {
+        log_error(&err);
+        0
+    });
+    let from_source = match source {
+        Some(source) => SOURCE_REGISTRATIONS
+            .get(&source_network(source))
+            .await
+            .map(|counter| counter.lock().unwrap().count(unix_now()))
+            .unwrap_or_default(),
+        None => 0,
+    };
+    let difficulty = config.puzzle_difficulty
+        + excess_bits(global, config.puzzle_registrations_per_hour)
+        + excess_bits(from_source, config.puzzle_registrations_per_source);
+    difficulty.min(config.puzzle_max_difficulty.max(config.puzzle_difficulty))
+}
+
+fn excess_bits(count: u64, threshold: u64) -> u16 {
+    if threshold == 0 || count < threshold {
+        0
+    } else {
+        ((count / threshold).ilog2() + 1) as u16
+    }
+}
+
+/// Counts the accounts created in the last hour, across every broker instance.
+async fn recent_registrations() -> anyhow::Result<u64> {
+    static CACHE: LazyLock<Cache<(), u64>> = LazyLock::new(|| {
+        Cache::builder()
+            .time_to_live(Duration::from_secs(10))
+            .build()
+    });
+    CACHE
+        .try_get_with((), async {
+            let (count,): (i64,) = sqlx::query_as(
+                "SELECT COUNT(*) FROM users WHERE createtime > NOW() - INTERVAL '1 hour'",
+            )
+            .fetch_one(POSTGRES.deref())
+            .await?;
+            anyhow::Ok(count as u64)
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!(e))
+}
+
+/// The network that an address belongs to, so that hopping between neighboring addresses doesn't get around the per-source difficulty.
+fn source_network(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from_bits(ip.to_bits() & !0xff)),
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from_bits(ip.to_bits() & !((1 << 80) - 1))),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_excess_bits() {
+        assert_eq!(excess_bits(0, 10), 0);
+        assert_eq!(excess_bits(9, 10), 0);
+        assert_eq!(excess_bits(10, 10), 1);
+        assert_eq!(excess_bits(19, 10), 1);
+        assert_eq!(excess_bits(20, 10), 2);
+        assert_eq!(excess_bits(40, 10), 3);
+        assert_eq!(excess_bits(1_000_000, 10), 17);
+        // a zero threshold disables the adjustment
+        assert_eq!(excess_bits(1_000_000, 0), 0);
+    }
+
+    #[test]
+    fn test_window_counter() {
+        let start = 100 * SOURCE_WINDOW;
+        let mut counter = WindowCounter::default();
+        for _ in 0..10 {
+            counter.record(start);
+        }
+        assert_eq!(counter.count(start + 10), 10);
+        // halfway through the next window, half of the previous one is still covered
+        assert_eq!(counter.count(start + SOURCE_WINDOW * 3 / 2), 5);
+        counter.record(start + SOURCE_WINDOW * 3 / 2);
+        assert_eq!(counter.count(start + SOURCE_WINDOW * 3 / 2), 6);
+        // old registrations stop counting
+        assert_eq!(counter.count(start + SOURCE_WINDOW * 3), 0);
+        counter.record(start + SOURCE_WINDOW * 5);
+        assert_eq!(counter.count(start + SOURCE_WINDOW * 5), 1);
+    }
+
+    #[test]
+    fn test_puzzle() {
+        let key = [1; 32];
+        let now = 1_700_000_000;
+        let puzzle = issue_puzzle(&key, now, 4);
+        let solution = geph5_broker_protocol::puzzle::solve_puzzle(&puzzle, 4, |_| {});
+        check_puzzle_solution(&key, now + 60, &puzzle, &solution).unwrap();
+
+        assert!(
+            check_puzzle_solution(&key, now + PUZZLE_LIFETIME + 1, &puzzle, &solution).is_err()
+        );
+        assert!(check_puzzle_solution(&[2; 32], now, &puzzle, &solution).is_err());
+        // lowering the difficulty breaks the MAC
+        let easier = puzzle.replacen(".4.", ".0.", 1);
+        assert!(check_puzzle_solution(&key, now, &easier, &solution).is_err());
+        let other = issue_puzzle(&key, now, 4);
+        assert!(check_puzzle_solution(&key, now, &other, &solution).is_err());
+    }
+}
@@ -4,7 +4,7 @@ use geph5_broker_protocol::{BridgeDescriptor, RouteDescriptor};
 use geph5_misc_rpc::bridge::{B2eMetadata, BridgeControlClient, ObfsProtocol};
 
 use moka::future::Cache;
-use nanorpc_sillad::DialerTransport;
+use nanorpc_sillad::PooledTransport;
 
 use rand::RngCore;
 use sillad::tcp::TcpDialer;
@@ -16,6 +16,8 @@ use std::{
     time::{Duration, SystemTime},
 };
 
+use crate::CONFIG_FILE;
+
 pub async fn bridge_to_leaf_route(
     bridge: BridgeDescriptor,
     delay_ms: u32,
@@ -90,21 +92,35 @@ fn gencookie() -> String {
     hex::encode(b)
 }
 
+type BridgeTransport = PooledTransport<SosistabDialer<TcpDialer>>;
+
 async fn bridge_to_leaf_route_inner(
     bridge: BridgeDescriptor,
     exit_b2e: SocketAddr,
     protocol: ObfsProtocol,
 ) -> anyhow::Result<RouteDescriptor> {
-    let cookie = Cookie::new(&bridge.control_cookie);
-
-    let control_dialer = SosistabDialer {
-        inner: TcpDialer {
-            dest_addr: bridge.control_listen,
-        },
-        cookie,
-    };
+    // one pooled transport per bridge, so that the forwards for every exit share a few sosistab3 connections
+    static TRANSPORTS: LazyLock<Cache<(SocketAddr, String), BridgeTransport>> =
+        LazyLock::new(|| {
+            Cache::builder()
+                .time_to_idle(Duration::from_secs(600))
+                .build()
+        });
 
-    let control_client = BridgeControlClient(DialerTransport(control_dialer));
+    let transport = TRANSPORTS
+        .get_with(
+            (bridge.control_listen, bridge.control_cookie.clone()),
+            async {
+                PooledTransport::new(SosistabDialer {
+                    inner: TcpDialer {
+                        dest_addr: bridge.control_listen,
+                    },
+                    cookie: Cookie::new(&bridge.control_cookie),
+                })
+            },
+        )
+        .await;
+    let control_client = BridgeControlClient(transport);
 
     let sosistab_addr = control_client
         .tcp_forward(
@@ -112,7 +128,8 @@ async fn bridge_to_leaf_route_inner(
             B2eMetadata {
                 protocol: protocol.clone(),
                 expiry: SystemTime::now() + Duration::from_secs(86400),
-            },
+            }
+            .authenticate(&CONFIG_FILE.wait().exit_token),
         )
         .timeout(Duration::from_secs(4))
         .await
@@ -141,5 +158,7 @@ fn protocol_to_descriptor(protocol: ObfsProtocol, addr: SocketAddr) -> RouteDesc
             cookie,
             lower: protocol_to_descriptor(*obfs_protocol, addr).into(),
         },
+        // routes only ever go through TCP forwards
+        ObfsProtocol::Datagrams(_) => unreachable!("no route runs over datagrams"),
     }
 }
@@ -6,8 +6,10 @@ use ed25519_dalek::VerifyingKey;
 use futures_util::{TryFutureExt, future::join_all};
 use geph5_broker_protocol::{
     AccountLevel, AuthError, AvailabilityData, BridgeDescriptor, BrokerProtocol, BrokerService,
-    Credential, DOMAIN_EXIT_DESCRIPTOR, ExitDescriptor, ExitList, GenericError, Mac, NewsItem,
-    RouteDescriptor, Signed, UserInfo,
+    Capabilities, Credential, DOMAIN_EXIT_DESCRIPTOR, DOMAIN_EXIT_LIST_UPDATE, DOMAIN_ROUTE_BUNDLE,
+    DeviceInfo, DeviceProof, ExitDescriptor, ExitList, ExitListDelta, ExitListUpdate, GenericError,
+    Mac, NewsItem, RouteBundle, RouteDescriptor, Signed, TimestampedExitListUpdate, UserInfo,
+    feature,
 };
 use isocountry::CountryCode;
 use mizaru2::{BlindedClientToken, BlindedSignature, ClientToken, UnblindedSignature};
@@ -15,31 +17,38 @@ use moka::future::Cache;
 use nanorpc::{RpcService, ServerError};
 use once_cell::sync::Lazy;
 use std::{
-    net::SocketAddr,
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
     ops::Deref,
     sync::Arc,
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
-    CONFIG_FILE, FREE_MIZARU_SK, MASTER_SECRET, PLUS_MIZARU_SK,
+    CONFIG_FILE, MASTER_SECRET,
     auth::{new_auth_token, valid_auth_token},
     database::{ExitRow, POSTGRES, insert_exit, query_bridges},
+    devices::{enroll_device, list_devices, new_device_challenge, revoke_device},
+    mizaru_sk,
     routes::bridge_to_leaf_route,
 };
 use crate::{
-    auth::{get_user_info, register_secret, validate_credential},
+    auth::{
+        delete_account, get_login_history, get_user_info, record_login, regenerate_secret,
+        register_secret, validate_credential,
+    },
     log_error,
     news::fetch_news,
     payments::{PaymentClient, PaymentTransport, StartStripeArgs, payment_sessid},
-    puzzle::{new_puzzle, verify_puzzle_solution},
+    puzzle::{new_puzzle, record_registration, verify_puzzle_solution},
 };
 
 pub struct WrappedBrokerService(BrokerService<BrokerImpl>);
 
 impl WrappedBrokerService {
-    pub fn new() -> Self {
-        Self(BrokerService(BrokerImpl {}))
+    /// Creates a service for a request from the given source address, if known.
+    pub fn new(source: Option<IpAddr>) -> Self {
+        Self(BrokerService(BrokerImpl { source }))
     }
 }
 
@@ -62,9 +71,28 @@ impl RpcService for WrappedBrokerService {
     }
 }
 
-struct BrokerImpl {}
+struct BrokerImpl {
+    source: Option<IpAddr>,
+}
 
 impl BrokerImpl {
+    /// Gets the exits that users of the given level may use, tailored to the caller's capabilities.
+    async fn exit_list_for(
+        &self,
+        level: &AccountLevel,
+        caps: &Capabilities,
+    ) -> Result<ExitList, GenericError> {
+        if mizaru_sk(level).is_none() {
+            return Err(GenericError(format!("no such tier: {}", level.name())));
+        }
+        let mut exit_list = self.get_all_exits().await?;
+        exit_list.all_exits.retain(|(_, e)| exit_eligible(level, e));
+        if !caps.supports(feature::EXIT_METADATA) {
+            exit_list = exit_list.without_metadata();
+        }
+        Ok(exit_list)
+    }
+
     async fn get_all_exits(&self) -> Result<ExitList, GenericError> {
         static EXIT_CACHE: Lazy<Cache<(), ExitList>> = Lazy::new(|| {
             Cache::builder()
@@ -90,6 +118,8 @@ impl BrokerImpl {
                                     city: row.city,
                                     load: row.load,
                                     expiry: row.expiry as _,
+                                    metadata: serde_json::from_str(&row.metadata)
+                                        .unwrap_or_default(),
                                 },
                             )
                         })
@@ -117,19 +147,77 @@ fn is_plus_exit(exit: &ExitDescriptor) -> bool {
     )
 }
 
+/// Whether users of the given level may use the exit.
+fn exit_eligible(level: &AccountLevel, exit: &ExitDescriptor) -> bool {
+    match level {
+        AccountLevel::Free => !is_plus_exit(exit),
+        AccountLevel::Plus => true,
+        AccountLevel::Tier(name) => CONFIG_FILE
+            .wait()
+            .tiers
+            .iter()
+            .find(|t| &t.name == name)
+            .is_some_and(|t| {
+                t.exit_countries
+                    .as_ref()
+                    .is_none_or(|countries| countries.contains(&exit.country))
+            }),
+    }
+}
+
+/// Whether users of the given level may use bridges reserved for Plus users.
+fn plus_bridges_eligible(level: &AccountLevel) -> bool {
+    match level {
+        AccountLevel::Free => false,
+        AccountLevel::Plus => true,
+        AccountLevel::Tier(name) => CONFIG_FILE
+            .wait()
+            .tiers
+            .iter()
+            .any(|t| &t.name == name && t.plus_bridges),
+    }
+}
+
 #[async_trait]
 impl BrokerProtocol for BrokerImpl {
+    async fn get_capabilities(&self, peer: Capabilities) -> Capabilities {
+        tracing::debug!(
+            protocol_version = peer.protocol_version,
+            features = debug(&peer.features),
+            "peer advertised capabilities"
+        );
+        Capabilities::current()
+    }
+
     async fn get_mizaru_subkey(&self, level: AccountLevel, epoch: u16) -> Bytes {
-        match level {
-            AccountLevel::Free => &FREE_MIZARU_SK,
-            AccountLevel::Plus => &PLUS_MIZARU_SK,
+        // an unknown tier gets an empty key, which the client fails to decode
+        let Some(sk) = mizaru_sk(&level) else {
+            return Bytes::new();
+        };
+        match sk.get_subkey(epoch) {
+            Ok(subkey) => subkey.public_key().unwrap().to_der().unwrap().into(),
+            Err(err) => {
+                tracing::error!(epoch, err = debug(err), "cannot get mizaru subkey");
+                Bytes::new()
+            }
         }
-        .get_subkey(epoch)
-        .public_key()
-        .unwrap()
-        .to_der()
-        .unwrap()
-        .into()
+    }
+
+    async fn get_mizaru_public_keys(&self) -> BTreeMap<String, mizaru2::PublicKey> {
+        [AccountLevel::Free, AccountLevel::Plus]
+            .into_iter()
+            .chain(
+                CONFIG_FILE
+                    .wait()
+                    .tiers
+                    .iter()
+                    .map(|t| AccountLevel::Tier(t.name.clone())),
+            )
+            .filter_map(|level| {
+                let pk = mizaru_sk(&level)?.to_public_key();
+                Some((level.name().to_string(), pk))
+            })
+            .collect()
     }
 
     async fn get_auth_token(&self, credential: Credential) -> Result<String, AuthError> {
@@ -139,6 +227,9 @@ impl BrokerProtocol for BrokerImpl {
             .await
             .inspect_err(log_error)
             .map_err(|_| AuthError::RateLimited)?;
+        if let Err(err) = record_login(user_id).await {
+            log_error(&err);
+        }
 
         Ok(token)
     }
@@ -167,31 +258,77 @@ impl BrokerProtocol for BrokerImpl {
         if user_level != level {
             return Err(AuthError::WrongLevel);
         }
-        let signed = match level {
-            AccountLevel::Free => &FREE_MIZARU_SK,
-            AccountLevel::Plus => &PLUS_MIZARU_SK,
-        }
-        .blind_sign(epoch, &blind_token);
+        let signed = mizaru_sk(&level)
+            .ok_or(AuthError::WrongLevel)?
+            .blind_sign(epoch, &blind_token)
+            .map_err(|err| {
+                tracing::error!(epoch, err = debug(err), "cannot blind-sign");
+                AuthError::RateLimited
+            })?;
         tracing::debug!(elapsed = debug(start.elapsed()), "blind signing done");
         Ok(signed)
     }
 
     async fn get_exits(&self) -> Result<Signed<ExitList>, GenericError> {
-        let exit_list = self.get_all_exits().await?;
+        self.get_exits_v2(AccountLevel::Plus, Capabilities::legacy())
+            .await
+    }
+
+    async fn get_free_exits(&self) -> Result<Signed<ExitList>, GenericError> {
+        self.get_exits_v2(AccountLevel::Free, Capabilities::legacy())
+            .await
+    }
+
+    async fn get_tier_exits(&self, level: AccountLevel) -> Result<Signed<ExitList>, GenericError> {
+        self.get_exits_v2(level, Capabilities::legacy()).await
+    }
 
+    async fn get_exits_v2(
+        &self,
+        level: AccountLevel,
+        caps: Capabilities,
+    ) -> Result<Signed<ExitList>, GenericError> {
         Ok(Signed::new(
-            exit_list,
+            self.exit_list_for(&level, &caps).await?,
             DOMAIN_EXIT_DESCRIPTOR,
             MASTER_SECRET.deref(),
         ))
     }
 
-    async fn get_free_exits(&self) -> Result<Signed<ExitList>, GenericError> {
-        let mut exit_list = self.get_all_exits().await?;
-        exit_list.all_exits.retain(|(_, e)| !is_plus_exit(e));
+    async fn get_exits_since(
+        &self,
+        level: AccountLevel,
+        version: Option<blake3::Hash>,
+        caps: Capabilities,
+    ) -> Result<Signed<TimestampedExitListUpdate>, GenericError> {
+        // recent lists, so that we can compute deltas against whatever version clients have
+        static HISTORY: Lazy<Cache<blake3::Hash, Arc<ExitList>>> = Lazy::new(|| {
+            Cache::builder()
+                .time_to_live(Duration::from_secs(3600))
+                .max_capacity(10000)
+                .build()
+        });
+
+        let exit_list = self.exit_list_for(&level, &caps).await?;
+        let current = exit_list.version();
+        HISTORY.insert(current, Arc::new(exit_list.clone())).await;
+        let update = match version {
+            Some(version) if version == current => ExitListUpdate::Unchanged(current),
+            Some(version) => match HISTORY.get(&version).await {
+                Some(old) => ExitListDelta::between(&old, &exit_list)
+                    .map(ExitListUpdate::Delta)
+                    .unwrap_or(ExitListUpdate::Full(exit_list)),
+                None => ExitListUpdate::Full(exit_list),
+            },
+            None => ExitListUpdate::Full(exit_list),
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
         Ok(Signed::new(
-            exit_list,
-            DOMAIN_EXIT_DESCRIPTOR,
+            TimestampedExitListUpdate { update, timestamp },
+            DOMAIN_EXIT_LIST_UPDATE,
             MASTER_SECRET.deref(),
         ))
     }
@@ -218,22 +355,38 @@ impl BrokerProtocol for BrokerImpl {
         sig: UnblindedSignature,
         exit: SocketAddr,
     ) -> Result<RouteDescriptor, GenericError> {
-        // authenticate the token
-        let account_level = if PLUS_MIZARU_SK
-            .to_public_key()
-            .blind_verify(token, &sig)
-            .is_ok()
-        {
-            AccountLevel::Plus
-        } else {
-            FREE_MIZARU_SK.to_public_key().blind_verify(token, &sig)?;
+        let bundle = self
+            .get_routes_v2(token, sig, exit, Capabilities::legacy())
+            .await?;
+        Ok(bundle.inner.route)
+    }
 
-            AccountLevel::Free
-        };
+    async fn get_routes_v2(
+        &self,
+        token: ClientToken,
+        sig: UnblindedSignature,
+        exit: SocketAddr,
+        caps: Capabilities,
+    ) -> Result<Signed<RouteBundle>, GenericError> {
+        // authenticate the token
+        let account_level = [AccountLevel::Plus, AccountLevel::Free]
+            .into_iter()
+            .chain(
+                CONFIG_FILE
+                    .wait()
+                    .tiers
+                    .iter()
+                    .map(|t| AccountLevel::Tier(t.name.clone())),
+            )
+            .find(|level| {
+                mizaru_sk(level)
+                    .is_some_and(|sk| sk.to_public_key().blind_verify(token, &sig).is_ok())
+            })
+            .ok_or_else(|| GenericError("invalid connect token".into()))?;
 
         let raw_descriptors = query_bridges(&format!("{:?}", token)).await?;
 
-        let raw_descriptors = if account_level == AccountLevel::Free {
+        let raw_descriptors = if !plus_bridges_eligible(&account_level) {
             raw_descriptors
                 .into_iter()
                 .filter(|(_, _, is_plus)| !is_plus)
@@ -265,7 +418,32 @@ impl BrokerProtocol for BrokerImpl {
             routes.push(route)
         }
 
-        Ok(RouteDescriptor::Race(routes))
+        let route = RouteDescriptor::Race(routes)
+            .restrict_to(&caps)
+            .unwrap_or(RouteDescriptor::Race(vec![]))
+            .simplify();
+        if let Err(err) = route.validate() {
+            tracing::warn!(
+                err = display(err),
+                route = display(&route),
+                "serving a route that fails validation"
+            );
+        }
+        let bundle = RouteBundle {
+            route,
+            token_hash: RouteBundle::token_hash(token),
+            exit_b2e: exit,
+            expiry: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+                + 3600,
+        };
+        Ok(Signed::new(
+            bundle,
+            DOMAIN_ROUTE_BUNDLE,
+            MASTER_SECRET.deref(),
+        ))
     }
 
     async fn insert_exit(
@@ -284,6 +462,7 @@ impl BrokerProtocol for BrokerImpl {
             city: descriptor.city.clone(),
             load: descriptor.load,
             expiry: descriptor.expiry as _,
+            metadata: serde_json::to_string(&descriptor.metadata)?,
         };
         insert_exit(&exit).await?;
         Ok(())
@@ -354,7 +533,7 @@ impl BrokerProtocol for BrokerImpl {
     }
 
     async fn get_puzzle(&self) -> (String, u16) {
-        (new_puzzle().await, CONFIG_FILE.wait().puzzle_difficulty)
+        new_puzzle(self.source).await
     }
 
     async fn register_user_secret(
@@ -363,7 +542,9 @@ impl BrokerProtocol for BrokerImpl {
         solution: String,
     ) -> Result<String, GenericError> {
         verify_puzzle_solution(&puzzle, &solution).await?;
-        Ok(register_secret(None).await?)
+        let secret = register_secret(None).await?;
+        record_registration(self.source).await;
+        Ok(secret)
     }
 
     async fn upgrade_to_secret(&self, cred: Credential) -> Result<String, AuthError> {
@@ -373,6 +554,58 @@ impl BrokerProtocol for BrokerImpl {
             .await
     }
 
+    async fn regenerate_secret(&self, cred: Credential) -> Result<String, AuthError> {
+        let user_id = validate_credential(cred).await?;
+        regenerate_secret(user_id)
+            .await
+            .inspect_err(log_error)
+            .map_err(|_| AuthError::RateLimited)
+    }
+
+    async fn delete_account(&self, cred: Credential) -> Result<(), AuthError> {
+        let user_id = validate_credential(cred.clone()).await?;
+        delete_account(user_id, &cred)
+            .await
+            .inspect_err(log_error)
+            .map_err(|_| AuthError::RateLimited)
+    }
+
+    async fn get_login_history(&self, cred: Credential) -> Result<Vec<u64>, AuthError> {
+        let user_id = validate_credential(cred).await?;
+        get_login_history(user_id)
+            .await
+            .inspect_err(log_error)
+            .map_err(|_| AuthError::RateLimited)
+    }
+
+    async fn get_device_challenge(&self) -> String {
+        new_device_challenge()
+    }
+
+    async fn enroll_device(
+        &self,
+        auth_token: String,
+        name: String,
+        proof: DeviceProof,
+    ) -> Result<(), AuthError> {
+        let user_id = auth_token_user(auth_token).await?;
+        enroll_device(user_id, &name, &proof).await
+    }
+
+    async fn list_devices(&self, auth_token: String) -> Result<Vec<DeviceInfo>, AuthError> {
+        let user_id = auth_token_user(auth_token).await?;
+        list_devices(user_id).await
+    }
+
+    async fn revoke_device(
+        &self,
+        auth_token: String,
+        public_key: VerifyingKey,
+    ) -> Result<(), AuthError> {
+        let user_id = auth_token_user(auth_token).await?;
+        revoke_device(user_id, &public_key).await
+    }
+
     async fn get_news(&self, lang: String) -> Result<Vec<NewsItem>, GenericError> {
         let (send, recv) = oneshot::channel();
         smolscale::spawn(async move { send.send(fetch_news(&lang).await) }).detach();
@@ -414,6 +647,15 @@ impl BrokerProtocol for BrokerImpl {
     }
 }
 
+/// Gets the user that an auth token belongs to.
+async fn auth_token_user(auth_token: String) -> Result<i32, AuthError> {
+    match valid_auth_token(auth_token).await {
+        Ok(Some((user_id, _))) => Ok(user_id),
+        Ok(None) => Err(AuthError::Forbidden),
+        Err(_) => Err(AuthError::RateLimited),
+    }
+}
+
 pub static STATSD_CLIENT: Lazy<Option<StatsdClient>> = Lazy::new(|| {
     if let Some(statsd_addr) = CONFIG_FILE.wait().statsd_addr {
         let socket = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
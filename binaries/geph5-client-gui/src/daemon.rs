@@ -3,11 +3,15 @@ mod subproc;
 
 use std::sync::Arc;
 
+use base32::Alphabet;
 use geph5_client::{Config, ControlClient};
 
 use once_cell::sync::Lazy;
 
-use crate::timeseries::TimeSeries;
+use crate::{
+    prefs::{pref_read, pref_write},
+    timeseries::TimeSeries,
+};
 
 pub static TOTAL_BYTES_TIMESERIES: TimeSeries = TimeSeries::new(60 * 600);
 
@@ -18,6 +22,16 @@ pub static DAEMON_HANDLE: Lazy<Arc<dyn Daemon>> =
 #[cfg(windows)]
 pub static DAEMON_HANDLE: Lazy<Arc<dyn Daemon>> = Lazy::new(|| Arc::new(subproc::SubprocDaemon));
 
+/// The secret that the daemon's control protocol requires, generated once and kept in the preferences, so that a GUI started later can still control a running daemon.
+fn control_secret() -> anyhow::Result<String> {
+    if let Ok(secret) = pref_read("control_secret") {
+        return Ok(secret.to_string());
+    }
+    let secret = base32::encode(Alphabet::Crockford, &rand::random::<[u8; 20]>());
+    pref_write("control_secret", &secret)?;
+    Ok(secret)
+}
+
 pub trait Daemon: Sync + Send + 'static {
     fn start(&self, cfg: Config) -> anyhow::Result<()>;
 
@@ -5,7 +5,7 @@ use std::{
 
 use geph5_client::{Client, Config};
 
-use super::Daemon;
+use super::{Daemon, control_secret};
 
 #[derive(Default)]
 pub struct InlineDaemon {
@@ -13,7 +13,9 @@ pub struct InlineDaemon {
 }
 
 impl Daemon for InlineDaemon {
-    fn start(&self, cfg: Config) -> anyhow::Result<()> {
+    fn start(&self, mut cfg: Config) -> anyhow::Result<()> {
+        // the GUI talks to the client in-process, but any control port it listens on should still be protected
+        cfg.control_secret = Some(control_secret()?);
         let client = Client::start(cfg);
         *self.daemon.lock().unwrap() = Some(client);
         Ok(())
@@ -29,7 +31,7 @@ impl Daemon for InlineDaemon {
         if let Some(daemon) = daemon.as_ref() {
             daemon.control_client()
         } else {
-            geph5_client::ControlClient::from(nanorpc_sillad::DialerTransport(
+            geph5_client::ControlClient::from(nanorpc_sillad::DialerTransport::new(
                 sillad::tcp::TcpDialer {
                     dest_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
                 },
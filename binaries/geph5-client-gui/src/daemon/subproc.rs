@@ -4,7 +4,7 @@ use geph5_client::Config;
 
 use crate::prefs::PREF_DIR;
 
-use super::Daemon;
+use super::{Daemon, control_secret};
 
 pub struct SubprocDaemon;
 
@@ -16,6 +16,7 @@ impl Daemon for SubprocDaemon {
             IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
             CONTROL_PORT,
         ));
+        cfg.control_secret = Some(control_secret()?);
         let cfg_path = PREF_DIR.join("config.yaml");
         std::fs::write(
             cfg_path.clone(),
@@ -52,9 +53,21 @@ impl Daemon for SubprocDaemon {
     }
 
     fn control_client(&self) -> geph5_client::ControlClient {
-        geph5_client::ControlClient::from(nanorpc_sillad::DialerTransport(sillad::tcp::TcpDialer {
+        let dialer = sillad::tcp::TcpDialer {
             dest_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), CONTROL_PORT),
-        }))
+        };
+        match control_secret() {
+            Ok(secret) => geph5_client::ControlClient::from(
+                nanorpc_sillad::DialerTransport::new_authenticated(
+                    dialer,
+                    nanorpc_sillad::PresharedToken::new(&secret),
+                ),
+            ),
+            Err(err) => {
+                tracing::warn!(err = debug(err), "cannot read the control secret");
+                geph5_client::ControlClient::from(nanorpc_sillad::DialerTransport::new(dialer))
+            }
+        }
     }
 
     fn check_dead(&self) -> anyhow::Result<()> {
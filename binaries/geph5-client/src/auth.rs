@@ -6,14 +6,15 @@ use std::{
 use anyctx::AnyCtx;
 use anyhow::Context as _;
 use blind_rsa_signatures as brs;
-use geph5_broker_protocol::{AccountLevel, AuthError};
+use ed25519_dalek::SigningKey;
+use geph5_broker_protocol::{AccountLevel, AuthError, Credential, DeviceProof, feature};
 use mizaru2::{ClientToken, UnblindedSignature};
 use rand::Rng;
 use smol_timeout2::TimeoutExt;
 use stdcode::StdcodeSerializeExt;
 
 use crate::{
-    broker::broker_client,
+    broker::{broker_capabilities, broker_client},
     client::Config,
     database::{db_read, db_read_or_wait, db_remove, db_write},
 };
@@ -35,17 +36,91 @@ pub async fn get_connect_token(
 
 pub async fn get_auth_token(ctx: &AnyCtx<Config>) -> anyhow::Result<String> {
     if let Some(token) = db_read(ctx, "auth_token").await? {
-        Ok(String::from_utf8_lossy(&token).to_string())
+        return Ok(String::from_utf8_lossy(&token).to_string());
+    }
+    tracing::debug!("obtaining auth token");
+    let device_auth_token = match db_read(ctx, "device_key").await? {
+        Some(device_key) => device_login(ctx, device_key).await?,
+        None => None,
+    };
+    let auth_token = if let Some(auth_token) = device_auth_token {
+        auth_token
     } else {
-        tracing::debug!("obtaining auth token");
+        let credential_feature = match &ctx.init().credentials {
+            Credential::TestDummy => None,
+            Credential::LegacyUsernamePassword { .. } => Some(feature::CREDENTIAL_LEGACY),
+            Credential::Secret(_) => Some(feature::CREDENTIAL_SECRET),
+            Credential::Device(_) => Some(feature::CREDENTIAL_DEVICE),
+        };
+        if let Some(feature) = credential_feature {
+            let caps = broker_capabilities(ctx).await?;
+            anyhow::ensure!(
+                caps.supports(feature),
+                "broker does not accept {feature} credentials"
+            );
+        }
         let auth_token = broker_client(ctx)?
             .get_auth_token(ctx.init().credentials.clone())
             .await??;
-        db_write(ctx, "auth_token", auth_token.as_bytes()).await?;
-        Ok(auth_token)
+        if let Some(name) = &ctx.init().device_name {
+            let _ = enroll_device(ctx, &auth_token, name)
+                .await
+                .inspect_err(|err| tracing::warn!(err = debug(err), "could not enroll device"));
+        }
+        auth_token
+    };
+    db_write(ctx, "auth_token", auth_token.as_bytes()).await?;
+    Ok(auth_token)
+}
+
+/// Logs in with the stored device key. If the broker rejects the key, as it does once the device is revoked, the key is forgotten and `None` is returned, so that the credentials are used instead.
+async fn device_login(ctx: &AnyCtx<Config>, device_key: Vec<u8>) -> anyhow::Result<Option<String>> {
+    let device_key = SigningKey::from_bytes(
+        &device_key
+            .try_into()
+            .ok()
+            .context("stored device key must be 32 bytes")?,
+    );
+    let client = broker_client(ctx)?;
+    let challenge = client.get_device_challenge().await?;
+    let proof = Box::new(DeviceProof::new(&device_key, challenge));
+    match client.get_auth_token(Credential::Device(proof)).await? {
+        Ok(auth_token) => Ok(Some(auth_token)),
+        Err(AuthError::Forbidden) => {
+            tracing::warn!("device key rejected, logging in with the credentials instead");
+            db_remove(ctx, "device_key").await?;
+            Ok(None)
+        }
+        Err(err) => Err(anyhow::Error::from(err).context("device login failed")),
     }
 }
 
+/// Enrolls a fresh device key to the account, so that later logins prove possession of the key instead of sending the credentials.
+async fn enroll_device(ctx: &AnyCtx<Config>, auth_token: &str, name: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        broker_capabilities(ctx)
+            .await?
+            .supports(feature::CREDENTIAL_DEVICE),
+        "broker does not support device credentials"
+    );
+    let device_key = SigningKey::from_bytes(&rand::thread_rng().r#gen());
+    let client = broker_client(ctx)?;
+    let challenge = client.get_device_challenge().await?;
+    client
+        .enroll_device(
+            auth_token.to_string(),
+            name.to_string(),
+            DeviceProof::new(&device_key, challenge),
+        )
+        .await??;
+    db_write(ctx, "device_key", device_key.as_bytes()).await?;
+    tracing::info!(
+        public_key = hex::encode(device_key.verifying_key().as_bytes()),
+        "enrolled device"
+    );
+    Ok(())
+}
+
 pub async fn auth_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
     if ctx.init().broker.is_none() {
         return smol::future::pending().await;
@@ -68,17 +143,19 @@ async fn refresh_conn_token(ctx: &AnyCtx<Config>, auth_token: &str) -> anyhow::R
     let inner = async {
         let epoch = mizaru2::current_epoch();
         let broker_client = broker_client(ctx)?;
+        let tiers_supported = broker_capabilities(ctx)
+            .await?
+            .supports(feature::ACCOUNT_TIERS);
 
         let last_plus_expiry: u64 = db_read(ctx, "plus_expiry")
             .await?
             .and_then(|b| stdcode::deserialize(&b).ok())
             .unwrap_or_default();
-        let plus_expiry = broker_client
+        let user_info = broker_client
             .get_user_info(auth_token.to_string())
             .await??
-            .context("no such user")?
-            .plus_expires_unix
-            .unwrap_or_default();
+            .context("no such user")?;
+        let plus_expiry = user_info.plus_expires_unix.unwrap_or_default();
 
         if plus_expiry > 0 && last_plus_expiry == 0 {
             tracing::debug!("we gained a plus! gonna clean up the conn token cache here");
@@ -95,10 +172,17 @@ async fn refresh_conn_token(ctx: &AnyCtx<Config>, auth_token: &str) -> anyhow::R
                 .is_none()
             {
                 let token = ClientToken::random();
-                for level in [AccountLevel::Plus, AccountLevel::Free] {
-                    tracing::debug!(epoch, level = debug(level), "refreshing conn token");
+                // try the user's custom tier first, if any, then the built-in levels
+                let levels = user_info
+                    .tier
+                    .iter()
+                    .filter(|_| tiers_supported)
+                    .map(|tier| AccountLevel::from_name(tier))
+                    .chain([AccountLevel::Plus, AccountLevel::Free]);
+                for level in levels {
+                    tracing::debug!(epoch, level = debug(&level), "refreshing conn token");
                     let subkey = broker_client
-                        .get_mizaru_subkey(level, epoch)
+                        .get_mizaru_subkey(level.clone(), epoch)
                         .await
                         .context("cannot get subkey")?;
                     tracing::debug!(epoch, subkey_len = subkey.len(), "got subkey");
@@ -107,7 +191,12 @@ async fn refresh_conn_token(ctx: &AnyCtx<Config>, auth_token: &str) -> anyhow::R
                         brs::PublicKey::from_der(&subkey).context("cannot decode subkey")?;
                     let (blind_token, secret) = token.blind(&subkey);
                     let conn_token = broker_client
-                        .get_connect_token(auth_token.to_string(), level, epoch, blind_token)
+                        .get_connect_token(
+                            auth_token.to_string(),
+                            level.clone(),
+                            epoch,
+                            blind_token,
+                        )
                         .await
                         .context("cannot get connect token")?;
 
@@ -118,10 +207,14 @@ async fn refresh_conn_token(ctx: &AnyCtx<Config>, auth_token: &str) -> anyhow::R
                             }
 
                             if let Some(keys) = &ctx.init().broker_keys {
-                                let mizaru_hex = if level == AccountLevel::Plus {
-                                    &keys.mizaru_plus
-                                } else {
-                                    &keys.mizaru_free
+                                let mizaru_hex = match &level {
+                                    AccountLevel::Plus => &keys.mizaru_plus,
+                                    AccountLevel::Free => &keys.mizaru_free,
+                                    AccountLevel::Tier(name) => {
+                                        keys.mizaru_tiers.get(name).with_context(|| {
+                                            format!("no mizaru key for tier {name}")
+                                        })?
+                                    }
                                 };
                                 let bts =
                                     hex::decode(mizaru_hex).context("cannot decode mizaru hex")?;
@@ -147,7 +240,11 @@ async fn refresh_conn_token(ctx: &AnyCtx<Config>, auth_token: &str) -> anyhow::R
                             break;
                         }
                         Err(AuthError::WrongLevel) => {
-                            tracing::debug!(epoch, level = debug(level), "switching to next level");
+                            tracing::debug!(
+                                epoch,
+                                level = debug(&level),
+                                "switching to next level"
+                            );
                             continue;
                         }
                         Err(e) => anyhow::bail!("cannot get token: {e}"),
@@ -7,13 +7,14 @@ use anyhow::Context;
 
 use aws_lambda::AwsLambdaTransport;
 use fronted_http::FrontedHttpTransport;
-use geph5_broker_protocol::BrokerClient;
+use geph5_broker_protocol::{BrokerClient, Capabilities};
 use itertools::Itertools;
 use nanorpc::DynRpcTransport;
 use race::RaceTransport;
 
 use serde::{Deserialize, Serialize};
 use sillad::tcp::TcpDialer;
+use smol::lock::OnceCell;
 use std::net::SocketAddr;
 
 use crate::client::{Config, CtxField};
@@ -45,7 +46,7 @@ impl BrokerSource {
                 host: None,
             }),
             BrokerSource::DirectTcp(dest_addr) => {
-                DynRpcTransport::new(nanorpc_sillad::DialerTransport(TcpDialer {
+                DynRpcTransport::new(nanorpc_sillad::DialerTransport::new(TcpDialer {
                     dest_addr: *dest_addr,
                 }))
             }
@@ -87,3 +88,24 @@ static BROKER_CLIENT: CtxField<Option<BrokerClient>> = |ctx| {
         .as_ref()
         .map(|src| BrokerClient::from(src.rpc_transport()))
 };
+
+/// Gets the capabilities of the broker, negotiating them on first use. Brokers that cannot negotiate are treated as having [Capabilities::legacy], though negotiation is retried next time.
+pub async fn broker_capabilities(ctx: &AnyCtx<Config>) -> anyhow::Result<Capabilities> {
+    let broker = broker_client(ctx)?;
+    let caps = ctx
+        .get(BROKER_CAPABILITIES)
+        .get_or_try_init(|| broker.get_capabilities(Capabilities::current()))
+        .await;
+    match caps {
+        Ok(caps) => Ok(caps.clone()),
+        Err(err) => {
+            tracing::warn!(
+                err = debug(err),
+                "broker did not negotiate capabilities, assuming legacy"
+            );
+            Ok(Capabilities::legacy())
+        }
+    }
+}
+
+static BROKER_CAPABILITIES: CtxField<OnceCell<Capabilities>> = |_| OnceCell::new();
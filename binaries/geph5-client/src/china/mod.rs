@@ -1,26 +1,23 @@
-use std::collections::HashSet;
+use std::net::IpAddr;
 
 use once_cell::sync::Lazy;
 
-/// List of all Chinese domains.
-static DOMAINS: Lazy<HashSet<String>> = Lazy::new(|| {
-    let ss = include_str!("china-domains.txt");
-    ss.split_ascii_whitespace()
-        .filter(|v| v.len() > 1)
-        .map(|v| v.to_string())
-        .collect()
+use crate::rule_lists::HostList;
+
+/// List of all Chinese domains and IP networks.
+static CHINA: Lazy<HostList> = Lazy::new(|| {
+    let mut list = String::from(include_str!("china-domains.txt"));
+    list.push('\n');
+    list.push_str(include_str!("china-ips.txt"));
+    HostList::parse(&list)
 });
 
 /// Returns true if the given host is Chinese
 pub fn is_chinese_host(host: &str) -> bool {
-    // explode by dots
-    let exploded: Vec<_> = host.split('.').collect();
-    // join & lookup in loop
-    for i in 0..exploded.len() {
-        let candidate = (exploded[i..]).join(".");
-        if DOMAINS.contains(&candidate) {
-            return true;
-        }
-    }
-    false
+    CHINA.contains_domain(host)
+}
+
+/// Returns true if the given IP address is Chinese
+pub fn is_chinese_ip(ip: IpAddr) -> bool {
+    CHINA.contains_ip(ip)
 }
@@ -2,27 +2,30 @@ use anyctx::AnyCtx;
 
 use anyhow::Context;
 use bytes::Bytes;
-use futures_util::{future::Shared, task::noop_waker, FutureExt, TryFutureExt};
+use futures_util::{FutureExt, TryFutureExt, future::Shared, task::noop_waker};
 use geph5_broker_protocol::{Credential, ExitList, UserInfo};
 use nanorpc::DynRpcTransport;
 use sillad::Pipe;
 use smol::future::FutureExt as _;
-use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use serde::{Deserialize, Serialize};
 use smolscale::immortal::Immortal;
 
 use crate::{
     auth::{auth_loop, get_auth_token},
-    broker::{broker_client, BrokerSource},
+    broker::{BrokerSource, broker_client},
     client_inner::{client_inner, open_conn},
     control_prot::{
         ControlClient, ControlProtocolImpl, ControlService, DummyControlProtocolTransport,
     },
-    database::db_read_or_wait,
+    database::{cache_path, db_read_or_wait},
     http_proxy::http_proxy_serve,
     pac::pac_serve,
+    proxy_auth::ProxyAccess,
     route::ExitConstraint,
+    rule_lists::{RuleListSource, rule_lists_loop},
+    rules::{RoutingRule, stop_named_exits},
     socks5::socks5_loop,
     vpn::{recv_vpn_packet, send_vpn_packet, vpn_loop},
 };
@@ -32,8 +35,14 @@ pub struct Config {
     pub socks5_listen: Option<SocketAddr>,
     pub http_proxy_listen: Option<SocketAddr>,
     pub pac_listen: Option<SocketAddr>,
+    /// Who may use the SOCKS5 and HTTP proxies.
+    #[serde(default)]
+    pub proxy_access: ProxyAccess,
 
     pub control_listen: Option<SocketAddr>,
+    /// If set, the control protocol only serves requests that carry this token, as sealed by [nanorpc_sillad::PresharedToken].
+    #[serde(default)]
+    pub control_secret: Option<String>,
     pub exit_constraint: ExitConstraint,
     #[serde(default)]
     pub bridge_mode: BridgeMode,
@@ -44,14 +53,33 @@ pub struct Config {
 
     #[serde(default)]
     pub vpn: bool,
+    /// Whitelists dialed addresses from the VPN of another client, without running a VPN. Set for the clients that tunnel to named exits on behalf of a client in VPN mode, whose connections would otherwise loop back into the tun device.
+    #[serde(skip)]
+    pub(crate) vpn_whitelist_only: bool,
     #[serde(default)]
     pub spoof_dns: bool,
+    /// Passes Chinese domains through, as a built-in routing rule after `routing_rules`.
     #[serde(default)]
     pub passthrough_china: bool,
+    /// Rules that decide, in order, whether connections go directly, through the tunnel, to another exit, or nowhere.
+    #[serde(default)]
+    pub routing_rules: Vec<RoutingRule>,
+    /// Exit constraints that routing rules can send connections to by name.
+    #[serde(default)]
+    pub named_exits: BTreeMap<String, ExitConstraint>,
+    /// Lists of domains and CIDRs that routing rules can match by name, which are kept up to date in the background.
+    #[serde(default)]
+    pub rule_lists: BTreeMap<String, RuleListSource>,
+    /// Keep sessions alive across broken pipes, so that proxied connections survive brief network changes. Only used with exits that advertise session resumption in their metadata.
+    #[serde(default)]
+    pub session_resumption: bool,
     #[serde(default)]
     pub dry_run: bool,
     #[serde(default)]
     pub credentials: Credential,
+    /// If set, the client enrolls a device key under this name after logging in with the credentials, and logs in with the device key from then on.
+    #[serde(default)]
+    pub device_name: Option<String>,
 
     #[serde(default)]
     pub sess_metadata: serde_json::Value,
@@ -64,6 +92,9 @@ pub struct BrokerKeys {
     pub master: String,
     pub mizaru_free: String,
     pub mizaru_plus: String,
+    /// Mizaru keys of custom tiers, by tier name.
+    #[serde(default)]
+    pub mizaru_tiers: BTreeMap<String, String>,
 }
 
 impl Config {
@@ -77,6 +108,31 @@ impl Config {
         this.control_listen = None;
         this
     }
+
+    /// Create a config for a client that tunnels to the named exit of a routing rule on behalf of this one, without listening or routing on its own. It keeps its own cache, next to this one's, and logs in with the credentials rather than enrolling another device.
+    pub fn for_named_exit(&self, name: &str, exit_constraint: ExitConstraint) -> Self {
+        let mut this = self.clone();
+        this.exit_constraint = exit_constraint;
+        this.socks5_listen = None;
+        this.http_proxy_listen = None;
+        this.pac_listen = None;
+        this.control_listen = None;
+        this.vpn = false;
+        this.vpn_whitelist_only = self.vpn || self.vpn_whitelist_only;
+        let mut cache = cache_path(self).into_os_string();
+        cache.push(format!(
+            ".exit-{}",
+            hex::encode(&blake3::hash(name.as_bytes()).as_bytes()[..8])
+        ));
+        this.cache = Some(cache.into());
+        this.device_name = None;
+        this.spoof_dns = false;
+        this.passthrough_china = false;
+        this.routing_rules = vec![];
+        this.named_exits = BTreeMap::new();
+        this.rule_lists = BTreeMap::new();
+        this
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
@@ -94,7 +150,7 @@ impl Default for BridgeMode {
 
 pub struct Client {
     task: Shared<smol::Task<Result<(), Arc<anyhow::Error>>>>,
-    ctx: AnyCtx<Config>,
+    pub(crate) ctx: AnyCtx<Config>,
 }
 
 impl Client {
@@ -201,16 +257,25 @@ async fn client_main(ctx: AnyCtx<Config>) -> anyhow::Result<()> {
             .await
     } else {
         let vpn_loop = vpn_loop(&ctx);
+        let _stop_named_exits = scopeguard::guard((), |_| stop_named_exits(&ctx));
 
         let _client_loop = Immortal::spawn(client_inner(ctx.clone()));
 
         let rpc_serve = async {
             if let Some(control_listen) = ctx.init().control_listen {
-                nanorpc_sillad::rpc_serve(
-                    sillad::tcp::TcpListener::bind(control_listen).await?,
-                    ControlService(ControlProtocolImpl { ctx: ctx.clone() }),
-                )
-                .await?;
+                let mut server =
+                    nanorpc_sillad::RpcServer::new(ControlService(ControlProtocolImpl {
+                        ctx: ctx.clone(),
+                    }))
+                    .max_line_length(64 * 1024)
+                    .idle_timeout(Duration::from_secs(60))
+                    .max_connections(64);
+                if let Some(secret) = &ctx.init().control_secret {
+                    server = server.authenticator(nanorpc_sillad::PresharedToken::new(secret));
+                }
+                server
+                    .serve(sillad::tcp::TcpListener::bind(control_listen).await?)
+                    .await?;
                 anyhow::Ok(())
             } else {
                 smol::future::pending().await
@@ -230,6 +295,7 @@ async fn client_main(ctx: AnyCtx<Config>) -> anyhow::Result<()> {
             )
             .race(rpc_serve)
             .race(pac_serve(&ctx))
+            .race(rule_lists_loop(&ctx))
             .await
     }
 }
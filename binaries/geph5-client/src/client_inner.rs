@@ -3,14 +3,19 @@ use anyhow::Context;
 use bytes::Bytes;
 use clone_macro::clone;
 use ed25519_dalek::VerifyingKey;
-use futures_util::{future::join_all, AsyncReadExt as _};
+use futures_util::{future::join_all, AsyncReadExt as _, AsyncWriteExt as _};
+use geph5_broker_protocol::{exit_metadata, ExitDescriptor, MetadataRequirement};
 use geph5_misc_rpc::{
-    exit::{ClientCryptHello, ClientExitCryptPipe, ClientHello, ExitHello, ExitHelloInner},
+    exit::{
+        ClientCryptHello, ClientExitCryptPipe, ClientHello, ExitHello, ExitHelloInner,
+        HybridClientSecret, SessionResumeRequest, SessionResumeResponse,
+        CRYPT_HELLO_X25519_MLKEM768, CRYPT_HELLO_X25519_REKEYING, SESSION_RESUME_MAGIC,
+    },
     read_prepend_length, write_prepend_length,
 };
 use nursery_macro::nursery;
 
-use picomux::{LivenessConfig, PicoMux};
+use picomux::{LivenessConfig, PicoMux, ResumeConfig};
 use rand::Rng;
 use sillad::{dialer::Dialer as _, EitherPipe, Pipe};
 use smol::future::FutureExt as _;
@@ -26,7 +31,7 @@ use std::{
 use stdcode::StdcodeSerializeExt;
 
 use crate::{
-    auth::get_connect_token, china::is_chinese_host, client::CtxField, control_prot::{ConnectedInfo, CURRENT_CONN_INFO}, route::get_dialer, spoof_dns::fake_dns_backtranslate, stats::{stat_incr_num, stat_set_num}, vpn::smart_vpn_whitelist, ConnInfo
+    auth::get_connect_token, client::CtxField, control_prot::{ConnectedInfo, CURRENT_CONN_INFO}, route::get_dialer, rules::{named_exit_ctx, route_action, RouteAction}, spoof_dns::fake_dns_backtranslate, stats::{stat_incr_num, stat_set_num}, vpn::smart_vpn_whitelist, ConnInfo
 };
 
 use super::Config;
@@ -50,8 +55,11 @@ pub async fn open_conn(
         dest_addr.to_string()
     };
 
-    if let Some((dest_host, _)) = dest_addr.rsplit_once(":") {
-        if whitelist_host(ctx, dest_host) {
+    match route_action(ctx, protocol, &dest_addr) {
+        RouteAction::Block => {
+            anyhow::bail!("connection to {dest_addr} blocked by routing rules")
+        }
+        RouteAction::Direct if protocol == "tcp" => {
             let addrs = smol::net::resolve(&dest_addr).await?;
             for addr in addrs.iter() {
                 smart_vpn_whitelist(ctx, addr.ip());
@@ -62,8 +70,29 @@ pub async fn open_conn(
             );
             return Ok(sillad::tcp::HappyEyeballsTcpDialer(addrs).dial().await?);
         }
+        RouteAction::Direct => {
+            tracing::debug!(
+                dest_addr = debug(&dest_addr),
+                protocol,
+                "only TCP can bypass the tunnel, so tunneling instead"
+            );
+        }
+        RouteAction::Exit(name) => {
+            let exit_ctx = named_exit_ctx(ctx, &name)?;
+            return Box::pin(open_conn(&exit_ctx, protocol, &dest_addr)).await;
+        }
+        RouteAction::Proxy => {}
     }
 
+    open_tunneled_conn(ctx, protocol, &dest_addr).await
+}
+
+/// Opens a connection through the tunnel, regardless of the routing rules.
+pub async fn open_tunneled_conn(
+    ctx: &AnyCtx<Config>,
+    protocol: &str,
+    dest_addr: &str,
+) -> anyhow::Result<Box<dyn sillad::Pipe>> {
     let (send, recv) = oneshot::channel();
     let elem = (format!("{protocol}${dest_addr}"), send);
     let _ = ctx.get(CONN_REQ_CHAN).0.send(elem).await;
@@ -78,31 +107,6 @@ pub async fn open_conn(
     Ok(Box::new(conn))
 }
 
-
-fn whitelist_host(ctx: &AnyCtx<Config>, host: &str) -> bool {
-    if host.is_empty() || host.contains("[") {
-        return false;
-    }
-    if let Ok(ip) = IpAddr::from_str(host) {
-        match ip {
-            IpAddr::V4(v4) => v4.is_private() || v4.is_loopback() || v4.is_link_local(),
-            IpAddr::V6(v6) => v6.is_loopback(),
-        }
-    } else {
-        if ctx.init().passthrough_china {
-            if let Some(domain) = psl::domain_str(host) {
-                if is_chinese_host(domain) {
-                    return true;
-                }
-            }
-        }
-        match psl::suffix(host.as_bytes()) {
-            None => false,
-            Some(suf) => !suf.is_known(),
-        }
-    }
-}
-
 type ChanElem = (String, oneshot::Sender<picomux::Stream>);
 
 static CONN_REQ_CHAN: CtxField<(
@@ -113,6 +117,7 @@ static CONN_REQ_CHAN: CtxField<(
     (a, b)
 };
 
+/// How many sessions the client keeps open at once. Exits count per-token session caps in multiples of this, so changing it needs a matching change to `SESSIONS_PER_CLIENT` in geph5-exit.
 pub static CONCURRENCY: usize = 8;
 
 #[tracing::instrument(skip_all)]
@@ -129,6 +134,7 @@ pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
 
         let ctx = ctx.clone();
         smolscale::spawn(async move {
+            let mut resumable = None;
             loop {
                 let once = async {
                     *ctx.get(CURRENT_CONN_INFO).lock() = ConnInfo::Connecting;
@@ -142,7 +148,7 @@ pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
                             "dial completed"
                         );
 
-                        let authed_pipe = client_auth(&ctx, raw_pipe, pubkey)
+                        let authed_pipe = client_auth(&ctx, raw_pipe, pubkey, exit.clone())
                             .await
                             .context("could not client auth")?;
 
@@ -165,7 +171,9 @@ pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
                         exit: exit.clone(),
                     });
                     let addr: SocketAddr = authed_pipe.remote_addr().unwrap_or("").parse()?;
-                    proxy_loop(ctx.clone(), authed_pipe, instance)
+                    let (mux, fresh) =
+                        start_session(&ctx, authed_pipe, &exit, &mut resumable).await?;
+                    proxy_loop(ctx.clone(), mux, fresh, instance, addr)
                         .await
                         .context(format!("inner connection to {addr} failed"))
 
@@ -194,22 +202,76 @@ pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
     unreachable!()
 }
 
-#[tracing::instrument(skip_all, fields(instance=instance, server=display(authed_pipe.remote_addr().unwrap_or("(none)"))))]
-async fn proxy_loop(
-    ctx: AnyCtx<Config>,
-    authed_pipe: impl Pipe,
-    instance: usize,
-) -> anyhow::Result<()> {
+/// Starts a mux session over an authenticated pipe, returning the mux and whether it's a fresh session. With session resumption on, and supported by the exit, the previous session of this instance is moved onto the pipe if the exit still has it.
+async fn start_session(
+    ctx: &AnyCtx<Config>,
+    mut authed_pipe: impl Pipe,
+    exit: &ExitDescriptor,
+    resumable: &mut Option<([u8; 32], Arc<PicoMux>)>,
+) -> anyhow::Result<(Arc<PicoMux>, bool)> {
+    // exits that cannot resume sessions would wait for picomux frames forever
+    let exit_resumes = exit.satisfies(&MetadataRequirement::Equals(
+        exit_metadata::SESSION_RESUMPTION.to_string(),
+        "true".to_string(),
+    ));
+    if !ctx.init().session_resumption || !exit_resumes {
+        let (read, write) = authed_pipe.split();
+        let mut mux = PicoMux::new(read, write);
+        mux.set_liveness(LivenessConfig {
+            ping_interval: Duration::from_secs(1800),
+            timeout: Duration::from_secs(3),
+        });
+        return Ok((Arc::new(mux), true));
+    }
+
+    let request = match resumable.as_ref().filter(|(_, mux)| mux.is_alive()) {
+        Some((token, _)) => SessionResumeRequest::Resume(*token),
+        None => SessionResumeRequest::New,
+    };
+    authed_pipe.write_all(&[SESSION_RESUME_MAGIC]).await?;
+    write_prepend_length(&request.stdcode(), &mut authed_pipe).await?;
+    let response = read_prepend_length(&mut authed_pipe)
+        .timeout(Duration::from_secs(10))
+        .await
+        .context("timed out waiting for session resume response")??;
+    let response: SessionResumeResponse = stdcode::deserialize(&response)
+        .context("cannot deserialize session resume response")?;
     let (read, write) = authed_pipe.split();
-    let mut mux = PicoMux::new(read, write);
+    if response.resumed {
+        match resumable.as_ref() {
+            Some((token, mux)) if *token == response.token => {
+                tracing::debug!("resumed the previous session");
+                mux.reattach(read, write)?;
+                return Ok((mux.clone(), false));
+            }
+            _ => anyhow::bail!("exit resumed a session we never had"),
+        }
+    }
+
+    let resume_cfg = ResumeConfig::default();
+    let mut mux = PicoMux::new_resumable(read, write, resume_cfg);
+    // dead pipes are detected by the resumable link, so pings should only fail once the session can no longer be resumed
     mux.set_liveness(LivenessConfig {
         ping_interval: Duration::from_secs(1800),
-        timeout: Duration::from_secs(3),
+        timeout: resume_cfg.resume_timeout,
     });
     let mux = Arc::new(mux);
+    *resumable = Some((response.token, mux.clone()));
+    Ok((mux, true))
+}
 
+#[tracing::instrument(skip_all, fields(instance=instance, server=display(server)))]
+async fn proxy_loop(
+    ctx: AnyCtx<Config>,
+    mux: Arc<PicoMux>,
+    fresh: bool,
+    instance: usize,
+    server: SocketAddr,
+) -> anyhow::Result<()> {
     // we first register the session metadata
-    mux.open(&serde_json::to_vec(&ctx.init().sess_metadata)?).await?;
+    if fresh {
+        mux.open(&serde_json::to_vec(&ctx.init().sess_metadata)?).await?;
+    }
 
     async {
         nursery!({
@@ -239,6 +301,10 @@ async fn proxy_loop(
             }
         })
     }.or(mux.wait_until_dead())
+    .or(async {
+        mux.wait_detached().await;
+        anyhow::bail!("session lost its underlying pipe")
+    })
     .await
 }
 
@@ -247,6 +313,8 @@ async fn client_auth(
     ctx: &AnyCtx<Config>,
     mut pipe: impl Pipe,
     pubkey: VerifyingKey,
+    // owned, so that the returned pipe does not borrow it
+    exit: ExitDescriptor,
 ) -> anyhow::Result<impl Pipe> {
     let server = pipe.remote_addr().unwrap_or("").to_string();
 
@@ -256,7 +324,7 @@ async fn client_auth(
         let (level, token, sig) = get_connect_token(ctx)
             .await
             .context("cannot get connect token")?;
-        tracing::info!(level=debug(level), "authentication with a connect token");
+        tracing::info!(level=debug(&level), "authentication with a connect token");
         (level, token, sig).stdcode().into()
     };
     match pipe.shared_secret().map(|s| s.to_owned()) {
@@ -288,9 +356,25 @@ async fn client_auth(
         None => {
             tracing::debug!(server, "requiring full authentication");
             let my_esk = x25519_dalek::EphemeralSecret::random_from_rng(rand::thread_rng());
+            let exit_supports = |hello: &str| {
+                exit.satisfies(&MetadataRequirement::Contains(
+                    exit_metadata::CRYPT_HELLOS.to_string(),
+                    hello.to_string(),
+                ))
+            };
+            // use the strongest key exchange that the exit supports, since older exits cannot parse newer hellos
+            let (hybrid_secret, crypt_hello) = if exit_supports(CRYPT_HELLO_X25519_MLKEM768) {
+                let (secret, hello) = HybridClientSecret::generate();
+                (Some(secret), hello)
+            } else if exit_supports(CRYPT_HELLO_X25519_REKEYING) {
+                (None, ClientCryptHello::X25519Rekeying((&my_esk).into()))
+            } else {
+                (None, ClientCryptHello::X25519((&my_esk).into()))
+            };
+            let rekeying = !matches!(crypt_hello, ClientCryptHello::X25519(_));
             let client_hello = ClientHello {
                 credentials,
-                crypt_hello: ClientCryptHello::X25519((&my_esk).into()),
+                crypt_hello,
             };
             write_prepend_length(&client_hello.stdcode(), &mut pipe).await?;
             tracing::trace!(server, "wrote client hello");
@@ -313,10 +397,26 @@ async fn client_auth(
                     )
                 }
                 ExitHelloInner::X25519(their_epk) => {
+                    anyhow::ensure!(
+                        hybrid_secret.is_none(),
+                        "exit answered our hybrid key exchange with plain X25519"
+                    );
                     let shared_secret = my_esk.diffie_hellman(&their_epk);
                     let read_key = blake3::derive_key("e2c", shared_secret.as_bytes());
                     let write_key = blake3::derive_key("c2e", shared_secret.as_bytes());
-                    Ok(EitherPipe::Right(ClientExitCryptPipe::new(
+                    Ok(EitherPipe::Right(if rekeying {
+                        ClientExitCryptPipe::new_rekeying(pipe, read_key, write_key)
+                    } else {
+                        ClientExitCryptPipe::new(pipe, read_key, write_key)
+                    }))
+                }
+                ExitHelloInner::X25519MlKem768(their_epk, ciphertext) => {
+                    let shared_secret = hybrid_secret
+                        .context("exit sent a hybrid answer to a non-hybrid key exchange")?
+                        .finish(&their_epk, &ciphertext)?;
+                    let read_key = blake3::derive_key("e2c", &shared_secret);
+                    let write_key = blake3::derive_key("c2e", &shared_secret);
+                    Ok(EitherPipe::Right(ClientExitCryptPipe::new_rekeying(
                         pipe, read_key, write_key,
                     )))
                 }
@@ -38,6 +38,9 @@ pub trait ControlProtocol {
         username: String,
         password: String,
     ) -> Result<String, String>;
+    async fn regenerate_secret(&self, secret: String) -> Result<String, String>;
+    async fn delete_account(&self, secret: String) -> Result<(), String>;
+    async fn login_history(&self, secret: String) -> Result<Vec<u64>, String>;
     async fn stat_history(&self, stat: String) -> Result<Vec<f64>, String>;
     async fn exit_list(&self) -> Result<Vec<ExitDescriptor>, String>;
     async fn latest_news(&self, lang: String) -> Result<Vec<NewsItem>, String>;
@@ -145,7 +148,9 @@ impl ControlProtocol for ControlProtocolImpl {
                     .map_err(|e| format!("{:?}", e))?
                     .ok_or_else(|| "no such user".to_string())?;
                 Ok(UserInfo {
-                    level: if res.plus_expires_unix.is_some() {
+                    level: if let Some(tier) = &res.tier {
+                        AccountLevel::from_name(tier)
+                    } else if res.plus_expires_unix.is_some() {
                         AccountLevel::Plus
                     } else {
                         AccountLevel::Free
@@ -230,6 +235,33 @@ impl ControlProtocol for ControlProtocolImpl {
             .map_err(|e| format!("{:?}", e))?)
     }
 
+    async fn regenerate_secret(&self, secret: String) -> Result<String, String> {
+        Ok(broker_client(&self.ctx)
+            .map_err(|e| format!("{:?}", e))?
+            .regenerate_secret(geph5_broker_protocol::Credential::Secret(secret))
+            .await
+            .map_err(|e| format!("{:?}", e))?
+            .map_err(|e| format!("{:?}", e))?)
+    }
+
+    async fn delete_account(&self, secret: String) -> Result<(), String> {
+        broker_client(&self.ctx)
+            .map_err(|e| format!("{:?}", e))?
+            .delete_account(geph5_broker_protocol::Credential::Secret(secret))
+            .await
+            .map_err(|e| format!("{:?}", e))?
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    async fn login_history(&self, secret: String) -> Result<Vec<u64>, String> {
+        Ok(broker_client(&self.ctx)
+            .map_err(|e| format!("{:?}", e))?
+            .get_login_history(geph5_broker_protocol::Credential::Secret(secret))
+            .await
+            .map_err(|e| format!("{:?}", e))?
+            .map_err(|e| format!("{:?}", e))?)
+    }
+
     async fn stat_history(&self, stat: String) -> Result<Vec<f64>, String> {
         Ok(vec![1.0, 2.0, 3.0])
     }
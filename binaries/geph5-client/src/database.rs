@@ -2,28 +2,24 @@ use anyctx::AnyCtx;
 use event_listener::Event;
 use sqlx::{pool::PoolOptions, Row};
 use sqlx::{sqlite::SqliteConnectOptions, SqlitePool};
-use std::str::FromStr;
+use std::{path::PathBuf, str::FromStr};
 use stdcode::StdcodeSerializeExt;
 
 use crate::client::{Config, CtxField};
 
+/// Where the client with this config keeps its cache.
+pub fn cache_path(cfg: &Config) -> PathBuf {
+    cfg.cache.clone().unwrap_or_else(|| {
+        dirs::config_dir().unwrap().join(format!(
+            "geph5-persist-{}.db",
+            hex::encode(blake3::hash(&cfg.credentials.stdcode()).as_bytes())
+        ))
+    })
+}
+
 static DATABASE: CtxField<SqlitePool> = |ctx| {
     // TODO this somehow does not make all the connections share the same db?
-    let db_path = ctx
-        .init()
-        .cache
-        .as_ref()
-        .map(|s| s.to_string_lossy().to_string())
-        .unwrap_or_else(|| {
-            dirs::config_dir()
-                .unwrap()
-                .join(format!(
-                    "geph5-persist-{}.db",
-                    hex::encode(blake3::hash(&ctx.init().credentials.stdcode()).as_bytes())
-                ))
-                .to_string_lossy()
-                .to_string()
-        });
+    let db_path = cache_path(ctx.init()).to_string_lossy().to_string();
     tracing::debug!("INITIALIZING DATABASE");
     let options = SqliteConnectOptions::from_str(&db_path)
         .unwrap()
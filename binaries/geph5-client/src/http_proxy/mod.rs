@@ -18,6 +18,10 @@ pub async fn http_proxy_serve(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
                     continue;
                 }
             };
+            if !ctx.init().proxy_access.allows_source(addr.ip()) {
+                tracing::debug!(%addr, "refusing HTTP proxy connection from a disallowed source");
+                continue;
+            }
             let ctx = ctx.clone();
             let cloned_server = shared_server.clone();
             join_set.spawn(async move {
@@ -52,6 +56,21 @@ async fn server_dispatch(
     proxy_server: SharedProxyServer,
     ctx: AnyCtx<Config>,
 ) -> std::io::Result<Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty<Bytes>>>> {
+    let access = &ctx.init().proxy_access;
+    let user = if access.requires_login() {
+        let user = req
+            .headers()
+            .get("Proxy-Authorization")
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| access.check_basic_auth(header));
+        if user.is_none() {
+            tracing::debug!(%client_addr, "HTTP proxy request without a valid login");
+            return Ok(make_proxy_auth_required());
+        }
+        user
+    } else {
+        None
+    };
     let host = match host_addr(req.uri()) {
         None => {
             if req.uri().authority().is_some() {
@@ -154,6 +173,7 @@ async fn server_dispatch(
                     );
                     let stream = open_conn(&ctx, "tcp", &host.to_string()).await;
                     if let Ok(stream) = stream {
+                        let stream = UserCountedPipe::wrap(&ctx, user.as_deref(), stream);
                         establish_connect_tunnel(upgraded, stream, client_addr).await
                     }
                 }
@@ -176,16 +196,31 @@ async fn server_dispatch(
         set_conn_keep_alive(req.version(), req.headers_mut(), conn_keep_alive);
         let (parts, body) = req.into_parts();
         let body = match body.collect().await {
-            Ok(c) => c.boxed(),
+            Ok(c) => c.to_bytes(),
             Err(_) => return Ok(make_bad_request()),
         };
+        if let Some(user) = &user {
+            count_user_bytes(&ctx, user, false, body.len());
+        }
+        let body = Full::new(body).boxed();
         let mut res: Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty<Bytes>>> =
             match proxy_server
                 .client
                 .request(Request::from_parts(parts, body))
                 .await
             {
-                Ok(res) => res.map(|b| HttpEither::Left(b.boxed())),
+                Ok(res) => res.map(|b| match user {
+                    Some(user) => HttpEither::Left(
+                        b.map_frame(move |frame| {
+                            if let Some(data) = frame.data_ref() {
+                                count_user_bytes(&ctx, &user, true, data.len());
+                            }
+                            frame
+                        })
+                        .boxed(),
+                    ),
+                    None => HttpEither::Left(b.boxed()),
+                }),
                 Err(err) => {
                     tracing::trace!(
                         method = %method,
@@ -224,7 +259,10 @@ use http::{
 };
 use http_body_util::{combinators::BoxBody, BodyExt, Either as HttpEither, Empty, Full};
 use hyper::{
-    body::Incoming, service::service_fn, upgrade::Upgraded, Request, Response, StatusCode,
+    body::Incoming,
+    service::service_fn,
+    upgrade::Upgraded,
+    Request, Response, StatusCode,
 };
 use tokio::task::JoinSet;
 
@@ -285,9 +323,23 @@ fn make_bad_request() -> Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty
     resp
 }
 
+fn make_proxy_auth_required() -> Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty<Bytes>>> {
+    let mut resp = make_bad_request();
+    *resp.status_mut() = StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+    resp.headers_mut().insert(
+        "Proxy-Authenticate",
+        HeaderValue::from_static("Basic realm=\"geph5\""),
+    );
+    resp
+}
+
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-use crate::{client_inner::open_conn, Config};
+use crate::{
+    client_inner::open_conn,
+    proxy_auth::{count_user_bytes, UserCountedPipe},
+    Config,
+};
 
 use self::address::{host_addr, Address};
 fn authority_addr(scheme_str: Option<&str>, authority: &Authority) -> Option<Address> {
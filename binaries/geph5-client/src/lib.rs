@@ -3,7 +3,10 @@ pub use broker::broker_client;
 pub use client::Client;
 pub use client::{BridgeMode, BrokerKeys, Config};
 pub use control_prot::{ConnInfo, ControlClient};
+pub use proxy_auth::{ProxyAccess, ProxyUser};
 pub use route::ExitConstraint;
+pub use rule_lists::RuleListSource;
+pub use rules::{RouteAction, RoutingRule, RuleSet};
 
 mod auth;
 mod broker;
@@ -16,7 +19,10 @@ mod http_proxy;
 pub mod logs;
 
 mod pac;
+mod proxy_auth;
 mod route;
+mod rule_lists;
+mod rules;
 mod socks5;
 mod spoof_dns;
 mod stats;
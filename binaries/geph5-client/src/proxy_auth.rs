@@ -0,0 +1,263 @@
+use std::{
+    net::IpAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyctx::AnyCtx;
+use base64::Engine as _;
+use futures_util::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use socksv5::v5::{SocksV5AuthMethod, read_handshake, write_auth_method};
+
+use crate::{Config, stats::stat_incr_num};
+
+/// Who may use the SOCKS5 and HTTP proxies. This matters once they listen on more than localhost, for example to share one client on a LAN.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ProxyAccess {
+    /// If not empty, connections must log in as one of these users.
+    #[serde(default)]
+    pub users: Vec<ProxyUser>,
+    /// If not empty, only connections from these networks are served.
+    #[serde(default)]
+    pub allowed_sources: Vec<IpNet>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProxyUser {
+    pub username: String,
+    pub password: String,
+}
+
+impl ProxyAccess {
+    pub fn allows_source(&self, ip: IpAddr) -> bool {
+        self.allowed_sources.is_empty() || self.allowed_sources.iter().any(|net| net.contains(&ip))
+    }
+
+    pub fn requires_login(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    /// Whether the username and password belong to a user.
+    pub fn check_login(&self, username: &str, password: &str) -> bool {
+        // comparing hashes takes constant time
+        let password = blake3::hash(password.as_bytes());
+        self.users.iter().any(|user| {
+            user.username == username && blake3::hash(user.password.as_bytes()) == password
+        })
+    }
+
+    /// Checks the value of an HTTP `Proxy-Authorization` header, returning the user that it logs in as.
+    pub fn check_basic_auth(&self, header: &str) -> Option<String> {
+        let (scheme, credentials) = header.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let credentials = base64::engine::general_purpose::STANDARD
+            .decode(credentials.trim())
+            .ok()?;
+        let (username, password) = std::str::from_utf8(&credentials).ok()?.split_once(':')?;
+        self.check_login(username, password)
+            .then(|| username.to_string())
+    }
+}
+
+/// Negotiates the SOCKS5 authentication method, logging in with a username and password (RFC 1929) if the proxy requires it. Returns the user that logged in, if any.
+pub async fn socks5_login(
+    read: &mut (impl AsyncRead + Unpin),
+    write: &mut (impl AsyncWrite + Unpin),
+    access: &ProxyAccess,
+) -> anyhow::Result<Option<String>> {
+    if !access.requires_login() {
+        let _handshake = read_handshake(&mut *read).await?;
+        write_auth_method(&mut *write, SocksV5AuthMethod::Noauth).await?;
+        return Ok(None);
+    }
+
+    let mut header = [0u8; 2];
+    read.read_exact(&mut header).await?;
+    anyhow::ensure!(header[0] == 5, "not a SOCKS5 handshake");
+    let mut methods = vec![0u8; header[1] as usize];
+    read.read_exact(&mut methods).await?;
+    if !methods.contains(&2) {
+        // no acceptable methods
+        write.write_all(&[5, 0xff]).await?;
+        anyhow::bail!("SOCKS5 client cannot log in with a password");
+    }
+    write.write_all(&[5, 2]).await?;
+
+    let mut header = [0u8; 2];
+    read.read_exact(&mut header).await?;
+    anyhow::ensure!(header[0] == 1, "unknown SOCKS5 login version");
+    let mut username = vec![0u8; header[1] as usize];
+    read.read_exact(&mut username).await?;
+    let mut password_len = [0u8; 1];
+    read.read_exact(&mut password_len).await?;
+    let mut password = vec![0u8; password_len[0] as usize];
+    read.read_exact(&mut password).await?;
+
+    let username = String::from_utf8_lossy(&username).into_owned();
+    if access.check_login(&username, &String::from_utf8_lossy(&password)) {
+        write.write_all(&[1, 0]).await?;
+        Ok(Some(username))
+    } else {
+        write.write_all(&[1, 1]).await?;
+        anyhow::bail!("wrong SOCKS5 login for {username}")
+    }
+}
+
+/// Adds bytes to the counters of a proxy user, which are the `proxy_user.<username>.rx_bytes` and `proxy_user.<username>.tx_bytes` stats.
+pub fn count_user_bytes(ctx: &AnyCtx<Config>, username: &str, rx: bool, n: usize) {
+    let direction = if rx { "rx" } else { "tx" };
+    stat_incr_num(
+        ctx,
+        &format!("proxy_user.{username}.{direction}_bytes"),
+        n as _,
+    );
+}
+
+/// A pipe that counts its bytes toward a proxy user.
+pub struct UserCountedPipe {
+    inner: Box<dyn sillad::Pipe>,
+    ctx: AnyCtx<Config>,
+    username: String,
+}
+
+impl UserCountedPipe {
+    /// Wraps the pipe if there is a user to count toward.
+    pub fn wrap(
+        ctx: &AnyCtx<Config>,
+        username: Option<&str>,
+        inner: Box<dyn sillad::Pipe>,
+    ) -> Box<dyn sillad::Pipe> {
+        match username {
+            Some(username) => Box::new(Self {
+                inner,
+                ctx: ctx.clone(),
+                username: username.to_string(),
+            }),
+            None => inner,
+        }
+    }
+}
+
+impl AsyncRead for UserCountedPipe {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = &res {
+            count_user_bytes(&self.ctx, &self.username, true, *n);
+        }
+        res
+    }
+}
+
+impl AsyncWrite for UserCountedPipe {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = &res {
+            count_user_bytes(&self.ctx, &self.username, false, *n);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+impl sillad::Pipe for UserCountedPipe {
+    fn shared_secret(&self) -> Option<&[u8]> {
+        self.inner.shared_secret()
+    }
+
+    fn protocol(&self) -> &str {
+        self.inner.protocol()
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.inner.remote_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_access() {
+        let access = ProxyAccess {
+            users: vec![ProxyUser {
+                username: "alice".into(),
+                password: "open sesame".into(),
+            }],
+            allowed_sources: vec![
+                "192.168.1.0/24".parse().unwrap(),
+                "::1/128".parse().unwrap(),
+            ],
+        };
+        assert!(access.allows_source("192.168.1.42".parse().unwrap()));
+        assert!(access.allows_source("::1".parse().unwrap()));
+        assert!(!access.allows_source("10.0.0.1".parse().unwrap()));
+        assert!(ProxyAccess::default().allows_source("10.0.0.1".parse().unwrap()));
+
+        assert!(access.check_login("alice", "open sesame"));
+        assert!(!access.check_login("alice", "open sesame!"));
+        assert!(!access.check_login("bob", "open sesame"));
+
+        // "alice:open sesame"
+        assert_eq!(
+            access.check_basic_auth("Basic YWxpY2U6b3BlbiBzZXNhbWU="),
+            Some("alice".into())
+        );
+        assert_eq!(
+            access.check_basic_auth("Bearer YWxpY2U6b3BlbiBzZXNhbWU="),
+            None
+        );
+        assert_eq!(access.check_basic_auth("Basic bm9wZQ=="), None);
+    }
+
+    #[test]
+    fn test_socks5_login() {
+        let access = ProxyAccess {
+            users: vec![ProxyUser {
+                username: "alice".into(),
+                password: "pw".into(),
+            }],
+            allowed_sources: vec![],
+        };
+        smol::block_on(async {
+            let mut request = vec![5, 2, 0, 2, 1, 5];
+            request.extend_from_slice(b"alice");
+            request.push(2);
+            request.extend_from_slice(b"pw");
+            let mut response = vec![];
+            let user = socks5_login(&mut &request[..], &mut response, &access)
+                .await
+                .unwrap();
+            assert_eq!(user.as_deref(), Some("alice"));
+            assert_eq!(response, [5, 2, 1, 0]);
+
+            // clients that cannot log in are turned away
+            let mut response = vec![];
+            assert!(
+                socks5_login(&mut &[5, 1, 0][..], &mut response, &access)
+                    .await
+                    .is_err()
+            );
+            assert_eq!(response, [5, 0xff]);
+        });
+    }
+}
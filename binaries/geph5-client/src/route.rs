@@ -1,4 +1,7 @@
-use std::time::{Duration, SystemTime};
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
 
 use anyctx::AnyCtx;
 use anyhow::Context;
@@ -7,9 +10,12 @@ use async_native_tls::TlsConnector;
 use ed25519_dalek::VerifyingKey;
 
 use geph5_broker_protocol::{
-    AccountLevel, DOMAIN_EXIT_DESCRIPTOR, ExitDescriptor, RouteDescriptor,
+    AccountLevel, Capabilities, DOMAIN_EXIT_DESCRIPTOR, DOMAIN_EXIT_LIST_UPDATE,
+    DOMAIN_ROUTE_BUNDLE, ExitDescriptor, ExitList, ExitListUpdate, MetadataRequirement,
+    RouteBundle, RouteDescriptor, Signed, feature,
 };
 use isocountry::CountryCode;
+use mizaru2::ClientToken;
 use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
 use sillad::{
@@ -23,8 +29,9 @@ use smol_timeout2::TimeoutExt as _;
 
 use crate::{
     auth::get_connect_token,
-    broker::broker_client,
+    broker::{broker_capabilities, broker_client},
     client::{Config, CtxField},
+    database::{db_read, db_write},
     vpn::smart_vpn_whitelist,
 };
 
@@ -36,6 +43,8 @@ pub enum ExitConstraint {
     Hostname(String),
     Country(CountryCode),
     CountryCity(CountryCode, String),
+    /// Narrows down another constraint to exits whose metadata meets every requirement.
+    WithMetadata(Box<ExitConstraint>, Vec<MetadataRequirement>),
 }
 
 /// Gets a sillad Dialer that produces a single, pre-authentication pipe, as well as the public key.
@@ -82,7 +91,13 @@ async fn get_dialer_inner(
     let mut country_constraint = None;
     let mut city_constraint = None;
     let mut hostname_constraint = None;
-    match &ctx.init().exit_constraint {
+    let mut metadata_requirements = vec![];
+    let mut constraint = &ctx.init().exit_constraint;
+    while let ExitConstraint::WithMetadata(inner, requirements) = constraint {
+        metadata_requirements.extend(requirements.iter().cloned());
+        constraint = inner;
+    }
+    match constraint {
         ExitConstraint::Direct(dir) => {
             let (dir, pubkey) = dir
                 .split_once('/')
@@ -108,6 +123,7 @@ async fn get_dialer_inner(
                     city: "".to_string(),
                     load: 0.0,
                     expiry: 0,
+                    metadata: Default::default(),
                 },
                 ConnTestDialer {
                     ping_count: 1,
@@ -125,6 +141,7 @@ async fn get_dialer_inner(
             hostname_constraint = Some(hostname.clone());
         }
         ExitConstraint::Auto => {}
+        ExitConstraint::WithMetadata(..) => unreachable!("metadata constraints were peeled off"),
     }
 
     // First get the conn token
@@ -132,22 +149,8 @@ async fn get_dialer_inner(
         .await
         .context("could not get connect token")?;
 
-    let broker = broker_client(ctx).context("could not get broker client")?;
-    let exits = match level {
-        AccountLevel::Plus => broker.get_exits().await,
-        AccountLevel::Free => broker.get_free_exits().await,
-    }?
-    .map_err(|e| anyhow::anyhow!("broker refused to serve exits: {e}"))?;
+    let exits = get_exit_list(ctx, level).await?;
 
-    let exits = exits
-        .verify(DOMAIN_EXIT_DESCRIPTOR, |their_pk| {
-            if let Some(broker_pk) = &ctx.init().broker_keys {
-                hex::encode(their_pk.as_bytes()) == broker_pk.master
-            } else {
-                true
-            }
-        })
-        .context("could not verify")?;
     // filter for things that fit
     let (pubkey, exit) = if let Some(min) = exits
         .all_exits
@@ -168,7 +171,8 @@ async fn get_dialer_inner(
             } else {
                 true
             };
-            country_pass && city_pass && hostname_pass
+            let metadata_pass = metadata_requirements.iter().all(|req| exit.satisfies(req));
+            country_pass && city_pass && hostname_pass && metadata_pass
         })
         .min_by_key(|e| (e.1.load * 1000.0) as u64)
     {
@@ -195,16 +199,41 @@ async fn get_dialer_inner(
     tracing::debug!(token = display(&conn_token), "CONN TOKEN");
 
     // also get bridges
-    let bridge_routes = broker
-        .get_routes(conn_token, sig, exit.b2e_listen)
-        .await?
-        .map_err(|e| anyhow::anyhow!("broker refused to serve bridge routes: {e}"))?;
-    tracing::debug!(
-        "bridge routes obtained: {}",
-        serde_yaml::to_string(&serde_json::to_value(&bridge_routes)?)?
-    );
-
-    let bridge_dialer = route_to_dialer(ctx, &bridge_routes);
+    let broker = broker_client(ctx).context("could not get broker client")?;
+    let broker_caps = broker_capabilities(ctx).await?;
+    let bridge_routes = if broker_caps.supports(feature::TAILORED_ROUTES) {
+        BrokerRoutes::Signed(Box::new(
+            broker
+                .get_routes_v2(conn_token, sig, exit.b2e_listen, Capabilities::current())
+                .await?
+                .map_err(|e| anyhow::anyhow!("broker refused to serve bridge routes: {e}"))?,
+        ))
+    } else {
+        BrokerRoutes::Unsigned(
+            broker
+                .get_routes(conn_token, sig, exit.b2e_listen)
+                .await?
+                .map_err(|e| anyhow::anyhow!("broker refused to serve bridge routes: {e}"))?,
+        )
+    };
+    let bridge_dialer = match verify_routes(ctx, bridge_routes, conn_token, exit.b2e_listen) {
+        Ok(bridge_routes) => {
+            if let Err(err) = bridge_routes.validate() {
+                tracing::warn!(
+                    err = display(err),
+                    route = display(&bridge_routes),
+                    "bridge routes fail validation"
+                );
+            }
+            let bridge_routes = bridge_routes.simplify();
+            tracing::debug!(route = display(&bridge_routes), "bridge routes obtained");
+            route_to_dialer(ctx, &bridge_routes)
+        }
+        Err(err) => {
+            tracing::warn!(err = debug(err), "refusing bridge routes");
+            FailingDialer.dynamic()
+        }
+    };
 
     let final_dialer = match ctx.init().bridge_mode {
         crate::BridgeMode::Auto => direct_dialer
@@ -217,6 +246,176 @@ async fn get_dialer_inner(
     Ok((*pubkey, exit.clone(), final_dialer))
 }
 
+/// Bridge routes as served by the broker. Only brokers too old to sign routes serve them unsigned.
+enum BrokerRoutes {
+    Signed(Box<Signed<RouteBundle>>),
+    Unsigned(RouteDescriptor),
+}
+
+/// Checks that the routes come from the broker and were issued for this token and exit, before they are turned into a dialer. When broker keys are configured, unsigned routes are refused.
+fn verify_routes(
+    ctx: &AnyCtx<Config>,
+    routes: BrokerRoutes,
+    token: ClientToken,
+    exit_b2e: SocketAddr,
+) -> anyhow::Result<RouteDescriptor> {
+    let broker_keys = &ctx.init().broker_keys;
+    match routes {
+        BrokerRoutes::Signed(signed) => {
+            let bundle = signed
+                .verify(DOMAIN_ROUTE_BUNDLE, |their_pk| {
+                    if let Some(broker_pk) = broker_keys {
+                        hex::encode(their_pk.as_bytes()) == broker_pk.master
+                    } else {
+                        true
+                    }
+                })
+                .context("could not verify routes")?;
+            Ok(bundle.check(token, exit_b2e)?)
+        }
+        BrokerRoutes::Unsigned(route) => {
+            anyhow::ensure!(
+                broker_keys.is_none(),
+                "broker served unsigned routes, but broker keys are configured"
+            );
+            Ok(route)
+        }
+    }
+}
+
+/// How long a stored exit list can still be used when the broker cannot be reached.
+const MAX_STORED_EXIT_LIST_AGE: Duration = Duration::from_secs(86400);
+
+/// How far the timestamp of a signed exit list update can be from our clock. Anything older could be a replayed update.
+const MAX_EXIT_LIST_UPDATE_SKEW: Duration = Duration::from_secs(7200);
+
+/// An exit list in the database, along with when it was last confirmed by the broker.
+#[derive(Serialize, Deserialize)]
+struct StoredExitList {
+    exits: ExitList,
+    /// Unix timestamp, in seconds.
+    confirmed: u64,
+}
+
+/// Gets the exit list for the given level, keeping the last verified list in the database. If the broker does not answer quickly, the stored list is used instead, so that startup does not depend on a slow broker, as long as the broker confirmed it recently enough.
+async fn get_exit_list(ctx: &AnyCtx<Config>, level: AccountLevel) -> anyhow::Result<ExitList> {
+    let db_key = format!("exit_list_{}", level.name());
+    let stored: Option<StoredExitList> = db_read(ctx, &db_key)
+        .await?
+        .and_then(|bts| serde_json::from_slice(&bts).ok());
+    let now = unix_now();
+    let stored_fresh = stored.as_ref().is_some_and(|stored| {
+        now.saturating_sub(stored.confirmed) <= MAX_STORED_EXIT_LIST_AGE.as_secs()
+    });
+    let fetch = fetch_exit_list(ctx, level, stored.as_ref().map(|s| s.exits.clone()));
+    let fetched = if stored_fresh {
+        fetch
+            .timeout(Duration::from_secs(3))
+            .await
+            .unwrap_or_else(|| Err(anyhow::anyhow!("timed out fetching the exit list")))
+    } else {
+        fetch.await
+    };
+    match fetched {
+        Ok(exits) => {
+            let stored = StoredExitList {
+                exits,
+                confirmed: now,
+            };
+            db_write(ctx, &db_key, &serde_json::to_vec(&stored)?).await?;
+            Ok(stored.exits)
+        }
+        Err(err) => match stored {
+            Some(stored) if stored_fresh => {
+                tracing::warn!(
+                    err = debug(err),
+                    "could not fetch the exit list, using the stored one"
+                );
+                Ok(stored.exits)
+            }
+            _ => Err(err),
+        },
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Fetches and verifies the exit list from the broker, as a delta against the stored list if the broker supports it.
+async fn fetch_exit_list(
+    ctx: &AnyCtx<Config>,
+    level: AccountLevel,
+    stored: Option<ExitList>,
+) -> anyhow::Result<ExitList> {
+    let broker = broker_client(ctx)?;
+    let broker_caps = broker_capabilities(ctx).await?;
+    let is_broker_pk = |their_pk: &VerifyingKey| {
+        if let Some(broker_pk) = &ctx.init().broker_keys {
+            hex::encode(their_pk.as_bytes()) == broker_pk.master
+        } else {
+            true
+        }
+    };
+
+    if broker_caps.supports(feature::EXIT_LIST_DELTA) {
+        let update = broker
+            .get_exits_since(
+                level,
+                stored.as_ref().map(|list| list.version()),
+                Capabilities::current(),
+            )
+            .await?
+            .map_err(|e| anyhow::anyhow!("broker refused to serve exits: {e}"))?
+            .verify(DOMAIN_EXIT_LIST_UPDATE, is_broker_pk)
+            .context("could not verify")?;
+        anyhow::ensure!(
+            unix_now().abs_diff(update.timestamp) <= MAX_EXIT_LIST_UPDATE_SKEW.as_secs(),
+            "exit list update is not fresh, broker time {}",
+            update.timestamp
+        );
+        return match update.update {
+            ExitListUpdate::Unchanged(version) => {
+                let stored =
+                    stored.context("broker says the exit list is unchanged, but we have none")?;
+                anyhow::ensure!(
+                    stored.version() == version,
+                    "broker says the exit list is unchanged, but our version differs"
+                );
+                Ok(stored)
+            }
+            ExitListUpdate::Delta(delta) => {
+                let mut exits =
+                    stored.context("broker sent an exit list delta, but we have no list")?;
+                exits.apply(&delta)?;
+                Ok(exits)
+            }
+            ExitListUpdate::Full(exits) => Ok(exits),
+        };
+    }
+
+    let exits = match level {
+        level if broker_caps.supports(feature::EXIT_METADATA) => {
+            broker.get_exits_v2(level, Capabilities::current()).await
+        }
+        AccountLevel::Plus => broker.get_exits().await,
+        AccountLevel::Free => broker.get_free_exits().await,
+        level @ AccountLevel::Tier(_) => {
+            if !broker_caps.supports(feature::ACCOUNT_TIERS) {
+                anyhow::bail!("broker does not support account tiers")
+            }
+            broker.get_tier_exits(level).await
+        }
+    }?
+    .map_err(|e| anyhow::anyhow!("broker refused to serve exits: {e}"))?;
+    exits
+        .verify(DOMAIN_EXIT_DESCRIPTOR, is_broker_pk)
+        .context("could not verify")
+}
+
 // async fn reachability_test(
 //     ctx: AnyCtx<Config>,
 //     dialers: BTreeMap<String, DynDialer>,
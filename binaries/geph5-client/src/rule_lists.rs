@@ -0,0 +1,299 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use async_compat::CompatExt;
+use async_native_tls::TlsConnector;
+use bytes::Bytes;
+use futures_util::future::{Either, join_all};
+use http_body_util::{BodyExt, Empty, Limited};
+use hyper::{Request, Uri};
+use hyper_util::rt::TokioIo;
+use ipnet::IpNet;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use smol_timeout2::TimeoutExt;
+use stdcode::StdcodeSerializeExt;
+
+use crate::{
+    Config,
+    client::CtxField,
+    client_inner::open_tunneled_conn,
+    database::{db_read, db_write},
+};
+
+/// How often lists are downloaded or read again.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(6 * 3600);
+
+/// How soon to try again after failing to refresh a list.
+const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How long fetching a list can take before it counts as a failure.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Downloaded lists larger than this are refused.
+const MAX_LIST_SIZE: usize = 64 << 20;
+
+/// Where to get a list of domains and CIDRs. Lists have one entry per line, and `#` starts a comment.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleListSource {
+    File(PathBuf),
+    /// An HTTP or HTTPS URL, which is always downloaded through the tunnel.
+    Url(String),
+}
+
+/// A set of domains, which also contains their subdomains, and IP networks.
+#[derive(Default)]
+pub struct HostList {
+    domains: HashSet<String>,
+    ips: IpTrie,
+}
+
+impl HostList {
+    pub fn parse(text: &str) -> Self {
+        let mut list = Self::default();
+        for line in text.lines() {
+            let entry = line.split('#').next().unwrap_or_default().trim();
+            if let Ok(net) = entry.parse::<IpNet>() {
+                list.ips.insert(net);
+            } else if let Ok(ip) = entry.parse::<IpAddr>() {
+                list.ips.insert(ip.into());
+            } else {
+                let domain = entry.trim_start_matches('.').to_ascii_lowercase();
+                if domain.len() > 1 {
+                    list.domains.insert(domain);
+                }
+            }
+        }
+        list
+    }
+
+    pub fn contains_domain(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.');
+        std::iter::once(domain)
+            .chain(domain.match_indices('.').map(|(i, _)| &domain[i + 1..]))
+            .any(|suffix| self.domains.contains(suffix))
+    }
+
+    pub fn contains_ip(&self, ip: IpAddr) -> bool {
+        self.ips.contains(ip)
+    }
+}
+
+/// A binary trie of IP prefixes, for finding whether any of many networks contains an address in time proportional to the address length.
+#[derive(Default)]
+struct IpTrie {
+    v4: TrieNode,
+    v6: TrieNode,
+}
+
+#[derive(Default)]
+struct TrieNode {
+    /// Whether a whole network ends here, so that every address below is contained.
+    terminal: bool,
+    children: [Option<Box<TrieNode>>; 2],
+}
+
+impl IpTrie {
+    fn insert(&mut self, net: IpNet) {
+        let mut node = match net {
+            IpNet::V4(_) => &mut self.v4,
+            IpNet::V6(_) => &mut self.v6,
+        };
+        let bits = address_bits(net.network());
+        for i in 0..net.prefix_len() {
+            if node.terminal {
+                // a shorter prefix already covers this network
+                return;
+            }
+            node = node.children[bit(bits, i)].get_or_insert_with(Default::default);
+        }
+        node.terminal = true;
+        node.children = [None, None];
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let mut node = match ip {
+            IpAddr::V4(_) => &self.v4,
+            IpAddr::V6(_) => &self.v6,
+        };
+        let bits = address_bits(ip);
+        for i in 0..128 {
+            if node.terminal {
+                return true;
+            }
+            match &node.children[bit(bits, i)] {
+                Some(child) => node = child,
+                None => return false,
+            }
+        }
+        node.terminal
+    }
+}
+
+/// The bits of an address, most significant first.
+fn address_bits(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(v4) => (u32::from(v4) as u128) << 96,
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+fn bit(bits: u128, i: u8) -> usize {
+    ((bits >> (127 - i)) & 1) as usize
+}
+
+/// The lists that are loaded so far, by name.
+pub static RULE_LISTS: CtxField<RwLock<HashMap<String, Arc<HostList>>>> = |_| Default::default();
+
+/// Keeps every configured list loaded and up to date. Downloaded lists are cached in the database, so that they work before the tunnel is up.
+pub async fn rule_lists_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    join_all(
+        ctx.init()
+            .rule_lists
+            .iter()
+            .map(|(name, source)| rule_list_loop(ctx, name, source)),
+    )
+    .await;
+    smol::future::pending().await
+}
+
+async fn rule_list_loop(ctx: &AnyCtx<Config>, name: &str, source: &RuleListSource) {
+    let cache_key = format!("rule_list_{name}");
+    let mut fetched_at = 0;
+    if let RuleListSource::Url(_) = source {
+        let cached = db_read(ctx, &cache_key)
+            .await
+            .ok()
+            .flatten()
+            .and_then(|b| stdcode::deserialize::<(u64, String)>(&b).ok());
+        if let Some((time, text)) = cached {
+            tracing::debug!(name, "loaded rule list from the cache");
+            install(ctx, name, &text);
+            fetched_at = time;
+        }
+    }
+    loop {
+        let due = UNIX_EPOCH + Duration::from_secs(fetched_at) + REFRESH_INTERVAL;
+        if let Ok(wait) = due.duration_since(SystemTime::now()) {
+            smol::Timer::after(wait).await;
+        }
+        let fetched = fetch_list(ctx, source)
+            .timeout(FETCH_TIMEOUT)
+            .await
+            .unwrap_or_else(|| Err(anyhow::anyhow!("timed out fetching the list")));
+        match fetched {
+            Ok(text) => {
+                install(ctx, name, &text);
+                fetched_at = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                if let RuleListSource::Url(_) = source {
+                    let cached = (fetched_at, text).stdcode();
+                    if let Err(err) = db_write(ctx, &cache_key, &cached).await {
+                        tracing::warn!(name, err = debug(err), "could not cache rule list");
+                    }
+                }
+            }
+            Err(err) => {
+                tracing::warn!(name, err = debug(err), "could not refresh rule list");
+                smol::Timer::after(RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+fn install(ctx: &AnyCtx<Config>, name: &str, text: &str) {
+    let list = HostList::parse(text);
+    tracing::debug!(name, domains = list.domains.len(), "rule list loaded");
+    ctx.get(RULE_LISTS)
+        .write()
+        .insert(name.to_string(), Arc::new(list));
+}
+
+async fn fetch_list(ctx: &AnyCtx<Config>, source: &RuleListSource) -> anyhow::Result<String> {
+    match source {
+        RuleListSource::File(path) => Ok(smol::fs::read_to_string(path).await?),
+        RuleListSource::Url(url) => {
+            let bytes = fetch_url(ctx, url).await?;
+            Ok(String::from_utf8(bytes.to_vec())?)
+        }
+    }
+}
+
+/// Downloads the body of a URL through the tunnel.
+async fn fetch_url(ctx: &AnyCtx<Config>, url: &str) -> anyhow::Result<Bytes> {
+    let uri: Uri = url.parse()?;
+    let host = uri.host().context("URL has no host")?.to_string();
+    let https = match uri.scheme_str() {
+        Some("https") => true,
+        Some("http") => false,
+        _ => anyhow::bail!("only HTTP and HTTPS URLs can be downloaded"),
+    };
+    let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+    let conn = open_tunneled_conn(ctx, "tcp", &format!("{host}:{port}")).await?;
+    let conn = if https {
+        Either::Left(TlsConnector::new().connect(&host, conn).await?)
+    } else {
+        Either::Right(conn)
+    };
+
+    let (mut sender, driver) =
+        hyper::client::conn::http1::handshake(TokioIo::new(conn.compat())).await?;
+    let _driver = smolscale::spawn(driver);
+    let request = Request::get(uri.path_and_query().map_or("/", |pq| pq.as_str()))
+        .header("Host", host)
+        .body(Empty::<Bytes>::new())?;
+    let response = sender.send_request(request).await?;
+    anyhow::ensure!(
+        response.status().is_success(),
+        "server responded with {}",
+        response.status()
+    );
+    let body = Limited::new(response.into_body(), MAX_LIST_SIZE)
+        .collect()
+        .await
+        .map_err(|err| anyhow::anyhow!(err))?;
+    Ok(body.to_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_list() {
+        let list = HostList::parse(
+            "# example list\n\
+             example.com\n\
+             .Example.org  # leading dot\n\
+             10.0.0.0/8\n\
+             10.1.0.0/16\n\
+             192.168.1.1\n\
+             2001:db8::/32\n",
+        );
+        assert!(list.contains_domain("example.com"));
+        assert!(list.contains_domain("www.example.com"));
+        assert!(list.contains_domain("a.b.example.org"));
+        assert!(!list.contains_domain("badexample.com"));
+        assert!(!list.contains_domain("com"));
+
+        assert!(list.contains_ip("10.200.3.4".parse().unwrap()));
+        assert!(list.contains_ip("10.1.2.3".parse().unwrap()));
+        assert!(list.contains_ip("192.168.1.1".parse().unwrap()));
+        assert!(!list.contains_ip("192.168.1.2".parse().unwrap()));
+        assert!(!list.contains_ip("11.0.0.1".parse().unwrap()));
+        assert!(list.contains_ip("2001:db8:1::1".parse().unwrap()));
+        assert!(!list.contains_ip("2001:db9::1".parse().unwrap()));
+        // IPv4 networks do not contain IPv6 addresses with the same leading bits
+        assert!(!list.contains_ip("a00::1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use ipnet::IpNet;
+use parking_lot::Mutex;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Client, Config,
+    china::{is_chinese_host, is_chinese_ip},
+    client::CtxField,
+    rule_lists::{HostList, RULE_LISTS},
+};
+
+/// What to do with a connection.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteAction {
+    /// Connect directly, bypassing the tunnel. Only TCP connections can do this; others go through the tunnel.
+    Direct,
+    /// Connect through the tunnel.
+    Proxy,
+    /// Refuse to connect.
+    Block,
+    /// Connect through a separate tunnel, to an exit that meets the constraint of this name in `named_exits`.
+    Exit(String),
+}
+
+/// Built-in sets of destinations.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleSet {
+    /// Private, loopback, and link-local addresses.
+    Private,
+    /// Hosts without a known public suffix, like `printer.local`.
+    UnknownSuffix,
+    /// Domains and IP addresses in China.
+    China,
+}
+
+/// A routing rule. A connection matches the rule if its destination matches any of the domain, CIDR, and rule set conditions, its port is in `port`, and its protocol is in `protocol`. Empty conditions match everything.
+///
+/// CIDRs only match destinations given as IP addresses, since domains are not resolved to evaluate rules.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoutingRule {
+    /// Matches a domain and all its subdomains.
+    #[serde(default)]
+    pub domain_suffix: Vec<String>,
+    #[serde(default)]
+    pub domain_keyword: Vec<String>,
+    #[serde(default)]
+    pub domain_regex: Vec<String>,
+    #[serde(default)]
+    pub ip_cidr: Vec<IpNet>,
+    #[serde(default)]
+    pub rule_set: Vec<RuleSet>,
+    /// Names of lists in `rule_lists`. Until all of them are loaded, a rule that blocks matches every destination, so that it fails closed.
+    #[serde(default)]
+    pub rule_list: Vec<String>,
+    #[serde(default)]
+    pub port: Vec<u16>,
+    /// Protocols as passed to the tunnel, like `tcp` or `udp`.
+    #[serde(default)]
+    pub protocol: Vec<String>,
+    pub action: RouteAction,
+}
+
+struct CompiledRule {
+    rule: RoutingRule,
+    regexes: Vec<Regex>,
+}
+
+impl CompiledRule {
+    fn new(rule: RoutingRule) -> Self {
+        let regexes = rule
+            .domain_regex
+            .iter()
+            .filter_map(|regex| {
+                Regex::new(regex)
+                    .inspect_err(|err| {
+                        tracing::warn!(regex, err = display(err), "ignoring invalid domain regex")
+                    })
+                    .ok()
+            })
+            .collect();
+        Self { rule, regexes }
+    }
+
+    fn matches(&self, dest: &Destination, lists: &HashMap<String, Arc<HostList>>) -> bool {
+        let rule = &self.rule;
+        if !rule.port.is_empty() && !rule.port.contains(&dest.port) {
+            return false;
+        }
+        if !rule.protocol.is_empty() && !rule.protocol.iter().any(|p| p == dest.protocol) {
+            return false;
+        }
+        if rule.domain_suffix.is_empty()
+            && rule.domain_keyword.is_empty()
+            && rule.domain_regex.is_empty()
+            && rule.ip_cidr.is_empty()
+            && rule.rule_set.is_empty()
+            && rule.rule_list.is_empty()
+        {
+            return true;
+        }
+        if rule.action == RouteAction::Block
+            && rule.rule_list.iter().any(|name| !lists.contains_key(name))
+        {
+            return true;
+        }
+        let mut lists = rule.rule_list.iter().filter_map(|name| lists.get(name));
+        match dest.host {
+            Host::Ip(ip) => {
+                rule.ip_cidr.iter().any(|net| net.contains(&ip))
+                    || rule.rule_set.iter().any(|set| set.contains(&dest.host))
+                    || lists.any(|list| list.contains_ip(ip))
+            }
+            Host::Domain(ref domain) => {
+                rule.domain_suffix.iter().any(|suffix| {
+                    let suffix = suffix.trim_start_matches('.').to_ascii_lowercase();
+                    domain == &suffix || domain.ends_with(&format!(".{suffix}"))
+                }) || rule
+                    .domain_keyword
+                    .iter()
+                    .any(|keyword| domain.contains(&keyword.to_ascii_lowercase()))
+                    || self.regexes.iter().any(|regex| regex.is_match(domain))
+                    || rule.rule_set.iter().any(|set| set.contains(&dest.host))
+                    || lists.any(|list| list.contains_domain(domain))
+            }
+        }
+    }
+}
+
+impl RuleSet {
+    fn contains(&self, host: &Host) -> bool {
+        match (self, host) {
+            (RuleSet::Private, Host::Ip(IpAddr::V4(v4))) => {
+                v4.is_private() || v4.is_loopback() || v4.is_link_local()
+            }
+            (RuleSet::Private, Host::Ip(IpAddr::V6(v6))) => v6.is_loopback(),
+            (RuleSet::UnknownSuffix, Host::Domain(domain)) => {
+                psl::suffix(domain.as_bytes()).is_some_and(|suf| !suf.is_known())
+            }
+            (RuleSet::China, Host::Domain(domain)) => {
+                psl::domain_str(domain).is_some_and(is_chinese_host)
+            }
+            (RuleSet::China, Host::Ip(ip)) => is_chinese_ip(*ip),
+            _ => false,
+        }
+    }
+}
+
+enum Host {
+    Ip(IpAddr),
+    Domain(String),
+}
+
+struct Destination<'a> {
+    host: Host,
+    port: u16,
+    protocol: &'a str,
+}
+
+impl<'a> Destination<'a> {
+    fn parse(protocol: &'a str, dest_addr: &str) -> Option<Self> {
+        let (host, port) = dest_addr.rsplit_once(':')?;
+        let port = port.parse().ok()?;
+        let host = if let Some(v6) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+            Host::Ip(Ipv6Addr::from_str(v6).ok()?.into())
+        } else if let Ok(ip) = IpAddr::from_str(host) {
+            Host::Ip(ip)
+        } else if host.is_empty() {
+            return None;
+        } else {
+            Host::Domain(host.trim_end_matches('.').to_ascii_lowercase())
+        };
+        Some(Self {
+            host,
+            port,
+            protocol,
+        })
+    }
+}
+
+/// The configured rules, followed by the built-in ones that pass local destinations (and Chinese ones, if configured) through.
+static RULES: CtxField<Vec<CompiledRule>> = |ctx| {
+    let mut builtin = vec![RuleSet::Private, RuleSet::UnknownSuffix];
+    if ctx.init().passthrough_china {
+        builtin.push(RuleSet::China);
+    }
+    ctx.init()
+        .routing_rules
+        .iter()
+        .cloned()
+        .chain(std::iter::once(RoutingRule {
+            domain_suffix: vec![],
+            domain_keyword: vec![],
+            domain_regex: vec![],
+            ip_cidr: vec![],
+            rule_set: builtin,
+            rule_list: vec![],
+            port: vec![],
+            protocol: vec![],
+            action: RouteAction::Direct,
+        }))
+        .map(CompiledRule::new)
+        .collect()
+};
+
+/// Decides what to do with a connection to the given destination, by the first rule that matches it. Connections that match no rule go through the tunnel.
+pub fn route_action(ctx: &AnyCtx<Config>, protocol: &str, dest_addr: &str) -> RouteAction {
+    let Some(dest) = Destination::parse(protocol, dest_addr) else {
+        return RouteAction::Proxy;
+    };
+    let lists = ctx.get(RULE_LISTS).read();
+    ctx.get(RULES)
+        .iter()
+        .find(|rule| rule.matches(&dest, &lists))
+        .map(|rule| rule.rule.action.clone())
+        .unwrap_or(RouteAction::Proxy)
+}
+
+static NAMED_EXIT_CLIENTS: CtxField<Mutex<HashMap<String, Arc<Client>>>> = |_| Default::default();
+
+/// Gets the context of the client that tunnels to the named exit, starting it if needed, or starting it again if it died.
+pub fn named_exit_ctx(ctx: &AnyCtx<Config>, name: &str) -> anyhow::Result<AnyCtx<Config>> {
+    let constraint = ctx
+        .init()
+        .named_exits
+        .get(name)
+        .with_context(|| format!("no exit named {name}"))?;
+    let mut clients = ctx.get(NAMED_EXIT_CLIENTS).lock();
+    if let Some(client) = clients.get(name) {
+        match client.check_dead() {
+            Ok(()) => return Ok(client.ctx.clone()),
+            Err(err) => tracing::warn!(name, err = debug(err), "tunnel to a named exit died"),
+        }
+    }
+    tracing::info!(name, "starting a tunnel to a named exit");
+    let client = Client::start(ctx.init().for_named_exit(name, constraint.clone()));
+    let child_ctx = client.ctx.clone();
+    clients.insert(name.to_string(), Arc::new(client));
+    Ok(child_ctx)
+}
+
+/// Stops the clients that tunnel to named exits, which happens when the client that started them stops.
+pub fn stop_named_exits(ctx: &AnyCtx<Config>) {
+    ctx.get(NAMED_EXIT_CLIENTS).lock().clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(action: RouteAction) -> RoutingRule {
+        RoutingRule {
+            domain_suffix: vec![],
+            domain_keyword: vec![],
+            domain_regex: vec![],
+            ip_cidr: vec![],
+            rule_set: vec![],
+            rule_list: vec![],
+            port: vec![],
+            protocol: vec![],
+            action,
+        }
+    }
+
+    #[test]
+    fn test_rule_matching() {
+        let suffix = CompiledRule::new(RoutingRule {
+            domain_suffix: vec!["example.com".into()],
+            port: vec![443],
+            ..rule(RouteAction::Direct)
+        });
+        let dest = |protocol, addr| Destination::parse(protocol, addr).unwrap();
+        let mut lists = HashMap::new();
+        lists.insert(
+            "work".to_string(),
+            Arc::new(HostList::parse("corp.example\n172.16.0.0/12")),
+        );
+        assert!(suffix.matches(&dest("tcp", "example.com:443"), &lists));
+        assert!(suffix.matches(&dest("tcp", "www.Example.com:443"), &lists));
+        assert!(!suffix.matches(&dest("tcp", "badexample.com:443"), &lists));
+        assert!(!suffix.matches(&dest("tcp", "example.com:80"), &lists));
+
+        let mixed = CompiledRule::new(RoutingRule {
+            domain_regex: vec![r"^ads?\.".into()],
+            ip_cidr: vec![
+                "10.0.0.0/8".parse().unwrap(),
+                "2001:db8::/32".parse().unwrap(),
+            ],
+            protocol: vec!["udp".into()],
+            ..rule(RouteAction::Block)
+        });
+        assert!(mixed.matches(&dest("udp", "ads.tracker.net:53"), &lists));
+        assert!(mixed.matches(&dest("udp", "10.1.2.3:53"), &lists));
+        assert!(mixed.matches(&dest("udp", "[2001:db8::1]:53"), &lists));
+        assert!(!mixed.matches(&dest("tcp", "10.1.2.3:53"), &lists));
+        assert!(!mixed.matches(&dest("udp", "notads.tracker.net:53"), &lists));
+
+        let private = CompiledRule::new(RoutingRule {
+            rule_set: vec![RuleSet::Private, RuleSet::UnknownSuffix],
+            ..rule(RouteAction::Direct)
+        });
+        assert!(private.matches(&dest("tcp", "192.168.1.1:80"), &lists));
+        assert!(private.matches(&dest("tcp", "printer.local:631"), &lists));
+        assert!(!private.matches(&dest("tcp", "8.8.8.8:53"), &lists));
+        assert!(!private.matches(&dest("tcp", "example.com:80"), &lists));
+
+        let listed = CompiledRule::new(RoutingRule {
+            rule_list: vec!["work".into(), "missing".into()],
+            ..rule(RouteAction::Direct)
+        });
+        assert!(listed.matches(&dest("tcp", "git.corp.example:22"), &lists));
+        assert!(listed.matches(&dest("tcp", "172.20.0.1:443"), &lists));
+        assert!(!listed.matches(&dest("tcp", "example.com:443"), &lists));
+
+        let blocked = CompiledRule::new(RoutingRule {
+            rule_list: vec!["work".into(), "missing".into()],
+            port: vec![443],
+            ..rule(RouteAction::Block)
+        });
+        assert!(blocked.matches(&dest("tcp", "example.com:443"), &lists));
+        assert!(!blocked.matches(&dest("tcp", "example.com:80"), &lists));
+        lists.insert("missing".to_string(), Arc::new(HostList::parse("")));
+        assert!(!blocked.matches(&dest("tcp", "example.com:443"), &lists));
+        assert!(blocked.matches(&dest("tcp", "git.corp.example:443"), &lists));
+
+        assert!(
+            CompiledRule::new(rule(RouteAction::Proxy))
+                .matches(&dest("tcp", "example.com:80"), &lists)
+        );
+    }
+}
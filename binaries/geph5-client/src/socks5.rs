@@ -1,16 +1,23 @@
-use crate::{client_inner::open_conn, taskpool::add_task};
+use crate::{
+    client_inner::open_conn,
+    proxy_auth::{socks5_login, UserCountedPipe},
+    taskpool::add_task,
+};
 
 use anyctx::AnyCtx;
 
-use futures_util::AsyncReadExt as _;
+use futures_util::{AsyncReadExt as _, AsyncWriteExt as _};
 use nursery_macro::nursery;
-use sillad::listener::Listener as _;
-use smol::future::FutureExt as _;
+use sillad::{listener::Listener as _, Pipe};
+use smol::{channel::Sender, future::FutureExt as _, net::UdpSocket, Task};
 use socksv5::v5::{
-    read_handshake, read_request, write_auth_method, write_request_status, SocksV5AuthMethod,
-    SocksV5Host, SocksV5RequestStatus,
+    read_request, write_request_status, SocksV5Command, SocksV5Host, SocksV5RequestStatus,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 };
-use std::net::Ipv4Addr;
 
 use super::Config;
 
@@ -23,9 +30,24 @@ pub async fn socks5_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
                 let client = listener.accept().await?;
                 let task = spawn!(async {
                     tracing::trace!("socks5 connection accepted");
+                    let client_ip = client
+                        .remote_addr()
+                        .and_then(|addr| addr.parse::<SocketAddr>().ok())
+                        .map(|addr| addr.ip());
+                    let access = &ctx.init().proxy_access;
+                    let allowed = match client_ip {
+                        Some(ip) => access.allows_source(ip),
+                        None => access.allowed_sources.is_empty(),
+                    };
+                    if !allowed {
+                        tracing::debug!(
+                            client_ip = debug(client_ip),
+                            "refusing socks5 connection from a disallowed source"
+                        );
+                        return anyhow::Ok(());
+                    }
                     let (mut read_client, mut write_client) = client.split();
-                    let _handshake = read_handshake(&mut read_client).await?;
-                    write_auth_method(&mut write_client, SocksV5AuthMethod::Noauth).await?;
+                    let user = socks5_login(&mut read_client, &mut write_client, access).await?;
                     let request = read_request(&mut read_client).await?;
                     let port = request.port;
                     let domain: String = match &request.host {
@@ -34,26 +56,78 @@ pub async fn socks5_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
                             let v4addr = Ipv4Addr::new(v4[0], v4[1], v4[2], v4[3]);
                             v4addr.to_string()
                         }
-                        _ => anyhow::bail!("IPv6 not supported"),
+                        SocksV5Host::Ipv6(v6) => format!("[{}]", Ipv6Addr::from(*v6)),
                     };
                     let remote_addr = format!("{domain}:{port}");
                     tracing::trace!(
                         remote_addr = display(&remote_addr),
                         "socks5 request received"
                     );
-                    let stream = open_conn(ctx, "tcp", &remote_addr).await?;
-                    write_request_status(
-                        &mut write_client,
-                        SocksV5RequestStatus::Success,
-                        request.host,
-                        port,
-                    )
-                    .await?;
-                    tracing::trace!(remote_addr = display(&remote_addr), "connection opened");
-                    let (read_stream, write_stream) = stream.split();
-                    smol::io::copy(read_stream, write_client)
-                        .race(smol::io::copy(read_client, write_stream))
-                        .await?;
+                    match request.command {
+                        SocksV5Command::Connect => {
+                            let stream = UserCountedPipe::wrap(
+                                ctx,
+                                user.as_deref(),
+                                open_conn(ctx, "tcp", &remote_addr).await?,
+                            );
+                            write_request_status(
+                                &mut write_client,
+                                SocksV5RequestStatus::Success,
+                                request.host,
+                                port,
+                            )
+                            .await?;
+                            tracing::trace!(
+                                remote_addr = display(&remote_addr),
+                                "connection opened"
+                            );
+                            let (read_stream, write_stream) = stream.split();
+                            smol::io::copy(read_stream, write_client)
+                                .race(smol::io::copy(read_client, write_stream))
+                                .await?;
+                        }
+                        SocksV5Command::UdpAssociate => {
+                            let relay = UdpSocket::bind((listen_addr.ip(), 0)).await?;
+                            let relay_addr = relay.local_addr()?;
+                            write_request_status(
+                                &mut write_client,
+                                SocksV5RequestStatus::Success,
+                                socks_host(relay_addr.ip()),
+                                relay_addr.port(),
+                            )
+                            .await?;
+                            tracing::trace!(
+                                relay_addr = display(relay_addr),
+                                "UDP association opened"
+                            );
+                            let ctx = ctx.clone();
+                            let open = move |dest: String| {
+                                let ctx = ctx.clone();
+                                let user = user.clone();
+                                async move {
+                                    let conn = open_conn(&ctx, "udp", &dest).await?;
+                                    anyhow::Ok(UserCountedPipe::wrap(&ctx, user.as_deref(), conn))
+                                }
+                            };
+                            // the association lasts as long as the control connection
+                            udp_relay(relay, client_ip, open)
+                                .race(async {
+                                    let mut buf = [0u8; 1024];
+                                    while read_client.read(&mut buf).await? > 0 {}
+                                    anyhow::Ok(())
+                                })
+                                .await?;
+                        }
+                        SocksV5Command::Bind => {
+                            write_request_status(
+                                &mut write_client,
+                                SocksV5RequestStatus::CommandNotSupported,
+                                request.host,
+                                port,
+                            )
+                            .await?;
+                        }
+                    }
                     anyhow::Ok(())
                 });
                 if let Some(task_limit) = ctx.init().task_limit {
@@ -67,3 +141,226 @@ pub async fn socks5_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
         smol::future::pending().await
     }
 }
+
+fn socks_host(ip: IpAddr) -> SocksV5Host {
+    match ip {
+        IpAddr::V4(v4) => SocksV5Host::Ipv4(v4.octets()),
+        IpAddr::V6(v6) => SocksV5Host::Ipv6(v6.octets()),
+    }
+}
+
+/// The datagrams waiting to go out to one destination, and the task sending them.
+type UdpSession = (Sender<Vec<u8>>, Task<anyhow::Result<()>>);
+
+/// Relays the datagrams of a UDP association, giving every destination its own "udp" stream opened through `open`. Only datagrams from the client's address are relayed.
+async fn udp_relay<F, Fut, P>(
+    relay: UdpSocket,
+    client_ip: Option<IpAddr>,
+    open: F,
+) -> anyhow::Result<()>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = anyhow::Result<P>> + Send + 'static,
+    P: Pipe,
+{
+    let mut sessions: HashMap<String, UdpSession> = HashMap::new();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (n, src) = relay.recv_from(&mut buf).await?;
+        if client_ip.is_some_and(|ip| ip != src.ip()) {
+            continue;
+        }
+        let Some((dest, header_len)) = parse_udp_header(&buf[..n]) else {
+            tracing::trace!(src = display(src), "dropping a malformed SOCKS5 datagram");
+            continue;
+        };
+        let payload = buf[header_len..n].to_vec();
+        sessions.retain(|_, (send, _)| !send.is_closed());
+        if let Some((send, _)) = sessions.get(&dest) {
+            // like any other hop, drop datagrams instead of queueing them without bound
+            let _ = send.try_send(payload);
+            continue;
+        }
+
+        let (send, recv) = smol::channel::bounded(100);
+        let _ = send.try_send(payload);
+        // replies carry the same address that the client sent to
+        let header = buf[..header_len].to_vec();
+        let conn = open(dest.clone());
+        let relay = relay.clone();
+        let task = smolscale::spawn(async move {
+            let (read_conn, mut write_conn) = conn.await?.split();
+            let up_loop = async {
+                while let Ok(pkt) = recv.recv().await {
+                    write_conn
+                        .write_all(&(pkt.len() as u16).to_le_bytes())
+                        .await?;
+                    write_conn.write_all(&pkt).await?;
+                    write_conn.flush().await?;
+                }
+                anyhow::Ok(())
+            };
+            up_loop
+                .race(udp_download(read_conn, &relay, &header, src))
+                .await
+        });
+        sessions.insert(dest, (send, task));
+    }
+}
+
+/// Sends the datagrams that come back over a "udp" stream to the client, behind the given SOCKS5 header.
+async fn udp_download(
+    mut read_conn: impl futures_util::AsyncRead + Unpin,
+    relay: &UdpSocket,
+    header: &[u8],
+    client: SocketAddr,
+) -> anyhow::Result<()> {
+    loop {
+        let mut len_buf = [0u8; 2];
+        read_conn.read_exact(&mut len_buf).await?;
+        let mut pkt = header.to_vec();
+        let header_len = pkt.len();
+        pkt.resize(header_len + u16::from_le_bytes(len_buf) as usize, 0);
+        read_conn.read_exact(&mut pkt[header_len..]).await?;
+        relay.send_to(&pkt, client).await?;
+    }
+}
+
+/// Parses the header of a SOCKS5 UDP datagram, returning the destination as `host:port` along with the length of the header. Fragmented datagrams are not supported, so they give `None`.
+fn parse_udp_header(pkt: &[u8]) -> Option<(String, usize)> {
+    let (&[_, _, frag, atyp], rest) = pkt.split_first_chunk::<4>()?;
+    if frag != 0 {
+        return None;
+    }
+    let (host, rest) = match atyp {
+        1 => {
+            let (v4, rest) = rest.split_first_chunk::<4>()?;
+            (Ipv4Addr::from(*v4).to_string(), rest)
+        }
+        3 => {
+            let (&len, rest) = rest.split_first()?;
+            let (domain, rest) = rest.split_at_checked(len as usize)?;
+            (std::str::from_utf8(domain).ok()?.to_string(), rest)
+        }
+        4 => {
+            let (v6, rest) = rest.split_first_chunk::<16>()?;
+            (format!("[{}]", Ipv6Addr::from(*v6)), rest)
+        }
+        _ => return None,
+    };
+    let (port, rest) = rest.split_first_chunk::<2>()?;
+    Some((
+        format!("{host}:{}", u16::from_be_bytes(*port)),
+        pkt.len() - rest.len(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{AsyncReadExt as _, AsyncWriteExt as _};
+    use sillad::{
+        dialer::Dialer as _,
+        listener::Listener as _,
+        tcp::{TcpDialer, TcpListener},
+    };
+    use smol::future::FutureExt as _;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_udp_header() {
+        let mut v4 = vec![0, 0, 0, 1, 1, 2, 3, 4, 0, 53];
+        v4.extend_from_slice(b"payload");
+        assert_eq!(parse_udp_header(&v4), Some(("1.2.3.4:53".into(), 10)));
+
+        let mut domain = vec![0, 0, 0, 3, 11];
+        domain.extend_from_slice(b"example.com");
+        domain.extend_from_slice(&443u16.to_be_bytes());
+        assert_eq!(
+            parse_udp_header(&domain),
+            Some(("example.com:443".into(), domain.len()))
+        );
+
+        let mut v6 = vec![0, 0, 0, 4];
+        v6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        v6.extend_from_slice(&8080u16.to_be_bytes());
+        assert_eq!(parse_udp_header(&v6), Some(("[::1]:8080".into(), 22)));
+
+        // fragments and truncated headers are dropped
+        assert_eq!(parse_udp_header(&[0, 0, 1, 1, 1, 2, 3, 4, 0, 53]), None);
+        assert_eq!(parse_udp_header(&[0, 0, 0, 1, 1, 2]), None);
+    }
+
+    /// Stands in for an exit: accepts one "udp" stream, and relays its datagrams to `dest`.
+    async fn fake_exit(dest: String) -> anyhow::Result<Box<dyn Pipe>> {
+        let mut listener = TcpListener::bind("127.0.0.1:0".parse()?).await?;
+        let addr = listener.local_addr().await;
+        smolscale::spawn(async move {
+            let (read_conn, mut write_conn) = listener.accept().await?.split();
+            let socket = UdpSocket::bind("127.0.0.1:0").await?;
+            socket.connect(dest).await?;
+            let dn_loop = async {
+                let mut buf = [0u8; 2048];
+                loop {
+                    let n = socket.recv(&mut buf).await?;
+                    write_conn.write_all(&(n as u16).to_le_bytes()).await?;
+                    write_conn.write_all(&buf[..n]).await?;
+                }
+            };
+            fake_exit_up(read_conn, &socket).race(dn_loop).await
+        })
+        .detach();
+        Ok(Box::new(TcpDialer { dest_addr: addr }.dial().await?))
+    }
+
+    async fn fake_exit_up(
+        mut read_conn: impl futures_util::AsyncRead + Unpin,
+        socket: &UdpSocket,
+    ) -> anyhow::Result<()> {
+        loop {
+            let mut len_buf = [0u8; 2];
+            read_conn.read_exact(&mut len_buf).await?;
+            let mut pkt = vec![0u8; u16::from_le_bytes(len_buf) as usize];
+            read_conn.read_exact(&mut pkt).await?;
+            socket.send(&pkt).await?;
+        }
+    }
+
+    #[test]
+    fn test_udp_associate_echo() {
+        smolscale::block_on(async {
+            let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let echo_addr = echo.local_addr().unwrap();
+            let _echo_task = smolscale::spawn::<anyhow::Result<()>>(async move {
+                let mut buf = [0u8; 2048];
+                loop {
+                    let (n, src) = echo.recv_from(&mut buf).await?;
+                    echo.send_to(&buf[..n], src).await?;
+                }
+            });
+
+            let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let relay_addr = relay.local_addr().unwrap();
+            let _relay_task = smolscale::spawn(udp_relay(
+                relay,
+                Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                fake_exit,
+            ));
+
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut header = vec![0, 0, 0, 1];
+            header.extend_from_slice(&Ipv4Addr::LOCALHOST.octets());
+            header.extend_from_slice(&echo_addr.port().to_be_bytes());
+            for msg in [&b"hello"[..], b"world"] {
+                client
+                    .send_to(&[&header[..], msg].concat(), relay_addr)
+                    .await
+                    .unwrap();
+                let mut buf = [0u8; 2048];
+                let (n, from) = client.recv_from(&mut buf).await.unwrap();
+                assert_eq!(from, relay_addr);
+                assert_eq!(&buf[..n], [&header[..], msg].concat());
+            }
+        })
+    }
+}
@@ -39,7 +39,7 @@ use crate::{
 
 /// Whitelist a vpn address if needed
 pub fn smart_vpn_whitelist(ctx: &AnyCtx<Config>, addr: IpAddr) {
-    if ctx.init().vpn {
+    if ctx.init().vpn || ctx.init().vpn_whitelist_only {
         vpn_whitelist(addr);
     }
 }
@@ -1,14 +1,7 @@
 use std::net::{IpAddr, SocketAddr};
 
-use crate::CONFIG_FILE;
-
-pub fn proxy_allowed(addr: SocketAddr, is_free: bool) -> bool {
-    if is_free
-        && !CONFIG_FILE
-            .wait()
-            .free_port_whitelist
-            .contains(&addr.port())
-    {
+pub fn proxy_allowed(addr: SocketAddr, port_whitelist: Option<&[u16]>) -> bool {
+    if port_whitelist.is_some_and(|ports| !ports.contains(&addr.port())) {
         return false;
     }
     is_globally_routable(&addr.ip())
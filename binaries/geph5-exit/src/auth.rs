@@ -1,9 +1,10 @@
 use std::{sync::LazyLock, thread::available_parallelism};
 
-use geph5_broker_protocol::AccountLevel;
 use mizaru2::{ClientToken, UnblindedSignature};
 use threadpool::ThreadPool;
 
+use crate::tier::TierPolicy;
+
 static POOL: LazyLock<ThreadPool> = LazyLock::new(|| {
     ThreadPool::with_name(
         "user-verifier".to_string(),
@@ -12,33 +13,65 @@ static POOL: LazyLock<ThreadPool> = LazyLock::new(|| {
 });
 
 pub async fn verify_user(
-    level: AccountLevel,
+    tier: &TierPolicy,
     token: ClientToken,
     sig: UnblindedSignature,
 ) -> anyhow::Result<()> {
     if sig.epoch.abs_diff(mizaru2::current_epoch()) > 2 {
         anyhow::bail!("signature from wrong epoch")
     }
-    // TODO make this configurable, once we get to all the servers
-    let key = match level {
-        AccountLevel::Free => mizaru2::PublicKey::from_bytes(
-            hex::decode("0558216cbab7a9c46f298f4c26e171add9af87d0694988b8a8fe52ee932aa754")
-                .unwrap()
-                .try_into()
-                .unwrap(),
-        ),
-        AccountLevel::Plus => mizaru2::PublicKey::from_bytes(
-            hex::decode("cf6f58868c6d9459b3a63bc2bd86165631b3e916bad7f62b578cd9614e0bcb3b")
-                .unwrap()
-                .try_into()
-                .unwrap(),
-        ),
-    };
+    let keys = tier.accepted_keys();
 
     let (send, recv) = oneshot::channel();
     POOL.execute(move || {
-        let _ = send.send(key.blind_verify(token, &sig));
+        let _ = send.send(verify_with_keys(&keys, token, &sig));
     });
     recv.await??;
     Ok(())
 }
+
+/// Verifies a token against every accepted key, so that tokens issued under either side of a key rollover keep working.
+fn verify_with_keys(
+    keys: &[mizaru2::PublicKey],
+    token: ClientToken,
+    sig: &UnblindedSignature,
+) -> anyhow::Result<()> {
+    if keys.iter().any(|key| key.blind_verify(token, sig).is_ok()) {
+        Ok(())
+    } else {
+        anyhow::bail!("token not signed by any accepted mizaru key")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mizaru2::SecretKey;
+
+    use super::*;
+
+    fn signed_token(sk: &SecretKey) -> (ClientToken, UnblindedSignature) {
+        let token = ClientToken::random();
+        let (blinded, secret) = token.blind(&sk.get_subkey(1).unwrap().public_key().unwrap());
+        let sig = sk
+            .blind_sign(1, &blinded)
+            .unwrap()
+            .unblind(&secret, token)
+            .unwrap();
+        (token, sig)
+    }
+
+    #[test]
+    fn test_key_rollover() {
+        let old_sk = SecretKey::truncated_for_testing("old", [1; 32], 2);
+        let new_sk = SecretKey::truncated_for_testing("new", [2; 32], 2);
+        let unknown_sk = SecretKey::truncated_for_testing("unknown", [3; 32], 2);
+        let keys = [old_sk.to_public_key(), new_sk.to_public_key()];
+
+        let (token, sig) = signed_token(&old_sk);
+        verify_with_keys(&keys, token, &sig).unwrap();
+        let (token, sig) = signed_token(&new_sk);
+        verify_with_keys(&keys, token, &sig).unwrap();
+        let (token, sig) = signed_token(&unknown_sk);
+        assert!(verify_with_keys(&keys, token, &sig).is_err());
+    }
+}
@@ -1,21 +1,28 @@
 use std::{
+    collections::BTreeMap,
     net::IpAddr,
     str::FromStr,
     sync::atomic::{AtomicBool, Ordering},
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 use async_trait::async_trait;
 use ed25519_dalek::VerifyingKey;
-use geph5_broker_protocol::{BrokerClient, ExitDescriptor, Mac, Signed, DOMAIN_EXIT_DESCRIPTOR};
+use geph5_broker_protocol::{
+    exit_metadata, BrokerClient, ExitDescriptor, Mac, Signed, DOMAIN_EXIT_DESCRIPTOR,
+};
+use geph5_misc_rpc::exit::{CRYPT_HELLO_X25519_MLKEM768, CRYPT_HELLO_X25519_REKEYING};
 use nanorpc::{JrpcRequest, JrpcResponse, RpcTransport};
 use reqwest::Method;
 use tap::Tap;
 
 use crate::{
+    ipv6::ipv6_egress_available,
     ratelimit::{get_load, TOTAL_BYTE_COUNT},
     schedlag::SCHEDULER_LAG_SECS,
+    session::get_reuse_counts,
     tasklimit::get_task_count,
+    tier::pin_mizaru_keys,
     watchdog::kick_watchdog,
     CONFIG_FILE, SIGNING_SECRET,
 };
@@ -83,6 +90,9 @@ pub async fn broker_loop() -> anyhow::Result<()> {
         "listen information gotten"
     );
 
+    let metadata = exit_metadata().await;
+    tracing::info!(metadata = debug(&metadata), "exit metadata detected");
+
     let server_name = format!(
         "{}-{}",
         CONFIG_FILE.wait().country.alpha2().to_lowercase(),
@@ -93,7 +103,20 @@ pub async fn broker_loop() -> anyhow::Result<()> {
             let transport = BrokerRpcTransport::new(&broker.url);
             let client = BrokerClient(transport);
             let mut last_byte_count = TOTAL_BYTE_COUNT.load(Ordering::Relaxed);
+            let mut last_key_fetch: Option<Instant> = None;
+            let mut last_reuse_counts = get_reuse_counts();
             loop {
+                if CONFIG_FILE.wait().mizaru_pin_file.is_some()
+                    && last_key_fetch.is_none_or(|t| t.elapsed() > Duration::from_secs(600))
+                {
+                    // failing to pin keys must not keep the exit from being listed
+                    let pinned = async { pin_mizaru_keys(client.get_mizaru_public_keys().await?) };
+                    match pinned.await {
+                        Ok(()) => last_key_fetch = Some(Instant::now()),
+                        Err(err) => tracing::warn!(err = debug(err), "could not pin mizaru keys"),
+                    }
+                }
+
                 let upload = async {
                     let free_exits = client
                         .get_free_exits()
@@ -123,6 +146,26 @@ pub async fn broker_loop() -> anyhow::Result<()> {
                             .await?;
                         diff = diff.saturating_sub(1_000_000_000);
                     }
+                    if CONFIG_FILE.wait().report_token_reuse {
+                        let (reused, rejected) = get_reuse_counts();
+                        let reused_diff = reused - last_reuse_counts.0;
+                        let rejected_diff = rejected - last_reuse_counts.1;
+                        last_reuse_counts = (reused, rejected);
+                        if reused_diff > 0 {
+                            client
+                                .incr_stat(format!("{server_name}.token_reuse"), reused_diff as _)
+                                .await?;
+                        }
+                        if rejected_diff > 0 {
+                            client
+                                .incr_stat(
+                                    format!("{server_name}.token_reuse_rejected"),
+                                    rejected_diff as _,
+                                )
+                                .await?;
+                        }
+                    }
+
                     let load = get_load();
                     client
                         .set_stat(format!("{server_name}.load"), load as _)
@@ -155,6 +198,7 @@ pub async fn broker_loop() -> anyhow::Result<()> {
                             .unwrap()
                             .as_secs()
                             + 1800,
+                        metadata: metadata.clone(),
                     };
                     let to_upload = Mac::new(
                         Signed::new(descriptor, DOMAIN_EXIT_DESCRIPTOR, &SIGNING_SECRET),
@@ -180,3 +224,35 @@ pub async fn broker_loop() -> anyhow::Result<()> {
         }
     }
 }
+
+/// Builds the metadata advertised in the exit descriptor, from detected state and the config.
+async fn exit_metadata() -> BTreeMap<String, String> {
+    let config = CONFIG_FILE.wait();
+    let mut metadata = BTreeMap::new();
+    metadata.insert(
+        exit_metadata::IPV6.to_string(),
+        ipv6_egress_available().await.to_string(),
+    );
+    metadata.insert(exit_metadata::UDP.to_string(), "true".to_string());
+    metadata.insert(
+        exit_metadata::C2E_PROTOCOLS.to_string(),
+        "conntest".to_string(),
+    );
+    metadata.insert(
+        exit_metadata::CRYPT_HELLOS.to_string(),
+        [CRYPT_HELLO_X25519_REKEYING, CRYPT_HELLO_X25519_MLKEM768].join(","),
+    );
+    metadata.insert(
+        exit_metadata::SESSION_RESUMPTION.to_string(),
+        "true".to_string(),
+    );
+    metadata.insert(
+        exit_metadata::BANDWIDTH_MBPS.to_string(),
+        (config.total_ratelimit as u64 * 8 / 1000).to_string(),
+    );
+    if !config.tags.is_empty() {
+        metadata.insert(exit_metadata::TAGS.to_string(), config.tags.join(","));
+    }
+    metadata.extend(config.metadata.clone());
+    metadata
+}
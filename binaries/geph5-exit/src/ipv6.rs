@@ -148,6 +148,11 @@ pub async fn configure_ipv6_routing() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Whether this exit can reach IPv6 destinations, either through the configured subnet or a default IPv6 route.
+pub async fn ipv6_egress_available() -> bool {
+    CONFIG_FILE.wait().ipv6_subnet != Ipv6Net::default() || detect_ipv6_interface().await.is_ok()
+}
+
 async fn detect_ipv6_interface() -> anyhow::Result<String> {
     let output = Command::new("ip").args(["-6", "route"]).output().await?;
 
@@ -4,21 +4,30 @@ use futures_util::{AsyncReadExt, TryFutureExt};
 use geph5_broker_protocol::AccountLevel;
 use geph5_misc_rpc::{
     bridge::B2eMetadata,
-    exit::{ClientCryptHello, ClientExitCryptPipe, ClientHello, ExitHello, ExitHelloInner},
+    exit::{
+        ClientCryptHello, ClientExitCryptPipe, ClientHello, ExitHello, ExitHelloInner,
+        SESSION_RESUME_MAGIC, SessionResumeRequest, SessionResumeResponse, hybrid_exit_respond,
+    },
     read_prepend_length, write_prepend_length,
 };
 use mizaru2::{ClientToken, UnblindedSignature};
 use moka::future::Cache;
-use picomux::{LivenessConfig, PicoMux};
+use once_cell::sync::Lazy;
+use picomux::{LivenessConfig, PicoMux, ResumeConfig};
 
 use sillad::{EitherPipe, Pipe, listener::Listener, tcp::TcpListener};
 use smol::future::FutureExt as _;
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use stdcode::StdcodeSerializeExt;
 use tachyonix::Sender;
 
 use x25519_dalek::{EphemeralSecret, PublicKey};
 mod b2e_process;
+mod datagrams;
 mod tls;
 
 use crate::{
@@ -29,9 +38,24 @@ use crate::{
     ipv6::{EyeballDialer, configure_ipv6_routing},
     proxy::proxy_stream,
     ratelimit::{RateLimiter, get_ratelimiter},
+    session::register_session,
     tasklimit::new_task_until_death,
+    tier::{TierPolicy, tier_policy},
 };
 
+/// Resumable sessions that are still alive, keyed by their resumption token.
+static RESUMABLE_SESSIONS: Lazy<Cache<[u8; 32], ResumableSession>> =
+    Lazy::new(|| Cache::new(1_000_000));
+
+#[derive(Clone)]
+struct ResumableSession {
+    mux: Arc<PicoMux>,
+    auth: Arc<SessionAuth>,
+}
+
+/// The rate limit and tier that a session's streams are served with. Resuming a session replaces them with what the fresh pipe's credentials allow.
+type SessionAuth = Mutex<(RateLimiter, Option<Arc<TierPolicy>>)>;
+
 pub async fn listen_main() -> anyhow::Result<()> {
     configure_ipv6_routing().await?;
     let c2e = c2e_loop();
@@ -74,6 +98,14 @@ async fn c2e_loop() -> anyhow::Result<()> {
 }
 
 async fn b2e_loop() -> anyhow::Result<()> {
+    // b2e metadata is authenticated with the token that the exit shares with the broker, so without a broker, no bridge can reach us
+    let exit_token: &str = match &CONFIG_FILE.wait().broker {
+        Some(broker) => &broker.auth_token,
+        None => {
+            tracing::info!("not accepting b2e links since there's no broker to authenticate them");
+            return smol::future::pending().await;
+        }
+    };
     let mut listener = TcpListener::bind(CONFIG_FILE.wait().b2e_listen).await?;
     let b2e_table: Cache<B2eMetadata, Sender<picomux::Stream>> = Cache::builder()
         .time_to_idle(Duration::from_secs(1200))
@@ -100,7 +132,19 @@ async fn b2e_loop() -> anyhow::Result<()> {
         smolscale::spawn::<anyhow::Result<()>>(async move {
             loop {
                 let lala = b2e_mux.accept().await?;
-                let b2e_metadata: B2eMetadata = stdcode::deserialize(lala.metadata())?;
+                // a link with any stream that the broker did not vouch for is dropped entirely
+                let b2e_metadata = stdcode::deserialize(lala.metadata())
+                    .map_err(anyhow::Error::from)
+                    .and_then(|metadata| {
+                        B2eMetadata::verify(metadata, exit_token).map_err(anyhow::Error::from)
+                    })
+                    .inspect_err(|err| {
+                        tracing::debug!(
+                            bridge_addr = display(&bridge_addr),
+                            err = debug(err),
+                            "rejecting unauthenticated b2e link"
+                        )
+                    })?;
                 tracing::trace!(
                     bridge_addr = display(&bridge_addr),
                     "accepting b2e with metadata"
@@ -129,7 +173,7 @@ async fn handle_client(mut client: impl Pipe) -> anyhow::Result<()> {
     // execute the authentication
     let client_hello: ClientHello = stdcode::deserialize(&read_prepend_length(&mut client).await?)?;
 
-    let keys: Option<([u8; 32], [u8; 32])>;
+    let keys: Option<([u8; 32], [u8; 32], bool)>;
     let exit_hello_inner: ExitHelloInner = match client_hello.crypt_hello {
         ClientCryptHello::SharedSecretChallenge(key) => {
             let real_ss = client.shared_secret().context("no shared secret")?;
@@ -137,18 +181,30 @@ async fn handle_client(mut client: impl Pipe) -> anyhow::Result<()> {
             keys = None;
             ExitHelloInner::SharedSecretResponse(mac)
         }
-        ClientCryptHello::X25519(their_epk) => {
+        ClientCryptHello::X25519(their_epk) | ClientCryptHello::X25519Rekeying(their_epk) => {
+            let rekeying = matches!(
+                client_hello.crypt_hello,
+                ClientCryptHello::X25519Rekeying(_)
+            );
             let my_esk = EphemeralSecret::random_from_rng(rand::thread_rng());
             let my_epk = PublicKey::from(&my_esk);
             let shared_secret = my_esk.diffie_hellman(&their_epk);
             let read_key = blake3::derive_key("c2e", shared_secret.as_bytes());
             let write_key = blake3::derive_key("e2c", shared_secret.as_bytes());
-            keys = Some((read_key, write_key));
+            keys = Some((read_key, write_key, rekeying));
             ExitHelloInner::X25519(my_epk)
         }
+        ClientCryptHello::X25519MlKem768(their_epk, ref encapsulation_key) => {
+            let (shared_secret, inner) = hybrid_exit_respond(&their_epk, encapsulation_key)?;
+            let read_key = blake3::derive_key("c2e", &shared_secret);
+            let write_key = blake3::derive_key("e2c", &shared_secret);
+            keys = Some((read_key, write_key, true));
+            inner
+        }
     };
 
-    let mut is_free = false;
+    let mut tier = None;
+    let mut session_token = None;
     let ratelimit = if CONFIG_FILE.wait().broker.is_some() {
         let (level, token, sig): (AccountLevel, ClientToken, UnblindedSignature) =
             stdcode::deserialize(&client_hello.credentials)
@@ -156,11 +212,15 @@ async fn handle_client(mut client: impl Pipe) -> anyhow::Result<()> {
         if level == AccountLevel::Free && !ACCEPT_FREE.load(std::sync::atomic::Ordering::Relaxed) {
             anyhow::bail!("free users rejected here")
         }
-        verify_user(level, token, sig).await.inspect_err(|e| {
+        let policy = tier_policy(&level)
+            .with_context(|| format!("tier {} not served here", level.name()))?;
+        verify_user(&policy, token, sig).await.inspect_err(|e| {
             tracing::warn!(err = debug(e), "**** BAD BAD bad token received ***")
         })?;
-        is_free = level == AccountLevel::Free;
-        get_ratelimiter(level, token).await
+        session_token = Some(token);
+        let ratelimit = get_ratelimiter(&policy, token).await;
+        tier = Some(policy);
+        ratelimit
     } else {
         RateLimiter::unlimited()
     };
@@ -171,15 +231,103 @@ async fn handle_client(mut client: impl Pipe) -> anyhow::Result<()> {
     };
     write_prepend_length(&exit_hello.stdcode(), &mut client).await?;
 
-    let client = if let Some((read_key, write_key)) = keys {
-        EitherPipe::Left(ClientExitCryptPipe::new(client, read_key, write_key))
+    let client = if let Some((read_key, write_key, rekeying)) = keys {
+        EitherPipe::Left(if rekeying {
+            ClientExitCryptPipe::new_rekeying(client, read_key, write_key)
+        } else {
+            ClientExitCryptPipe::new(client, read_key, write_key)
+        })
     } else {
         EitherPipe::Right(client)
     };
 
-    let (client_read, client_write) = client.split();
-    let mux = PicoMux::new(client_read, client_write);
+    let (mut client_read, mut client_write) = client.split();
+    let mut first_byte = [0u8; 1];
+    client_read.read_exact(&mut first_byte).await?;
+    let (mut mux, resume_token) = if first_byte[0] == SESSION_RESUME_MAGIC {
+        let request: SessionResumeRequest =
+            stdcode::deserialize(&read_prepend_length(&mut client_read).await?)
+                .context("cannot deserialize session resume request")?;
+        let resumed = match request {
+            SessionResumeRequest::Resume(token) => RESUMABLE_SESSIONS
+                .get(&token)
+                .await
+                .filter(|session| session.mux.is_alive())
+                .map(|session| (token, session)),
+            SessionResumeRequest::New => None,
+        };
+        if let Some((token, session)) = resumed {
+            tracing::debug!("resuming a session on a fresh pipe");
+            // the credentials on this pipe were just verified, so they decide what the session gets from now on
+            *session.auth.lock().unwrap() = (ratelimit, tier);
+            write_prepend_length(
+                &SessionResumeResponse {
+                    token,
+                    resumed: true,
+                }
+                .stdcode(),
+                &mut client_write,
+            )
+            .await?;
+            session.mux.reattach(client_read, client_write)?;
+            return Ok(());
+        }
+        let token: [u8; 32] = rand::random();
+        write_prepend_length(
+            &SessionResumeResponse {
+                token,
+                resumed: false,
+            }
+            .stdcode(),
+            &mut client_write,
+        )
+        .await?;
+        (
+            PicoMux::new_resumable(client_read, client_write, ResumeConfig::default()),
+            Some(token),
+        )
+    } else {
+        (
+            PicoMux::new(
+                futures_util::io::Cursor::new(first_byte).chain(client_read),
+                client_write,
+            ),
+            None,
+        )
+    };
+    mux.set_max_concurrent_streams(CONFIG_FILE.wait().max_streams_per_session);
+    let mux = Arc::new(mux);
+    // only fresh sessions take up a slot, since resumed ones are already registered
+    let _session_guard = match (&tier, session_token) {
+        (Some(policy), Some(token)) => {
+            let weak = Arc::downgrade(&mux);
+            Some(register_session(policy, token, move || {
+                weak.upgrade().is_some_and(|mux| mux.is_alive())
+            })?)
+        }
+        _ => None,
+    };
+    let auth = Arc::new(Mutex::new((ratelimit, tier)));
+    if let Some(token) = resume_token {
+        RESUMABLE_SESSIONS
+            .insert(
+                token,
+                ResumableSession {
+                    mux: mux.clone(),
+                    auth: auth.clone(),
+                },
+            )
+            .await;
+    }
+
+    let result = serve_mux(&mux, &auth).await;
+    if let Some(token) = resume_token {
+        RESUMABLE_SESSIONS.invalidate(&token).await;
+    }
+    result
+}
 
+async fn serve_mux(mux: &PicoMux, auth: &SessionAuth) -> anyhow::Result<()> {
     let mut sess_metadata = Arc::new(serde_json::Value::Null);
     let dialer = EyeballDialer::new();
     loop {
@@ -191,16 +339,11 @@ async fn handle_client(mut client: impl Pipe) -> anyhow::Result<()> {
         }
         let sess_metadata = sess_metadata.clone();
         let dialer = dialer.clone();
+        let (ratelimit, tier) = auth.lock().unwrap().clone();
         smolscale::spawn(
-            proxy_stream(
-                dialer,
-                sess_metadata.clone(),
-                ratelimit.clone(),
-                stream,
-                is_free,
-            )
-            .race(new_task_until_death(Duration::from_secs(1)))
-            .map_err(|e| tracing::trace!(err = debug(e), "stream died with")),
+            proxy_stream(dialer, sess_metadata.clone(), ratelimit, stream, tier)
+                .race(new_task_until_death(Duration::from_secs(1)))
+                .map_err(|e| tracing::trace!(err = debug(e), "stream died with")),
         )
         .detach();
     }
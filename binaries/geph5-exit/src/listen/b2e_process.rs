@@ -8,19 +8,19 @@ use sillad_conntest::ConnTestListener;
 use sillad_sosistab3::{listener::SosistabListener, Cookie};
 use tachyonix::Receiver;
 
-use super::{handle_client, tls::dummy_tls_config};
+use super::{datagrams::DatagramListener, handle_client, tls::dummy_tls_config};
 
 pub async fn b2e_process(
     b2e_metadata: B2eMetadata,
     recv: Receiver<picomux::Stream>,
 ) -> anyhow::Result<()> {
     tracing::debug!("b2e_process called with {:?}", b2e_metadata);
-    let listener = ReceiverListener(recv);
+    let listener = ReceiverListener(recv).dynamic();
     b2e_inner(create_listener(b2e_metadata.protocol, listener)).await?;
     Ok(())
 }
 
-fn create_listener(protocol: ObfsProtocol, bottom: ReceiverListener) -> DynListener {
+fn create_listener(protocol: ObfsProtocol, bottom: DynListener) -> DynListener {
     match protocol {
         ObfsProtocol::Sosistab3(cookie) => {
             SosistabListener::new(bottom, Cookie::new(&cookie)).dynamic()
@@ -29,7 +29,7 @@ fn create_listener(protocol: ObfsProtocol, bottom: ReceiverListener) -> DynListe
             let inner = create_listener(*obfs_protocol, bottom);
             ConnTestListener::new(inner).dynamic()
         }
-        ObfsProtocol::None => bottom.dynamic(),
+        ObfsProtocol::None => bottom,
         ObfsProtocol::PlainTls(obfs_protocol) => {
             let inner = create_listener(*obfs_protocol, bottom);
             sillad_native_tls::TlsListener::new(inner, dummy_tls_config()).dynamic()
@@ -38,6 +38,9 @@ fn create_listener(protocol: ObfsProtocol, bottom: ReceiverListener) -> DynListe
             let inner = create_listener(*obfs_protocol, bottom);
             SosistabListener::new(inner, Cookie::new(&cookie)).dynamic()
         }
+        ObfsProtocol::Datagrams(obfs_protocol) => {
+            create_listener(*obfs_protocol, DatagramListener(bottom).dynamic())
+        }
     }
 }
 
@@ -0,0 +1,155 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use bipe::{BipeReader, BipeWriter};
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use geph5_misc_rpc::bridge::MAX_FORWARD_DATAGRAM;
+use sillad::{
+    Pipe,
+    listener::{DynListener, Listener},
+};
+use smol::future::FutureExt as _;
+
+/// Accepts the b2e streams of a UDP forward, which carry datagrams each prefixed with a big-endian `u16` length, as pipes that carry what is inside the datagrams.
+pub struct DatagramListener(pub DynListener);
+
+#[async_trait]
+impl Listener for DatagramListener {
+    type P = DatagramPipe;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        Ok(DatagramPipe::new(self.0.accept().await?))
+    }
+}
+
+/// A pipe over a stream of length-prefixed datagrams. Reads return the datagrams back to back, and writes are cut into datagrams of at most [MAX_FORWARD_DATAGRAM] bytes.
+pub struct DatagramPipe {
+    read_incoming: BipeReader,
+    write_outgoing: BipeWriter,
+    remote_addr: Option<String>,
+    _task: smol::Task<()>,
+}
+
+impl DatagramPipe {
+    fn new(inner: impl Pipe) -> Self {
+        let remote_addr = inner.remote_addr().map(|s| s.to_string());
+        let (inner_read, inner_write) = inner.split();
+        let (write_incoming, read_incoming) = bipe::bipe(65536);
+        let (write_outgoing, read_outgoing) = bipe::bipe(65536);
+        let task = smolscale::spawn(async move {
+            if let Err(err) = unframe(inner_read, write_incoming)
+                .race(frame(read_outgoing, inner_write))
+                .await
+            {
+                tracing::trace!(err = debug(err), "datagram pipe stopped");
+            }
+        });
+        Self {
+            read_incoming,
+            write_outgoing,
+            remote_addr,
+            _task: task,
+        }
+    }
+}
+
+async fn unframe(
+    mut inner: impl AsyncRead + Unpin,
+    mut incoming: impl AsyncWrite + Unpin,
+) -> std::io::Result<()> {
+    loop {
+        let mut len = [0u8; 2];
+        inner.read_exact(&mut len).await?;
+        let mut datagram = vec![0u8; u16::from_be_bytes(len) as usize];
+        inner.read_exact(&mut datagram).await?;
+        incoming.write_all(&datagram).await?;
+    }
+}
+
+async fn frame(
+    mut outgoing: impl AsyncRead + Unpin,
+    mut inner: impl AsyncWrite + Unpin,
+) -> std::io::Result<()> {
+    let mut buf = [0u8; MAX_FORWARD_DATAGRAM];
+    loop {
+        let n = outgoing.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        inner.write_all(&(n as u16).to_be_bytes()).await?;
+        inner.write_all(&buf[..n]).await?;
+    }
+}
+
+impl AsyncRead for DatagramPipe {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.read_incoming).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for DatagramPipe {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.write_outgoing).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.write_outgoing).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.write_outgoing).poll_close(cx)
+    }
+}
+
+impl Pipe for DatagramPipe {
+    fn protocol(&self) -> &str {
+        "b2e-datagrams"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.remote_addr.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_framing() {
+        smolscale::block_on(async {
+            let (mut outgoing_write, outgoing_read) = bipe::bipe(65536);
+            let (framed_write, mut framed_read) = bipe::bipe(65536);
+            let (incoming_write, mut incoming_read) = bipe::bipe(65536);
+            let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+            outgoing_write.write_all(&data).await.unwrap();
+            drop(outgoing_write);
+            frame(outgoing_read, framed_write).await.unwrap();
+
+            // every datagram fits the limit, and unframing them gives back the data
+            let mut framed = vec![];
+            framed_read.read_to_end(&mut framed).await.unwrap();
+            let mut rest = &framed[..];
+            while let [a, b, tail @ ..] = rest {
+                let len = u16::from_be_bytes([*a, *b]) as usize;
+                assert!(len <= MAX_FORWARD_DATAGRAM);
+                rest = &tail[len..];
+            }
+            let _ = unframe(&framed[..], incoming_write).await;
+            let mut unframed = vec![];
+            incoming_read.read_to_end(&mut unframed).await.unwrap();
+            assert_eq!(unframed, data);
+        });
+    }
+}
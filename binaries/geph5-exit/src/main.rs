@@ -13,8 +13,9 @@ use listen::listen_main;
 use once_cell::sync::{Lazy, OnceCell};
 use rand::Rng;
 use serde::Deserialize;
-use serde_with::{DisplayFromStr, serde_as};
+use serde_with::{DisplayFromStr, OneOrMany, serde_as};
 use std::{
+    collections::BTreeMap,
     net::{IpAddr, SocketAddr},
     path::PathBuf,
 };
@@ -27,6 +28,8 @@ mod listen;
 mod proxy;
 mod ratelimit;
 mod schedlag;
+mod session;
+mod tier;
 
 #[cfg(target_env = "musl")]
 #[global_allocator]
@@ -60,18 +63,60 @@ struct ConfigFile {
     #[serde(default = "default_plus_ratelimit")]
     plus_ratelimit: u32,
 
+    /// The hex-encoded mizaru public keys accepted for Free users. Several keys may be listed during a key rollover.
+    #[serde_as(as = "OneOrMany<_>")]
+    #[serde(default = "default_free_mizaru_pk")]
+    free_mizaru_pk: Vec<String>,
+
+    /// The hex-encoded mizaru public keys accepted for Plus users. Several keys may be listed during a key rollover.
+    #[serde_as(as = "OneOrMany<_>")]
+    #[serde(default = "default_plus_mizaru_pk")]
+    plus_mizaru_pk: Vec<String>,
+
+    /// If set, the exit also accepts the mizaru public keys that the broker advertises, pinning the first one seen for each tier to this file so that it stays accepted across restarts and broker outages. Different keys advertised later are refused until configured explicitly.
+    #[serde(default)]
+    mizaru_pin_file: Option<PathBuf>,
+
     #[serde(default = "default_total_ratelimit")]
     total_ratelimit: u32,
 
     #[serde(default = "default_free_port_whitelist")]
     free_port_whitelist: Vec<u16>,
 
+    /// The maximum number of clients a single Free token may drive at once. Absent means no limit.
+    #[serde(default)]
+    free_max_sessions: Option<usize>,
+
+    /// The maximum number of clients a single Plus token may drive at once. Absent means no limit.
+    #[serde(default)]
+    plus_max_sessions: Option<usize>,
+
+    /// Whether to report anonymous token reuse counts to the broker, so that shared or leaked tokens show up in the stats.
+    #[serde(default)]
+    report_token_reuse: bool,
+
+    /// Account tiers served by this exit, in addition to Free and Plus. A tier named "free" or "plus" replaces the built-in one.
+    #[serde(default)]
+    tiers: Vec<TierConfig>,
+
     #[serde(default = "default_task_limit")]
     task_limit: usize,
 
+    /// The maximum number of concurrent streams a single client session may open, advertised to the client so that it waits instead of having streams killed.
+    #[serde(default)]
+    max_streams_per_session: Option<u32>,
+
     #[serde_as(as = "DisplayFromStr")]
     #[serde(default)]
     ipv6_subnet: Ipv6Net,
+
+    /// Operator tags advertised in the exit descriptor, like "streaming".
+    #[serde(default)]
+    tags: Vec<String>,
+
+    /// Extra metadata advertised in the exit descriptor, overriding the detected values.
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
 }
 
 fn default_free_ratelimit() -> u32 {
@@ -82,6 +127,14 @@ fn default_plus_ratelimit() -> u32 {
     50000
 }
 
+fn default_free_mizaru_pk() -> Vec<String> {
+    vec!["0558216cbab7a9c46f298f4c26e171add9af87d0694988b8a8fe52ee932aa754".into()]
+}
+
+fn default_plus_mizaru_pk() -> Vec<String> {
+    vec!["cf6f58868c6d9459b3a63bc2bd86165631b3e916bad7f62b578cd9614e0bcb3b".into()]
+}
+
 fn default_total_ratelimit() -> u32 {
     125000
 }
@@ -98,6 +151,27 @@ fn default_country_blacklist() -> Vec<String> {
     vec![]
 }
 
+/// The configuration of an account tier.
+#[serde_as]
+#[derive(Deserialize)]
+struct TierConfig {
+    name: String,
+    /// The hex-encoded mizaru public keys that sign the tier's connect tokens. Several keys may be listed during a key rollover.
+    #[serde_as(as = "OneOrMany<_>")]
+    mizaru_pk: Vec<String>,
+    /// The speed limit, in KB/s.
+    ratelimit: u32,
+    /// The burst size, in KB. Defaults to one second at the speed limit.
+    #[serde(default)]
+    burst: Option<u32>,
+    /// The only ports users of the tier may connect to. Absent means any port.
+    #[serde(default)]
+    port_whitelist: Option<Vec<u16>>,
+    /// The maximum number of clients a single token may drive at once. Absent means no limit.
+    #[serde(default)]
+    max_sessions: Option<usize>,
+}
+
 #[derive(Deserialize)]
 struct BrokerConfig {
     url: String,
@@ -145,6 +219,7 @@ fn main() -> anyhow::Result<()> {
     let config: ConfigFile = serde_yaml::from_slice(&std::fs::read(args.config)?)?;
 
     CONFIG_FILE.set(config).ok().unwrap();
+    Lazy::force(&tier::TIERS);
 
     smol::future::block_on(smolscale::spawn(listen_main()))
 }
@@ -14,6 +14,7 @@ use crate::{
     dns::{dns_resolve, raw_dns_respond, FilterOptions},
     ipv6::EyeballDialer,
     ratelimit::RateLimiter,
+    tier::TierPolicy,
 };
 
 use smol_timeout2::TimeoutExt;
@@ -24,7 +25,7 @@ pub async fn proxy_stream(
     sess_metadata: Arc<serde_json::Value>,
     ratelimit: RateLimiter,
     stream: picomux::Stream,
-    is_free: bool,
+    tier: Option<Arc<TierPolicy>>,
 ) -> anyhow::Result<()> {
     let dest_host = String::from_utf8_lossy(stream.metadata());
     let (protocol, dest_host): (&str, &str) = if dest_host.contains('$') {
@@ -37,7 +38,11 @@ pub async fn proxy_stream(
     let dest_addrs = dns_resolve(dest_host, filter)
         .await
         .context("failed to resolve DNS")?;
-    if !dest_addrs.iter().all(|addr| proxy_allowed(*addr, is_free)) {
+    let port_whitelist = tier.as_ref().and_then(|t| t.port_whitelist.as_deref());
+    if !dest_addrs
+        .iter()
+        .all(|addr| proxy_allowed(*addr, port_whitelist))
+    {
         anyhow::bail!("Proxying to {} is not allowed", dest_host);
     }
 
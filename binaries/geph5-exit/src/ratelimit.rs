@@ -10,7 +10,6 @@ use std::{
 use async_io_bufpool::pooled_read;
 use atomic_float::AtomicF32;
 use futures_util::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use geph5_broker_protocol::AccountLevel;
 use governor::{DefaultDirectRateLimiter, Quota};
 use mizaru2::ClientToken;
 use moka::future::Cache;
@@ -19,15 +18,9 @@ use smol_timeout2::TimeoutExt;
 use stdcode::StdcodeSerializeExt;
 use sysinfo::System;
 
-use crate::CONFIG_FILE;
+use crate::{tier::TierPolicy, CONFIG_FILE};
 
-static FREE_RL_CACHE: Lazy<Cache<blake3::Hash, RateLimiter>> = Lazy::new(|| {
-    Cache::builder()
-        .time_to_idle(Duration::from_secs(86400))
-        .build()
-});
-
-static PLUS_RL_CACHE: Lazy<Cache<blake3::Hash, RateLimiter>> = Lazy::new(|| {
+static RL_CACHE: Lazy<Cache<blake3::Hash, RateLimiter>> = Lazy::new(|| {
     Cache::builder()
         .time_to_idle(Duration::from_secs(86400))
         .build()
@@ -76,29 +69,12 @@ pub fn update_load_loop() {
     }
 }
 
-pub async fn get_ratelimiter(level: AccountLevel, token: ClientToken) -> RateLimiter {
-    match level {
-        AccountLevel::Free => {
-            FREE_RL_CACHE
-                .get_with(blake3::hash(&(level, token).stdcode()), async {
-                    RateLimiter::new(
-                        CONFIG_FILE.wait().free_ratelimit,
-                        CONFIG_FILE.wait().free_ratelimit,
-                    )
-                })
-                .await
-        }
-        AccountLevel::Plus => {
-            PLUS_RL_CACHE
-                .get_with(blake3::hash(&(level, token).stdcode()), async {
-                    RateLimiter::new(
-                        CONFIG_FILE.wait().plus_ratelimit,
-                        CONFIG_FILE.wait().plus_ratelimit * 5,
-                    )
-                })
-                .await
-        }
-    }
+pub async fn get_ratelimiter(tier: &TierPolicy, token: ClientToken) -> RateLimiter {
+    RL_CACHE
+        .get_with(blake3::hash(&(&tier.name, token).stdcode()), async {
+            RateLimiter::new(tier.ratelimit, tier.burst)
+        })
+        .await
 }
 
 /// A generic rate limiter.
@@ -0,0 +1,149 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use mizaru2::ClientToken;
+use once_cell::sync::Lazy;
+use stdcode::StdcodeSerializeExt;
+
+use crate::tier::TierPolicy;
+
+/// How many sessions one client drives at once. Clients open this many pipes in parallel, each carrying its own session, so session caps are counted in whole clients.
+pub const SESSIONS_PER_CLIENT: usize = 8;
+
+type LivenessCheck = Box<dyn Fn() -> bool + Send>;
+type SessionList = Vec<(u64, LivenessCheck)>;
+
+/// Active sessions, keyed by the hash of the tier name and token, with a way to tell whether each is still alive. Only hashes are kept, so nothing here links back to an account.
+static ACTIVE_SESSIONS: Lazy<Mutex<HashMap<blake3::Hash, SessionList>>> =
+    Lazy::new(Default::default);
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Sessions started with a token that was already driving a whole client's worth of sessions.
+static REUSED_SESSIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Sessions rejected because their token hit the concurrency cap.
+static REJECTED_SESSIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Gets the total number of reused and rejected sessions so far.
+pub fn get_reuse_counts() -> (u64, u64) {
+    (
+        REUSED_SESSIONS.load(Ordering::Relaxed),
+        REJECTED_SESSIONS.load(Ordering::Relaxed),
+    )
+}
+
+/// Registers a new session for the given token, failing if the token already drives as many clients' worth of sessions as its tier allows. Sessions that `is_alive` reports dead, like those of a client that reconnected before its old pipes timed out, stop counting. The session counts as active until the guard is dropped.
+pub fn register_session(
+    tier: &TierPolicy,
+    token: ClientToken,
+    is_alive: impl Fn() -> bool + Send + 'static,
+) -> anyhow::Result<SessionGuard> {
+    let key = blake3::hash(&(&tier.name, token).stdcode());
+    let mut sessions = ACTIVE_SESSIONS.lock().unwrap();
+    let active = sessions.entry(key).or_default();
+    active.retain(|(_, is_alive)| is_alive());
+    if tier
+        .max_sessions
+        .is_some_and(|max| active.len() >= max * SESSIONS_PER_CLIENT)
+    {
+        REJECTED_SESSIONS.fetch_add(1, Ordering::Relaxed);
+        anyhow::bail!(
+            "token already drives {} sessions, the maximum for its tier",
+            active.len()
+        )
+    }
+    if active.len() >= SESSIONS_PER_CLIENT {
+        REUSED_SESSIONS.fetch_add(1, Ordering::Relaxed);
+    }
+    let id = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+    active.push((id, Box::new(is_alive)));
+    Ok(SessionGuard { key, id })
+}
+
+/// Keeps a session registered as active.
+pub struct SessionGuard {
+    key: blake3::Hash,
+    id: u64,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let mut sessions = ACTIVE_SESSIONS.lock().unwrap();
+        if let Some(active) = sessions.get_mut(&self.key) {
+            active.retain(|(id, _)| *id != self.id);
+            if active.is_empty() {
+                sessions.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, atomic::AtomicBool};
+
+    use super::*;
+
+    fn tier(max_sessions: usize) -> TierPolicy {
+        TierPolicy {
+            name: "test".to_string(),
+            mizaru_pks: vec![],
+            ratelimit: 100,
+            burst: 100,
+            port_whitelist: None,
+            max_sessions: Some(max_sessions),
+        }
+    }
+
+    fn register_client(tier: &TierPolicy, token: ClientToken) -> Vec<SessionGuard> {
+        (0..SESSIONS_PER_CLIENT)
+            .map(|_| register_session(tier, token, || true).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_session_cap() {
+        let tier = tier(2);
+        let token = ClientToken::random();
+        let first = register_client(&tier, token);
+        let _second = register_client(&tier, token);
+        assert!(register_session(&tier, token, || true).is_err());
+        // other tokens are unaffected
+        let _other = register_client(&tier, ClientToken::random());
+        // closing a session frees up a slot
+        drop(first);
+        let _third = register_client(&tier, token);
+    }
+
+    #[test]
+    fn test_session_cap_one_client() {
+        // a single client opening all its pipes fits under the smallest cap
+        let tier = tier(1);
+        let token = ClientToken::random();
+        let _client = register_client(&tier, token);
+        assert!(register_session(&tier, token, || true).is_err());
+    }
+
+    #[test]
+    fn test_dead_sessions_do_not_count() {
+        let tier = tier(1);
+        let token = ClientToken::random();
+        let alive = Arc::new(AtomicBool::new(true));
+        let _old: Vec<_> = (0..SESSIONS_PER_CLIENT)
+            .map(|_| {
+                let alive = alive.clone();
+                register_session(&tier, token, move || alive.load(Ordering::Relaxed)).unwrap()
+            })
+            .collect();
+        assert!(register_session(&tier, token, || true).is_err());
+        // the client reconnects after its old pipes silently died
+        alive.store(false, Ordering::Relaxed);
+        let _new = register_client(&tier, token);
+    }
+}
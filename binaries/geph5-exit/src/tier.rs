@@ -0,0 +1,167 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Context;
+use geph5_broker_protocol::AccountLevel;
+use once_cell::sync::Lazy;
+
+use crate::CONFIG_FILE;
+
+/// How this exit treats the users of one account tier.
+pub struct TierPolicy {
+    pub name: String,
+    /// The configured mizaru public keys whose tokens are accepted.
+    pub mizaru_pks: Vec<mizaru2::PublicKey>,
+    /// The speed limit, in KB/s.
+    pub ratelimit: u32,
+    /// The burst size, in KB.
+    pub burst: u32,
+    /// The only ports that users of the tier may connect to, if they are restricted.
+    pub port_whitelist: Option<Vec<u16>>,
+    /// The maximum number of clients a single token may drive at once, if limited. Each client drives [crate::session::SESSIONS_PER_CLIENT] sessions.
+    pub max_sessions: Option<usize>,
+}
+
+impl TierPolicy {
+    /// All the mizaru public keys whose tokens are accepted for this tier, including the ones pinned from the broker.
+    pub fn accepted_keys(&self) -> Vec<mizaru2::PublicKey> {
+        let mut keys = self.mizaru_pks.clone();
+        if let Some(pinned) = PINNED_KEYS.read().unwrap().get(&self.name) {
+            keys.extend(pinned.iter().map(|pk| mizaru2::PublicKey::from_bytes(*pk)));
+        }
+        keys
+    }
+}
+
+/// The tiers this exit serves, by name. Free and Plus are built from the legacy config fields, unless the `tiers` list redefines them.
+pub static TIERS: Lazy<HashMap<String, Arc<TierPolicy>>> = Lazy::new(|| {
+    let config = CONFIG_FILE.wait();
+    let mut tiers = HashMap::new();
+    tiers.insert(
+        "free".to_string(),
+        Arc::new(TierPolicy {
+            name: "free".to_string(),
+            mizaru_pks: decode_mizaru_pks(&config.free_mizaru_pk)
+                .context("bad mizaru key for the free tier")
+                .unwrap(),
+            ratelimit: config.free_ratelimit,
+            burst: config.free_ratelimit,
+            port_whitelist: Some(config.free_port_whitelist.clone()),
+            max_sessions: config.free_max_sessions,
+        }),
+    );
+    tiers.insert(
+        "plus".to_string(),
+        Arc::new(TierPolicy {
+            name: "plus".to_string(),
+            mizaru_pks: decode_mizaru_pks(&config.plus_mizaru_pk)
+                .context("bad mizaru key for the plus tier")
+                .unwrap(),
+            ratelimit: config.plus_ratelimit,
+            burst: config.plus_ratelimit * 5,
+            port_whitelist: None,
+            max_sessions: config.plus_max_sessions,
+        }),
+    );
+    for tier in &config.tiers {
+        tiers.insert(
+            tier.name.clone(),
+            Arc::new(TierPolicy {
+                name: tier.name.clone(),
+                mizaru_pks: decode_mizaru_pks(&tier.mizaru_pk)
+                    .with_context(|| format!("bad mizaru key for tier {}", tier.name))
+                    .unwrap(),
+                ratelimit: tier.ratelimit,
+                burst: tier.burst.unwrap_or(tier.ratelimit),
+                port_whitelist: tier.port_whitelist.clone(),
+                max_sessions: tier.max_sessions,
+            }),
+        );
+    }
+    tiers
+});
+
+/// Gets the policy for an account level, or `None` if this exit does not serve that tier.
+pub fn tier_policy(level: &AccountLevel) -> Option<Arc<TierPolicy>> {
+    TIERS.get(level.name()).cloned()
+}
+
+/// Mizaru public keys learned from the broker, by tier name.
+static PINNED_KEYS: Lazy<RwLock<BTreeMap<String, Vec<[u8; 32]>>>> = Lazy::new(|| {
+    let pinned = CONFIG_FILE
+        .wait()
+        .mizaru_pin_file
+        .as_ref()
+        .and_then(|path| std::fs::read(path).ok())
+        .and_then(|bts| serde_json::from_slice::<BTreeMap<String, Vec<String>>>(&bts).ok())
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(tier, keys)| {
+            let keys = decode_mizaru_pks(&keys).ok()?;
+            Some((tier, keys.iter().map(|pk| pk.to_bytes()).collect()))
+        })
+        .collect();
+    RwLock::new(pinned)
+});
+
+/// Pins the mizaru public keys that the broker advertises for tiers that have none pinned yet, persisting them if anything changed.
+///
+/// Pinned keys are never replaced: a broker that advertises a different key for a tier is either rolling over or compromised, and only the operator can tell which. Such keys are refused with an error, and can be accepted by adding them to the tier's configured keys or by removing the tier from the pin file.
+pub fn pin_mizaru_keys(advertised: BTreeMap<String, mizaru2::PublicKey>) -> anyhow::Result<()> {
+    let Some(path) = &CONFIG_FILE.wait().mizaru_pin_file else {
+        return Ok(());
+    };
+    let mut pinned = PINNED_KEYS.write().unwrap();
+    let mut changed = false;
+    for (tier, pk) in advertised {
+        let configured = TIERS.get(&tier).is_some_and(|policy| {
+            policy
+                .mizaru_pks
+                .iter()
+                .any(|configured| configured.to_bytes() == pk.to_bytes())
+        });
+        let keys = pinned.entry(tier.clone()).or_default();
+        if configured || keys.contains(&pk.to_bytes()) {
+            continue;
+        }
+        if keys.is_empty() {
+            tracing::info!(
+                tier = display(&tier),
+                pk = hex::encode(pk.to_bytes()),
+                "pinning mizaru key"
+            );
+            keys.push(pk.to_bytes());
+            changed = true;
+        } else {
+            tracing::error!(
+                tier = display(&tier),
+                pk = hex::encode(pk.to_bytes()),
+                pinned = debug(keys.iter().map(hex::encode).collect::<Vec<_>>()),
+                "broker advertises a mizaru key that differs from the pinned one, refusing it"
+            );
+        }
+    }
+    if changed {
+        let to_save: BTreeMap<&String, Vec<String>> = pinned
+            .iter()
+            .map(|(tier, keys)| (tier, keys.iter().map(hex::encode).collect()))
+            .collect();
+        std::fs::write(path, serde_json::to_vec_pretty(&to_save)?)?;
+    }
+    Ok(())
+}
+
+fn decode_mizaru_pks(hex_pks: &[String]) -> anyhow::Result<Vec<mizaru2::PublicKey>> {
+    hex_pks
+        .iter()
+        .map(|hex_pk| {
+            let bts: [u8; 32] = hex::decode(hex_pk)?
+                .try_into()
+                .ok()
+                .context("mizaru public key must be 32 bytes")?;
+            Ok(mizaru2::PublicKey::from_bytes(bts))
+        })
+        .collect()
+}
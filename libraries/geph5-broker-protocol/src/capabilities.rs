@@ -0,0 +1,197 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::RouteDescriptor;
+
+/// The version of the broker protocol spoken by this crate. Peers that never negotiate are assumed to speak version 0.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Names of the optional features that peers may advertise.
+pub mod feature {
+    pub const ROUTE_SOSISTAB3: &str = "route.sosistab3";
+    pub const ROUTE_PLAIN_TLS: &str = "route.plain_tls";
+    pub const ROUTE_TIMEOUT: &str = "route.timeout";
+    pub const ROUTE_DELAY: &str = "route.delay";
+    pub const ROUTE_CONN_TEST: &str = "route.conn_test";
+    pub const CREDENTIAL_LEGACY: &str = "credential.legacy_username_password";
+    pub const CREDENTIAL_SECRET: &str = "credential.secret";
+    /// [crate::Credential::Device], along with the RPCs to enroll, list and revoke devices.
+    pub const CREDENTIAL_DEVICE: &str = "credential.device";
+    /// `regenerate_secret`, `delete_account` and `get_login_history`.
+    pub const ACCOUNT_SELF_SERVICE: &str = "account_self_service";
+    /// Account levels other than Free and Plus, along with `get_tier_exits`.
+    pub const ACCOUNT_TIERS: &str = "account_tiers";
+    /// `get_routes_v2`, which tailors routes to the capabilities of the caller and signs them.
+    pub const TAILORED_ROUTES: &str = "tailored_routes";
+    /// `get_exits_v2`, along with the metadata of exit descriptors.
+    pub const EXIT_METADATA: &str = "exit_metadata";
+    /// `get_exits_since`, which sends exit list deltas.
+    pub const EXIT_LIST_DELTA: &str = "exit_list_delta";
+}
+
+/// The protocol version and features that one side of the broker protocol supports.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct Capabilities {
+    pub protocol_version: u32,
+    pub features: BTreeSet<String>,
+}
+
+impl Capabilities {
+    /// Everything that this build supports.
+    pub fn current() -> Self {
+        let mut features = Self::legacy().features;
+        features.insert(feature::ACCOUNT_TIERS.to_string());
+        features.insert(feature::TAILORED_ROUTES.to_string());
+        features.insert(feature::EXIT_METADATA.to_string());
+        features.insert(feature::EXIT_LIST_DELTA.to_string());
+        features.insert(feature::CREDENTIAL_DEVICE.to_string());
+        features.insert(feature::ACCOUNT_SELF_SERVICE.to_string());
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            features,
+        }
+    }
+
+    /// What every peer supported before capabilities were negotiated. This is assumed for peers that do not negotiate.
+    pub fn legacy() -> Self {
+        Self {
+            protocol_version: 0,
+            features: [
+                feature::ROUTE_SOSISTAB3,
+                feature::ROUTE_PLAIN_TLS,
+                feature::ROUTE_TIMEOUT,
+                feature::ROUTE_DELAY,
+                feature::ROUTE_CONN_TEST,
+                feature::CREDENTIAL_LEGACY,
+                feature::CREDENTIAL_SECRET,
+            ]
+            .into_iter()
+            .map(|s| s.to_string())
+            .collect(),
+        }
+    }
+
+    /// Whether the given feature is supported.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.contains(feature)
+    }
+}
+
+impl RouteDescriptor {
+    /// Rewrites the route so that it only uses what the given peer supports. Unsupported wrappers like delays and connection tests are dropped, while unsupported transports are removed along with everything that depends on them. Returns `None` if nothing usable is left.
+    pub fn restrict_to(&self, caps: &Capabilities) -> Option<RouteDescriptor> {
+        match self {
+            RouteDescriptor::Tcp(addr) => Some(RouteDescriptor::Tcp(*addr)),
+            RouteDescriptor::Sosistab3 { cookie, lower } => {
+                if !caps.supports(feature::ROUTE_SOSISTAB3) {
+                    return None;
+                }
+                Some(RouteDescriptor::Sosistab3 {
+                    cookie: cookie.clone(),
+                    lower: lower.restrict_to(caps)?.into(),
+                })
+            }
+            RouteDescriptor::PlainTls { sni_domain, lower } => {
+                if !caps.supports(feature::ROUTE_PLAIN_TLS) {
+                    return None;
+                }
+                Some(RouteDescriptor::PlainTls {
+                    sni_domain: sni_domain.clone(),
+                    lower: lower.restrict_to(caps)?.into(),
+                })
+            }
+            RouteDescriptor::Race(routes) => {
+                let routes: Vec<_> = routes.iter().filter_map(|r| r.restrict_to(caps)).collect();
+                (!routes.is_empty()).then_some(RouteDescriptor::Race(routes))
+            }
+            RouteDescriptor::Fallback(routes) => {
+                let routes: Vec<_> = routes.iter().filter_map(|r| r.restrict_to(caps)).collect();
+                (!routes.is_empty()).then_some(RouteDescriptor::Fallback(routes))
+            }
+            RouteDescriptor::Timeout {
+                milliseconds,
+                lower,
+            } => {
+                let lower = lower.restrict_to(caps)?;
+                if caps.supports(feature::ROUTE_TIMEOUT) {
+                    Some(RouteDescriptor::Timeout {
+                        milliseconds: *milliseconds,
+                        lower: lower.into(),
+                    })
+                } else {
+                    Some(lower)
+                }
+            }
+            RouteDescriptor::Delay {
+                milliseconds,
+                lower,
+            } => {
+                let lower = lower.restrict_to(caps)?;
+                if caps.supports(feature::ROUTE_DELAY) {
+                    Some(RouteDescriptor::Delay {
+                        milliseconds: *milliseconds,
+                        lower: lower.into(),
+                    })
+                } else {
+                    Some(lower)
+                }
+            }
+            RouteDescriptor::ConnTest { ping_count, lower } => {
+                let lower = lower.restrict_to(caps)?;
+                if caps.supports(feature::ROUTE_CONN_TEST) {
+                    Some(RouteDescriptor::ConnTest {
+                        ping_count: *ping_count,
+                        lower: lower.into(),
+                    })
+                } else {
+                    Some(lower)
+                }
+            }
+            RouteDescriptor::Other(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restrict_route() {
+        let tcp = RouteDescriptor::Tcp("1.2.3.4:5".parse().unwrap());
+        let route = RouteDescriptor::Race(vec![
+            RouteDescriptor::ConnTest {
+                ping_count: 1,
+                lower: tcp.clone().into(),
+            },
+            RouteDescriptor::Sosistab3 {
+                cookie: "hello".into(),
+                lower: tcp.clone().into(),
+            },
+            RouteDescriptor::Other(serde_json::json!({"future": 1})),
+        ]);
+
+        let restricted = route.restrict_to(&Capabilities::current()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&restricted).unwrap(),
+            serde_json::to_value(RouteDescriptor::Race(vec![
+                RouteDescriptor::ConnTest {
+                    ping_count: 1,
+                    lower: tcp.clone().into(),
+                },
+                RouteDescriptor::Sosistab3 {
+                    cookie: "hello".into(),
+                    lower: tcp.clone().into(),
+                },
+            ]))
+            .unwrap()
+        );
+
+        let restricted = route.restrict_to(&Capabilities::default()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&restricted).unwrap(),
+            serde_json::to_value(RouteDescriptor::Race(vec![tcp])).unwrap()
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+pub const DOMAIN_DEVICE_CHALLENGE: &str = "device-challenge";
+
+/// Proof that a device holds the secret half of an ed25519 key: a signature over a challenge that the broker issued.
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct DeviceProof {
+    pub public_key: VerifyingKey,
+    pub challenge: String,
+    #[serde_as(as = "serde_with::hex::Hex")]
+    pub signature: [u8; 64],
+}
+
+impl DeviceProof {
+    /// Answers a broker-issued challenge with the given device key.
+    pub fn new(device_key: &SigningKey, challenge: String) -> Self {
+        let signature = device_key.sign(challenge_message(&challenge).as_bytes());
+        Self {
+            public_key: device_key.verifying_key(),
+            challenge,
+            signature: signature.to_bytes(),
+        }
+    }
+
+    /// Checks that the signature is valid for the challenge. This says nothing about whether the challenge itself is fresh, which only the broker can tell.
+    pub fn verify(&self) -> bool {
+        self.public_key
+            .verify_strict(
+                challenge_message(&self.challenge).as_bytes(),
+                &Signature::from_bytes(&self.signature),
+            )
+            .is_ok()
+    }
+}
+
+fn challenge_message(challenge: &str) -> blake3::Hash {
+    blake3::keyed_hash(
+        blake3::hash(DOMAIN_DEVICE_CHALLENGE.as_bytes()).as_bytes(),
+        challenge.as_bytes(),
+    )
+}
+
+/// A device enrolled to an account.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub public_key: VerifyingKey,
+    pub name: String,
+    pub enrolled_unix: u64,
+    /// When the device last logged in, if it ever did.
+    pub last_used_unix: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_proof() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let proof = DeviceProof::new(&key, "hello".into());
+        assert!(proof.verify());
+
+        // the proof only covers its own challenge
+        let mut wrong_challenge = proof.clone();
+        wrong_challenge.challenge = "world".into();
+        assert!(!wrong_challenge.verify());
+
+        // and only holds for the key that made it
+        let mut wrong_key = proof.clone();
+        wrong_key.public_key = SigningKey::from_bytes(&[2; 32]).verifying_key();
+        assert!(!wrong_key.verify());
+
+        // survives the JSON round trip that RPCs put it through
+        let json = serde_json::to_string(&proof).unwrap();
+        assert_eq!(serde_json::from_str::<DeviceProof>(&json).unwrap(), proof);
+    }
+}
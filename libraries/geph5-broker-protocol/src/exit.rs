@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     net::SocketAddr,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
@@ -24,6 +24,57 @@ pub struct ExitDescriptor {
     pub load: f32,
     /// When does this descriptor expire?
     pub expiry: u64,
+    /// Capabilities and operator tags, keyed by the names in [exit_metadata]. Left out of the encoding when empty, so that descriptors without metadata are signed exactly as before.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// Well-known keys of [ExitDescriptor::metadata]. Exits may set other keys too, which clients can still filter on.
+pub mod exit_metadata {
+    /// "true" if the exit can reach IPv6 destinations.
+    pub const IPV6: &str = "ipv6";
+    /// "true" if the exit proxies UDP.
+    pub const UDP: &str = "udp";
+    /// The comma-separated obfuscation protocols that the c2e port speaks.
+    pub const C2E_PROTOCOLS: &str = "c2e_protocols";
+    /// The total bandwidth capacity of the exit, in Mbps.
+    pub const BANDWIDTH_MBPS: &str = "bandwidth_mbps";
+    /// Comma-separated operator tags, like "streaming".
+    pub const TAGS: &str = "tags";
+    /// The comma-separated optional crypt hellos that the exit accepts, on top of plain X25519 and shared-secret challenges.
+    pub const CRYPT_HELLOS: &str = "crypt_hellos";
+    /// "true" if the exit can resume sessions on fresh pipes.
+    pub const SESSION_RESUMPTION: &str = "session_resumption";
+}
+
+/// A requirement that an exit's metadata must meet.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataRequirement {
+    /// The key must have exactly this value.
+    Equals(String, String),
+    /// The key must have a numeric value of at least this much.
+    AtLeast(String, f64),
+    /// The comma-separated list under the key must contain this item.
+    Contains(String, String),
+}
+
+impl ExitDescriptor {
+    /// Whether the exit's metadata meets the requirement.
+    pub fn satisfies(&self, requirement: &MetadataRequirement) -> bool {
+        match requirement {
+            MetadataRequirement::Equals(key, value) => self.metadata.get(key) == Some(value),
+            MetadataRequirement::AtLeast(key, min) => self
+                .metadata
+                .get(key)
+                .and_then(|v| v.parse::<f64>().ok())
+                .is_some_and(|v| v >= *min),
+            MetadataRequirement::Contains(key, item) => self
+                .metadata
+                .get(key)
+                .is_some_and(|v| v.split(',').any(|i| i.trim() == item)),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -35,6 +86,14 @@ pub struct ExitList {
 }
 
 impl ExitList {
+    /// Removes the metadata of every exit, for peers that would fail to verify a list containing it.
+    pub fn without_metadata(mut self) -> Self {
+        for (_, exit) in self.all_exits.iter_mut() {
+            exit.metadata.clear();
+        }
+        self
+    }
+
     /// A convenience method to find the overall expiry time of the exit list.
     pub fn expiry(&self) -> SystemTime {
         UNIX_EPOCH
@@ -47,3 +106,73 @@ impl ExitList {
             )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use stdcode::StdcodeSerializeExt;
+
+    use super::*;
+
+    fn descriptor() -> ExitDescriptor {
+        ExitDescriptor {
+            c2e_listen: "1.2.3.4:5".parse().unwrap(),
+            b2e_listen: "1.2.3.4:6".parse().unwrap(),
+            country: CountryCode::CAN,
+            city: "Montreal".into(),
+            load: 0.5,
+            expiry: 100,
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_empty_metadata_encoding() {
+        // the encoding that older peers sign and verify
+        let d = descriptor();
+        let legacy = (
+            d.c2e_listen,
+            d.b2e_listen,
+            d.country,
+            &d.city,
+            d.load,
+            d.expiry,
+        )
+            .stdcode();
+        assert_eq!(d.stdcode(), legacy);
+    }
+
+    #[test]
+    fn test_satisfies() {
+        let mut d = descriptor();
+        d.metadata.insert(exit_metadata::IPV6.into(), "true".into());
+        d.metadata
+            .insert(exit_metadata::BANDWIDTH_MBPS.into(), "1000".into());
+        d.metadata
+            .insert(exit_metadata::TAGS.into(), "streaming, gaming".into());
+
+        assert!(d.satisfies(&MetadataRequirement::Equals(
+            exit_metadata::IPV6.into(),
+            "true".into()
+        )));
+        assert!(!d.satisfies(&MetadataRequirement::Equals(
+            exit_metadata::UDP.into(),
+            "true".into()
+        )));
+        assert!(d.satisfies(&MetadataRequirement::AtLeast(
+            exit_metadata::BANDWIDTH_MBPS.into(),
+            500.0
+        )));
+        assert!(!d.satisfies(&MetadataRequirement::AtLeast(
+            exit_metadata::BANDWIDTH_MBPS.into(),
+            5000.0
+        )));
+        assert!(d.satisfies(&MetadataRequirement::Contains(
+            exit_metadata::TAGS.into(),
+            "gaming".into()
+        )));
+        assert!(!d.satisfies(&MetadataRequirement::Contains(
+            exit_metadata::TAGS.into(),
+            "torrent".into()
+        )));
+    }
+}
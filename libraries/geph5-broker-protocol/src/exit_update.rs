@@ -0,0 +1,189 @@
+use std::collections::{BTreeMap, HashMap};
+
+use ed25519_dalek::VerifyingKey;
+use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
+use thiserror::Error;
+
+use crate::{ExitDescriptor, ExitList};
+
+pub const DOMAIN_EXIT_LIST_UPDATE: &str = "exit-list-update";
+
+/// How the exit list changed since the version that a client already has.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitListUpdate {
+    /// The list is still at the given version.
+    Unchanged(blake3::Hash),
+    /// The changes to apply to the client's list.
+    Delta(ExitListDelta),
+    /// The whole list, when the client's version is unknown or a delta is impossible.
+    Full(ExitList),
+}
+
+/// An exit list update along with when the broker made it. The two are signed together, so that an old update cannot be replayed as a current one.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TimestampedExitListUpdate {
+    pub update: ExitListUpdate,
+    /// Unix timestamp, in seconds, of when the broker made the update.
+    pub timestamp: u64,
+}
+
+/// The differences between two versions of an exit list.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExitListDelta {
+    /// The version that the delta applies to.
+    pub base_version: blake3::Hash,
+    /// The version that results from applying the delta.
+    pub version: blake3::Hash,
+    /// Exits that are new or whose descriptors changed.
+    pub changed: Vec<(VerifyingKey, ExitDescriptor)>,
+    /// Exits that are gone.
+    pub removed: Vec<VerifyingKey>,
+}
+
+#[derive(Error, Debug)]
+pub enum DeltaError {
+    #[error("delta applies to a different version")]
+    WrongBase,
+    #[error("applying the delta gave the wrong version")]
+    WrongResult,
+}
+
+impl ExitList {
+    /// The version of the list, which is a hash of its contents that does not depend on the order of exits or city names.
+    pub fn version(&self) -> blake3::Hash {
+        let mut exits: Vec<_> = self
+            .all_exits
+            .iter()
+            .map(|(pk, exit)| (pk.as_bytes(), exit))
+            .collect();
+        exits.sort_unstable_by_key(|(pk, _)| *pk);
+        let city_names: BTreeMap<&String, BTreeMap<&str, &String>> = self
+            .city_names
+            .iter()
+            .map(|(city, names)| {
+                (
+                    city,
+                    names
+                        .iter()
+                        .map(|(lang, name)| (lang.as_str(), name))
+                        .collect(),
+                )
+            })
+            .collect();
+        blake3::hash(&(exits, city_names).stdcode())
+    }
+
+    /// Applies a delta in place, checking that both the starting and resulting versions match the delta.
+    pub fn apply(&mut self, delta: &ExitListDelta) -> Result<(), DeltaError> {
+        if self.version() != delta.base_version {
+            return Err(DeltaError::WrongBase);
+        }
+        let mut exits: HashMap<[u8; 32], (VerifyingKey, ExitDescriptor)> = self
+            .all_exits
+            .drain(..)
+            .map(|(pk, exit)| (pk.to_bytes(), (pk, exit)))
+            .collect();
+        for pk in delta.removed.iter() {
+            exits.remove(pk.as_bytes());
+        }
+        for (pk, exit) in delta.changed.iter() {
+            exits.insert(pk.to_bytes(), (*pk, exit.clone()));
+        }
+        self.all_exits = exits.into_values().collect();
+        self.all_exits.sort_unstable_by_key(|(pk, _)| pk.to_bytes());
+        if self.version() != delta.version {
+            return Err(DeltaError::WrongResult);
+        }
+        Ok(())
+    }
+}
+
+impl ExitListDelta {
+    /// Computes the delta that turns one list into another, or `None` if the lists differ in ways that deltas cannot express.
+    pub fn between(old: &ExitList, new: &ExitList) -> Option<Self> {
+        if old.city_names != new.city_names {
+            return None;
+        }
+        let old_exits: HashMap<[u8; 32], &ExitDescriptor> = old
+            .all_exits
+            .iter()
+            .map(|(pk, exit)| (pk.to_bytes(), exit))
+            .collect();
+        let new_keys: Vec<[u8; 32]> = new.all_exits.iter().map(|(pk, _)| pk.to_bytes()).collect();
+        let changed = new
+            .all_exits
+            .iter()
+            .filter(|(pk, exit)| old_exits.get(pk.as_bytes()) != Some(&exit))
+            .cloned()
+            .collect();
+        let removed = old
+            .all_exits
+            .iter()
+            .filter(|(pk, _)| !new_keys.contains(pk.as_bytes()))
+            .map(|(pk, _)| *pk)
+            .collect();
+        Some(Self {
+            base_version: old.version(),
+            version: new.version(),
+            changed,
+            removed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+    use isocountry::CountryCode;
+
+    use super::*;
+
+    fn exit(seed: u8, load: f32) -> (VerifyingKey, ExitDescriptor) {
+        (
+            SigningKey::from_bytes(&[seed; 32]).verifying_key(),
+            ExitDescriptor {
+                c2e_listen: format!("1.2.3.{seed}:1").parse().unwrap(),
+                b2e_listen: format!("1.2.3.{seed}:2").parse().unwrap(),
+                country: CountryCode::CAN,
+                city: "Montreal".into(),
+                load,
+                expiry: 100,
+                metadata: BTreeMap::new(),
+            },
+        )
+    }
+
+    fn list(exits: Vec<(VerifyingKey, ExitDescriptor)>) -> ExitList {
+        ExitList {
+            all_exits: exits,
+            city_names: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_delta_round_trip() {
+        let old = list(vec![exit(1, 0.1), exit(2, 0.2), exit(3, 0.3)]);
+        let new = list(vec![exit(4, 0.4), exit(2, 0.5), exit(1, 0.1)]);
+
+        let delta = ExitListDelta::between(&old, &new).unwrap();
+        assert_eq!(delta.changed.len(), 2);
+        assert_eq!(delta.removed.len(), 1);
+
+        let mut applied = old.clone();
+        applied.apply(&delta).unwrap();
+        assert_eq!(applied.version(), new.version());
+
+        // applying twice fails, since the base no longer matches
+        assert!(matches!(applied.apply(&delta), Err(DeltaError::WrongBase)));
+    }
+
+    #[test]
+    fn test_version_ignores_order() {
+        let a = list(vec![exit(1, 0.1), exit(2, 0.2)]);
+        let b = list(vec![exit(2, 0.2), exit(1, 0.1)]);
+        assert_eq!(a.version(), b.version());
+        assert_ne!(a.version(), list(vec![exit(1, 0.1)]).version());
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fmt::Display, net::SocketAddr};
+use std::{collections::BTreeMap, fmt::Display, net::SocketAddr};
 
 use async_trait::async_trait;
 use bytes::Bytes;
@@ -8,6 +8,8 @@ mod route;
 pub use route::*;
 mod exit;
 pub use exit::*;
+mod exit_update;
+pub use exit_update::*;
 mod signed;
 use serde::{Deserialize, Serialize};
 pub use signed::*;
@@ -16,12 +18,20 @@ pub mod puzzle;
 pub use mac::*;
 mod bridge;
 pub use bridge::*;
+mod capabilities;
+pub use capabilities::*;
+mod device;
+pub use device::*;
 use thiserror::Error;
 
 #[nanorpc_derive]
 #[async_trait]
 pub trait BrokerProtocol {
+    /// Exchanges capabilities with the broker: the caller advertises what it supports, and the broker answers with what it supports. Peers that never call this are assumed to have [Capabilities::legacy].
+    async fn get_capabilities(&self, peer: Capabilities) -> Capabilities;
     async fn get_mizaru_subkey(&self, level: AccountLevel, epoch: u16) -> Bytes;
+    /// Gets the current mizaru public key of every tier, by tier name.
+    async fn get_mizaru_public_keys(&self) -> BTreeMap<String, mizaru2::PublicKey>;
     async fn get_auth_token(&self, credential: Credential) -> Result<String, AuthError>;
     async fn get_user_info(&self, auth_token: String) -> Result<Option<UserInfo>, AuthError>;
     async fn get_user_info_by_cred(
@@ -38,12 +48,35 @@ pub trait BrokerProtocol {
 
     async fn get_exits(&self) -> Result<Signed<ExitList>, GenericError>;
     async fn get_free_exits(&self) -> Result<Signed<ExitList>, GenericError>;
+    /// Gets the exits that users of the given tier may use.
+    async fn get_tier_exits(&self, level: AccountLevel) -> Result<Signed<ExitList>, GenericError>;
+    /// Gets the exits that users of the given level may use, including exit metadata if the caller's capabilities allow for it. The other exit-listing methods always leave out the metadata, since older clients would fail to verify it.
+    async fn get_exits_v2(
+        &self,
+        level: AccountLevel,
+        caps: Capabilities,
+    ) -> Result<Signed<ExitList>, GenericError>;
+    /// Gets what changed in the exit list of the given level since the given version, signed under [DOMAIN_EXIT_LIST_UPDATE] together with the current time. A client without a list passes `None` to get the whole list.
+    async fn get_exits_since(
+        &self,
+        level: AccountLevel,
+        version: Option<blake3::Hash>,
+        caps: Capabilities,
+    ) -> Result<Signed<TimestampedExitListUpdate>, GenericError>;
     async fn get_routes(
         &self,
         token: ClientToken,
         sig: UnblindedSignature,
         exit_b2e: SocketAddr,
     ) -> Result<RouteDescriptor, GenericError>;
+    /// Like `get_routes`, but only returns routes that a caller with the given capabilities understands, and signs them along with the token and exit they are for.
+    async fn get_routes_v2(
+        &self,
+        token: ClientToken,
+        sig: UnblindedSignature,
+        exit_b2e: SocketAddr,
+        caps: Capabilities,
+    ) -> Result<Signed<RouteBundle>, GenericError>;
 
     async fn insert_exit(
         &self,
@@ -66,6 +99,32 @@ pub trait BrokerProtocol {
         solution: String,
     ) -> Result<String, GenericError>;
     async fn upgrade_to_secret(&self, cred: Credential) -> Result<String, AuthError>;
+    /// Replaces the account's secret with a new one, which is returned. The old secret stops working, and so do all existing auth tokens.
+    async fn regenerate_secret(&self, cred: Credential) -> Result<String, AuthError>;
+    /// Deletes the account along with all its data.
+    async fn delete_account(&self, cred: Credential) -> Result<(), AuthError>;
+    /// Gets the UNIX timestamps of the account's most recent logins, newest first.
+    async fn get_login_history(&self, cred: Credential) -> Result<Vec<u64>, AuthError>;
+
+    /// Gets a fresh challenge for a device to sign, either to log in with [Credential::Device] or to enroll. Challenges expire after a few minutes and can be used only once.
+    async fn get_device_challenge(&self) -> String;
+    /// Enrolls the device that made the proof to the account of the auth token, under the given name.
+    async fn enroll_device(
+        &self,
+        auth_token: String,
+        name: String,
+        proof: DeviceProof,
+    ) -> Result<(), AuthError>;
+    /// Lists the devices enrolled to the account of the auth token.
+    async fn list_devices(&self, auth_token: String) -> Result<Vec<DeviceInfo>, AuthError>;
+    /// Revokes a device of the account of the auth token, so that it can no longer log in with its device key.
+    ///
+    /// Auth tokens are not tied to the device that got them, so the ones the revoked device already got stay valid, and the device stays logged in until it asks for a new token. A device that still has the account's credentials can also log in with them again.
+    async fn revoke_device(
+        &self,
+        auth_token: String,
+        public_key: ed25519_dalek::VerifyingKey,
+    ) -> Result<(), AuthError>;
 
     async fn get_news(&self, lang: String) -> Result<Vec<NewsItem>, GenericError>;
 
@@ -87,16 +146,41 @@ pub struct AvailabilityData {
     pub success: bool,
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct UserInfo {
     pub user_id: u64,
     pub plus_expires_unix: Option<u64>,
+    /// The name of the custom tier the user belongs to, if any.
+    #[serde(default)]
+    pub tier: Option<String>,
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+/// The account tier of a user. Free and Plus are built in; any other tier is defined by name in the broker and exit configs. Free and Plus keep their original encodings, so older peers understand them.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum AccountLevel {
     Free,
     Plus,
+    Tier(String),
+}
+
+impl AccountLevel {
+    /// Gets the level with the given tier name, which is "free" or "plus" for the built-in levels.
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "free" => Self::Free,
+            "plus" => Self::Plus,
+            other => Self::Tier(other.to_string()),
+        }
+    }
+
+    /// The tier name of the level.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Free => "free",
+            Self::Plus => "plus",
+            Self::Tier(name) => name,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Error, Serialize, Deserialize)]
@@ -114,8 +198,13 @@ pub enum AuthError {
 #[serde(rename_all = "snake_case")]
 pub enum Credential {
     TestDummy,
-    LegacyUsernamePassword { username: String, password: String },
+    LegacyUsernamePassword {
+        username: String,
+        password: String,
+    },
     Secret(String),
+    /// A device key enrolled to the account, proven by signing a challenge from [BrokerProtocol::get_device_challenge].
+    Device(Box<DeviceProof>),
 }
 
 impl Default for Credential {
@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 use stdcode::StdcodeSerializeExt;
 use thiserror::Error;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Mac<T> {
     pub inner: T,
 
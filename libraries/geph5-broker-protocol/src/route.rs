@@ -1,6 +1,18 @@
-use std::net::SocketAddr;
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use mizaru2::ClientToken;
 use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
+use thiserror::Error;
+
+pub const DOMAIN_ROUTE_BUNDLE: &str = "route-bundle";
+
+/// The deepest that a route may be nested.
+pub const MAX_ROUTE_DEPTH: usize = 32;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "snake_case")]
@@ -33,3 +45,312 @@ pub enum RouteDescriptor {
     #[serde(untagged)]
     Other(serde_json::Value),
 }
+
+/// Routes handed out by the broker, bound to the connect token and exit they were issued for. The broker signs them under [DOMAIN_ROUTE_BUNDLE], so that whoever relays broker responses cannot inject bridges.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RouteBundle {
+    pub route: RouteDescriptor,
+    /// The hash of the connect token the routes were issued for, as computed by [RouteBundle::token_hash].
+    pub token_hash: blake3::Hash,
+    /// The bridge-to-exit address that the routes lead to.
+    pub exit_b2e: SocketAddr,
+    /// The UNIX timestamp after which the routes must not be used.
+    pub expiry: u64,
+}
+
+impl RouteBundle {
+    /// Hashes a connect token, to bind routes to it.
+    pub fn token_hash(token: ClientToken) -> blake3::Hash {
+        blake3::hash(&token.stdcode())
+    }
+
+    /// Checks that the bundle was issued for the given token and exit, and has not expired, returning the route inside.
+    pub fn check(
+        self,
+        token: ClientToken,
+        exit_b2e: SocketAddr,
+    ) -> Result<RouteDescriptor, RouteBundleError> {
+        if self.token_hash != Self::token_hash(token) {
+            return Err(RouteBundleError::WrongToken);
+        }
+        if self.exit_b2e != exit_b2e {
+            return Err(RouteBundleError::WrongExit);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if self.expiry < now {
+            return Err(RouteBundleError::Expired);
+        }
+        Ok(self.route)
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum RouteBundleError {
+    #[error("routes issued for a different connect token")]
+    WrongToken,
+    #[error("routes issued for a different exit")]
+    WrongExit,
+    #[error("routes expired")]
+    Expired,
+}
+
+/// Why a route is unusable.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum RouteError {
+    #[error("route nested deeper than {MAX_ROUTE_DEPTH} levels")]
+    TooDeep,
+    #[error("empty race or fallback")]
+    Empty,
+    #[error("unknown kind of route: {0}")]
+    Unknown(String),
+}
+
+impl RouteDescriptor {
+    /// Checks that the route is within the depth limit, has no empty races or fallbacks, and only uses kinds of routes that we understand.
+    pub fn validate(&self) -> Result<(), RouteError> {
+        self.validate_inner(1)
+    }
+
+    fn validate_inner(&self, depth: usize) -> Result<(), RouteError> {
+        if depth > MAX_ROUTE_DEPTH {
+            return Err(RouteError::TooDeep);
+        }
+        match self {
+            RouteDescriptor::Tcp(_) => Ok(()),
+            RouteDescriptor::Race(routes) | RouteDescriptor::Fallback(routes) => {
+                if routes.is_empty() {
+                    return Err(RouteError::Empty);
+                }
+                routes.iter().try_for_each(|r| r.validate_inner(depth + 1))
+            }
+            RouteDescriptor::Sosistab3 { lower, .. }
+            | RouteDescriptor::PlainTls { lower, .. }
+            | RouteDescriptor::Timeout { lower, .. }
+            | RouteDescriptor::Delay { lower, .. }
+            | RouteDescriptor::ConnTest { lower, .. } => lower.validate_inner(depth + 1),
+            RouteDescriptor::Other(value) => Err(RouteError::Unknown(value.to_string())),
+        }
+    }
+
+    /// Rewrites the route into an equivalent but simpler one: nested races and fallbacks are flattened, races and fallbacks of one route are unwrapped, empty ones are dropped from their parents, and nested delays and timeouts are merged.
+    pub fn simplify(self) -> RouteDescriptor {
+        match self {
+            RouteDescriptor::Race(routes) => {
+                let mut flat = vec![];
+                for route in routes.into_iter().map(|r| r.simplify()) {
+                    match route {
+                        RouteDescriptor::Race(inner) => flat.extend(inner),
+                        other => flat.push(other),
+                    }
+                }
+                if flat.len() == 1 {
+                    flat.pop().unwrap()
+                } else {
+                    RouteDescriptor::Race(flat)
+                }
+            }
+            RouteDescriptor::Fallback(routes) => {
+                let mut flat = vec![];
+                for route in routes.into_iter().map(|r| r.simplify()) {
+                    match route {
+                        RouteDescriptor::Fallback(inner) => flat.extend(inner),
+                        // an empty race fails immediately, so falling back past it changes nothing
+                        RouteDescriptor::Race(inner) if inner.is_empty() => {}
+                        other => flat.push(other),
+                    }
+                }
+                if flat.len() == 1 {
+                    flat.pop().unwrap()
+                } else {
+                    RouteDescriptor::Fallback(flat)
+                }
+            }
+            RouteDescriptor::Delay {
+                milliseconds,
+                lower,
+            } => match lower.simplify() {
+                lower if milliseconds == 0 => lower,
+                RouteDescriptor::Delay {
+                    milliseconds: inner,
+                    lower,
+                } => RouteDescriptor::Delay {
+                    milliseconds: milliseconds.saturating_add(inner),
+                    lower,
+                },
+                lower => RouteDescriptor::Delay {
+                    milliseconds,
+                    lower: lower.into(),
+                },
+            },
+            RouteDescriptor::Timeout {
+                milliseconds,
+                lower,
+            } => match lower.simplify() {
+                RouteDescriptor::Timeout {
+                    milliseconds: inner,
+                    lower,
+                } => RouteDescriptor::Timeout {
+                    milliseconds: milliseconds.min(inner),
+                    lower,
+                },
+                lower => RouteDescriptor::Timeout {
+                    milliseconds,
+                    lower: lower.into(),
+                },
+            },
+            RouteDescriptor::Sosistab3 { cookie, lower } => RouteDescriptor::Sosistab3 {
+                cookie,
+                lower: lower.simplify().into(),
+            },
+            RouteDescriptor::PlainTls { sni_domain, lower } => RouteDescriptor::PlainTls {
+                sni_domain,
+                lower: lower.simplify().into(),
+            },
+            RouteDescriptor::ConnTest { ping_count, lower } => RouteDescriptor::ConnTest {
+                ping_count,
+                lower: lower.simplify().into(),
+            },
+            other => other,
+        }
+    }
+}
+
+/// A compact, single-line rendering of the route, for logs and diagnostics. Cookies are left out.
+impl Display for RouteDescriptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteDescriptor::Tcp(addr) => write!(f, "tcp({addr})"),
+            RouteDescriptor::Sosistab3 { lower, .. } => write!(f, "sosistab3({lower})"),
+            RouteDescriptor::PlainTls {
+                sni_domain: Some(sni),
+                lower,
+            } => write!(f, "tls[{sni}]({lower})"),
+            RouteDescriptor::PlainTls {
+                sni_domain: None,
+                lower,
+            } => write!(f, "tls({lower})"),
+            RouteDescriptor::Race(routes) => fmt_list(f, "race", routes),
+            RouteDescriptor::Fallback(routes) => fmt_list(f, "fallback", routes),
+            RouteDescriptor::Timeout {
+                milliseconds,
+                lower,
+            } => write!(f, "timeout[{milliseconds}ms]({lower})"),
+            RouteDescriptor::Delay {
+                milliseconds,
+                lower,
+            } => write!(f, "delay[{milliseconds}ms]({lower})"),
+            RouteDescriptor::ConnTest { ping_count, lower } => {
+                write!(f, "conntest[{ping_count}]({lower})")
+            }
+            RouteDescriptor::Other(value) => write!(f, "other({value})"),
+        }
+    }
+}
+
+fn fmt_list(
+    f: &mut std::fmt::Formatter<'_>,
+    name: &str,
+    routes: &[RouteDescriptor],
+) -> std::fmt::Result {
+    write!(f, "{name}(")?;
+    for (i, route) in routes.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{route}")?;
+    }
+    write!(f, ")")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp(port: u16) -> RouteDescriptor {
+        RouteDescriptor::Tcp(SocketAddr::from(([1, 2, 3, 4], port)))
+    }
+
+    fn delay(milliseconds: u32, lower: RouteDescriptor) -> RouteDescriptor {
+        RouteDescriptor::Delay {
+            milliseconds,
+            lower: lower.into(),
+        }
+    }
+
+    #[test]
+    fn test_route_bundle_check() {
+        let token = ClientToken::random();
+        let exit: SocketAddr = "5.6.7.8:9".parse().unwrap();
+        let bundle = RouteBundle {
+            route: tcp(1),
+            token_hash: RouteBundle::token_hash(token),
+            exit_b2e: exit,
+            expiry: u64::MAX,
+        };
+        assert!(bundle.clone().check(token, exit).is_ok());
+        assert_eq!(
+            bundle
+                .clone()
+                .check(ClientToken::random(), exit)
+                .unwrap_err(),
+            RouteBundleError::WrongToken
+        );
+        assert_eq!(
+            bundle
+                .clone()
+                .check(token, "5.6.7.8:10".parse().unwrap())
+                .unwrap_err(),
+            RouteBundleError::WrongExit
+        );
+        let expired = RouteBundle {
+            expiry: 0,
+            ..bundle
+        };
+        assert_eq!(
+            expired.check(token, exit).unwrap_err(),
+            RouteBundleError::Expired
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(tcp(1).validate(), Ok(()));
+        assert_eq!(
+            RouteDescriptor::Race(vec![tcp(1), RouteDescriptor::Fallback(vec![])]).validate(),
+            Err(RouteError::Empty)
+        );
+        assert!(matches!(
+            RouteDescriptor::Other(serde_json::json!({"future": 1})).validate(),
+            Err(RouteError::Unknown(_))
+        ));
+        let deep = (0..MAX_ROUTE_DEPTH).fold(tcp(1), |route, _| delay(1, route));
+        assert_eq!(deep.validate(), Err(RouteError::TooDeep));
+    }
+
+    #[test]
+    fn test_simplify_and_display() {
+        let route = delay(
+            100,
+            RouteDescriptor::Fallback(vec![
+                RouteDescriptor::Race(vec![
+                    RouteDescriptor::Race(vec![tcp(1), delay(0, tcp(2))]),
+                    delay(500, delay(250, tcp(3))),
+                ]),
+                RouteDescriptor::Race(vec![]),
+            ]),
+        );
+        assert_eq!(
+            route.to_string(),
+            "delay[100ms](fallback(race(race(tcp(1.2.3.4:1), delay[0ms](tcp(1.2.3.4:2))), delay[500ms](delay[250ms](tcp(1.2.3.4:3)))), race()))"
+        );
+        let simple = route.simplify();
+        assert_eq!(
+            simple.to_string(),
+            "delay[100ms](race(tcp(1.2.3.4:1), tcp(1.2.3.4:2), delay[750ms](tcp(1.2.3.4:3))))"
+        );
+        assert_eq!(simple.validate(), Ok(()));
+    }
+}
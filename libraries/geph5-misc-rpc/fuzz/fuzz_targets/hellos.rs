@@ -0,0 +1,7 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    geph5_misc_rpc::fuzzing::deserialize_hellos(data);
+});
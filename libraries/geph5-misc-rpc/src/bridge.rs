@@ -1,8 +1,10 @@
 use std::{net::SocketAddr, time::SystemTime};
 
 use async_trait::async_trait;
+use geph5_broker_protocol::{Mac, MacError};
 use nanorpc::nanorpc_derive;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 /// The metadata object passed to the exit on every b2e link.
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, Hash, PartialEq)]
@@ -11,6 +13,35 @@ pub struct B2eMetadata {
     pub expiry: SystemTime,
 }
 
+/// The key that the broker authenticates b2e metadata with, derived from the token that the broker shares with exits.
+pub fn b2e_metadata_key(exit_token: &str) -> [u8; 32] {
+    blake3::derive_key("geph5 b2e metadata", exit_token.as_bytes())
+}
+
+impl B2eMetadata {
+    /// Authenticates the metadata, as the broker does before handing it to a bridge.
+    pub fn authenticate(self, exit_token: &str) -> Mac<Self> {
+        Mac::new(self, &b2e_metadata_key(exit_token))
+    }
+
+    /// Checks that authenticated metadata came from the broker and has not expired, returning what's inside.
+    pub fn verify(metadata: Mac<Self>, exit_token: &str) -> Result<Self, B2eMetadataError> {
+        let metadata = metadata.verify(&b2e_metadata_key(exit_token))?;
+        if metadata.expiry < SystemTime::now() {
+            return Err(B2eMetadataError::Expired);
+        }
+        Ok(metadata)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum B2eMetadataError {
+    #[error("b2e metadata has an invalid MAC")]
+    InvalidMac(#[from] MacError),
+    #[error("b2e metadata has expired")]
+    Expired,
+}
+
 /// Initialization information for an obfuscation session.
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, Hash, PartialEq)]
 pub enum ObfsProtocol {
@@ -19,11 +50,101 @@ pub enum ObfsProtocol {
     ConnTest(Box<Self>),
     PlainTls(Box<Self>),
     Sosistab3New(String, Box<Self>),
+    /// The inner protocol runs over the datagrams of a UDP forward rather than over a TCP connection. Exits turn the datagrams back into a byte stream without retransmitting anything, so this only suits paths that rarely lose datagrams.
+    Datagrams(Box<Self>),
+}
+
+/// The largest datagram that the exit sends back through a UDP forward, which keeps datagrams from being fragmented on common paths.
+pub const MAX_FORWARD_DATAGRAM: usize = 1200;
+
+/// Which kind of traffic a forward carries.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardKind {
+    Tcp,
+    Udp,
+}
+
+/// An active forward on a bridge, along with its usage so far.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ForwardInfo {
+    pub kind: ForwardKind,
+    /// The address that clients connect to.
+    pub listen: SocketAddr,
+    pub b2e_dest: SocketAddr,
+    pub metadata: B2eMetadata,
+    /// Connections currently open. For UDP, these are the sources that sent datagrams recently.
+    pub active_connections: u64,
+    pub total_connections: u64,
+    /// Bytes relayed in both directions.
+    pub byte_count: u64,
+}
+
+/// The overall state of a bridge.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BridgeHealth {
+    /// The one-minute load average divided by the number of CPUs, so that 1.0 means fully loaded.
+    pub load: f64,
+    pub listener_count: u64,
+    pub active_connections: u64,
+    /// Bytes relayed since the bridge started.
+    pub byte_count: u64,
 }
 
 /// The RPC protocol that bridges expose, called by the broker.
 #[nanorpc_derive]
 #[async_trait]
 pub trait BridgeControlProtocol {
-    async fn tcp_forward(&self, b2e_dest: SocketAddr, metadata: B2eMetadata) -> SocketAddr;
+    /// Listens for TCP connections, relaying each to the exit over a b2e stream that carries the given metadata. The metadata must be authenticated by the broker, or the exit rejects the link.
+    async fn tcp_forward(&self, b2e_dest: SocketAddr, metadata: Mac<B2eMetadata>) -> SocketAddr;
+
+    /// Like `tcp_forward`, but listens for UDP. Every source address gets its own b2e stream, which carries its datagrams each prefixed with a big-endian `u16` length. The metadata's protocol should contain [ObfsProtocol::Datagrams], so that the exit expects datagrams on the stream.
+    async fn udp_forward(&self, b2e_dest: SocketAddr, metadata: Mac<B2eMetadata>) -> SocketAddr;
+
+    /// Stops the forward listening on the given address, closing its connections. Returns whether there was such a forward.
+    async fn teardown_forward(&self, listen: SocketAddr) -> bool;
+
+    async fn list_forwards(&self) -> Vec<ForwardInfo>;
+
+    async fn health(&self) -> BridgeHealth;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_b2e_metadata_auth() {
+        let metadata = |expiry| B2eMetadata {
+            protocol: ObfsProtocol::Sosistab3("cookie".into()),
+            expiry,
+        };
+        let fresh = metadata(SystemTime::now() + Duration::from_secs(60));
+        assert_eq!(
+            B2eMetadata::verify(fresh.clone().authenticate("token"), "token").unwrap(),
+            fresh
+        );
+
+        // a different token gives a different key
+        assert!(matches!(
+            B2eMetadata::verify(fresh.clone().authenticate("other"), "token"),
+            Err(B2eMetadataError::InvalidMac(_))
+        ));
+
+        // tampering breaks the MAC
+        let mut tampered = fresh.authenticate("token");
+        tampered.inner.protocol = ObfsProtocol::None;
+        assert!(matches!(
+            B2eMetadata::verify(tampered, "token"),
+            Err(B2eMetadataError::InvalidMac(_))
+        ));
+
+        let stale = metadata(SystemTime::now() - Duration::from_secs(1));
+        assert!(matches!(
+            B2eMetadata::verify(stale.authenticate("token"), "token"),
+            Err(B2eMetadataError::Expired)
+        ));
+    }
 }
@@ -1,4 +1,9 @@
-use std::pin::Pin;
+use std::{
+    io::ErrorKind,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::Poll,
+};
 
 use anyhow::Context;
 
@@ -7,6 +12,10 @@ use bipe::{BipeReader, BipeWriter};
 use bytes::Bytes;
 use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit};
 use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use ml_kem::{
+    kem::{Decapsulate, Encapsulate},
+    Ciphertext, Encoded, EncodedSizeUser, KemCore, MlKem768,
+};
 use pin_project::pin_project;
 use serde::{Deserialize, Serialize};
 use sillad::Pipe;
@@ -34,8 +43,18 @@ pub enum ClientCryptHello {
     SharedSecretChallenge([u8; 32]),
     /// An X25519 public key to be used to add a layer of encryption
     X25519(x25519_dalek::PublicKey),
+    /// Like X25519, but the encryption layer periodically rekeys. Only sent to exits that list [CRYPT_HELLO_X25519_REKEYING] in their metadata.
+    X25519Rekeying(x25519_dalek::PublicKey),
+    /// An X25519 public key along with an ML-KEM-768 encapsulation key, for a hybrid key exchange that stays secure as long as either one holds up. The encryption layer rekeys like with [ClientCryptHello::X25519Rekeying]. Only sent to exits that list [CRYPT_HELLO_X25519_MLKEM768] in their metadata.
+    X25519MlKem768(x25519_dalek::PublicKey, Bytes),
 }
 
+/// The name under which exits advertise support for [ClientCryptHello::X25519Rekeying].
+pub const CRYPT_HELLO_X25519_REKEYING: &str = "x25519_rekeying";
+
+/// The name under which exits advertise support for [ClientCryptHello::X25519MlKem768].
+pub const CRYPT_HELLO_X25519_MLKEM768: &str = "x25519_mlkem768";
+
 /// ExitHello represents the response of the exit node to the initial
 /// hello message from the client. It includes a signature to verify the
 /// authenticity of the response.
@@ -58,8 +77,91 @@ pub enum ExitHelloInner {
     SharedSecretResponse(blake3::Hash),
     /// An X25519 public key to be used in the key exchange process
     X25519(x25519_dalek::PublicKey),
+    /// The answer to [ClientCryptHello::X25519MlKem768]: an X25519 public key and an ML-KEM-768 ciphertext
+    X25519MlKem768(x25519_dalek::PublicKey, Bytes),
+}
+
+/// The client's secrets for a hybrid X25519 + ML-KEM-768 key exchange.
+pub struct HybridClientSecret {
+    x25519: x25519_dalek::EphemeralSecret,
+    mlkem: <MlKem768 as KemCore>::DecapsulationKey,
+}
+
+impl HybridClientSecret {
+    /// Generates fresh secrets, returning them along with the hello that starts the key exchange.
+    pub fn generate() -> (Self, ClientCryptHello) {
+        let mut rng = rand::thread_rng();
+        let x25519 = x25519_dalek::EphemeralSecret::random_from_rng(&mut rng);
+        let (mlkem, encapsulation_key) = MlKem768::generate(&mut rng);
+        let hello = ClientCryptHello::X25519MlKem768(
+            (&x25519).into(),
+            Bytes::copy_from_slice(&encapsulation_key.as_bytes()),
+        );
+        (Self { x25519, mlkem }, hello)
+    }
+
+    /// Finishes the key exchange with the exit's answer, returning the shared secret.
+    pub fn finish(
+        self,
+        exit_epk: &x25519_dalek::PublicKey,
+        ciphertext: &[u8],
+    ) -> anyhow::Result<[u8; 32]> {
+        let ciphertext = Ciphertext::<MlKem768>::try_from(ciphertext)
+            .ok()
+            .context("bad ML-KEM ciphertext")?;
+        let mlkem_ss = self.mlkem.decapsulate(&ciphertext).unwrap();
+        let x25519_ss = self.x25519.diffie_hellman(exit_epk);
+        Ok(hybrid_shared_secret(x25519_ss.as_bytes(), &mlkem_ss))
+    }
+}
+
+/// Answers a hybrid key exchange on the exit's side, returning the shared secret along with the answer to send.
+pub fn hybrid_exit_respond(
+    client_epk: &x25519_dalek::PublicKey,
+    encapsulation_key: &[u8],
+) -> anyhow::Result<([u8; 32], ExitHelloInner)> {
+    let mut rng = rand::thread_rng();
+    let encapsulation_key =
+        Encoded::<<MlKem768 as KemCore>::EncapsulationKey>::try_from(encapsulation_key)
+            .ok()
+            .context("bad ML-KEM encapsulation key")?;
+    let (ciphertext, mlkem_ss) =
+        <MlKem768 as KemCore>::EncapsulationKey::from_bytes(&encapsulation_key)
+            .encapsulate(&mut rng)
+            .unwrap();
+    let x25519 = x25519_dalek::EphemeralSecret::random_from_rng(&mut rng);
+    let exit_epk = (&x25519).into();
+    let x25519_ss = x25519.diffie_hellman(client_epk);
+    Ok((
+        hybrid_shared_secret(x25519_ss.as_bytes(), &mlkem_ss),
+        ExitHelloInner::X25519MlKem768(exit_epk, Bytes::copy_from_slice(&ciphertext)),
+    ))
+}
+
+fn hybrid_shared_secret(x25519_ss: &[u8], mlkem_ss: &[u8]) -> [u8; 32] {
+    blake3::derive_key("geph5 x25519-mlkem768", &[x25519_ss, mlkem_ss].concat())
+}
+
+/// The first byte a client sends over an authenticated pipe to ask for a resumable session, rather than starting picomux directly. Picomux frames never start with this byte.
+pub const SESSION_RESUME_MAGIC: u8 = 0xff;
+
+/// SessionResumeRequest follows [SESSION_RESUME_MAGIC], asking the exit either for a brand new resumable session, or to move an existing one onto this pipe.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum SessionResumeRequest {
+    New,
+    Resume([u8; 32]),
+}
+
+/// SessionResumeResponse tells the client which session the pipe now carries. If the requested session is gone, the exit starts a new one with a different token.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionResumeResponse {
+    pub token: [u8; 32],
+    pub resumed: bool,
 }
 
+/// How many messages each direction of a rekeying [ClientExitCryptPipe] encrypts under one key before moving on to the next.
+pub const REKEY_INTERVAL: u64 = 1 << 16;
+
 /// ClientExitCryptPipe is a sillad::Pipe implementation representing an end-to-end encrypted connection between the client and the exit.
 #[pin_project]
 pub struct ClientExitCryptPipe {
@@ -70,6 +172,10 @@ pub struct ClientExitCryptPipe {
     write_outgoing: BipeWriter,
     _write_task: Task<()>,
 
+    /// The error that killed one of the background tasks, if any.
+    error: Arc<Mutex<Option<(ErrorKind, String)>>>,
+    shared_secret: [u8; 32],
+    protocol: &'static str,
     addr: Option<String>,
 }
 
@@ -78,8 +184,16 @@ impl AsyncRead for ClientExitCryptPipe {
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &mut [u8],
-    ) -> std::task::Poll<std::io::Result<usize>> {
-        self.project().read_incoming.poll_read(cx, buf)
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        match this.read_incoming.poll_read(cx, buf) {
+            // the read task ends the stream both when the pipe closes and when it fails, so check which one it was
+            Poll::Ready(Ok(0)) if !buf.is_empty() => match stored_error(this.error) {
+                Some(err) => Poll::Ready(Err(err)),
+                None => Poll::Ready(Ok(0)),
+            },
+            other => other,
+        }
     }
 }
 
@@ -88,68 +202,135 @@ impl AsyncWrite for ClientExitCryptPipe {
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
-    ) -> std::task::Poll<std::io::Result<usize>> {
-        self.project().write_outgoing.poll_write(cx, buf)
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        this.write_outgoing
+            .poll_write(cx, buf)
+            .map_err(|err| stored_error(this.error).unwrap_or(err))
     }
 
     fn poll_flush(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        self.project().write_outgoing.poll_flush(cx)
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        this.write_outgoing
+            .poll_flush(cx)
+            .map_err(|err| stored_error(this.error).unwrap_or(err))
     }
 
     fn poll_close(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
+    ) -> Poll<std::io::Result<()>> {
         self.project().write_outgoing.poll_close(cx)
     }
 }
 
+fn stored_error(error: &Mutex<Option<(ErrorKind, String)>>) -> Option<std::io::Error> {
+    error
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|(kind, msg)| std::io::Error::new(*kind, msg.clone()))
+}
+
 impl ClientExitCryptPipe {
     /// Creates a new pipe, given read and write keys
     pub fn new(pipe: impl Pipe, read_key: [u8; 32], write_key: [u8; 32]) -> Self {
+        Self::new_inner(pipe, read_key, write_key, None)
+    }
+
+    /// Creates a new pipe that moves on to a fresh key every [REKEY_INTERVAL] messages in each direction, so that compromising a key only exposes a bounded amount of traffic. Both ends must agree to rekey, which they do through [ClientCryptHello::X25519Rekeying].
+    pub fn new_rekeying(pipe: impl Pipe, read_key: [u8; 32], write_key: [u8; 32]) -> Self {
+        Self::new_inner(pipe, read_key, write_key, Some(REKEY_INTERVAL))
+    }
+
+    fn new_inner(
+        pipe: impl Pipe,
+        read_key: [u8; 32],
+        write_key: [u8; 32],
+        rekey_interval: Option<u64>,
+    ) -> Self {
         let addr = pipe.remote_addr().map(|s| s.to_string());
+        let shared_secret = shared_secret(&read_key, &write_key);
         let (mut pipe_read, mut pipe_write) = pipe.split();
         let (mut write_incoming, read_incoming) = bipe::bipe(32768);
         let (write_outgoing, mut read_outgoing) = bipe::bipe(32768);
+        let error: Arc<Mutex<Option<(ErrorKind, String)>>> = Default::default();
+        let is_rekey_time = move |nonce: u64| {
+            rekey_interval.is_some_and(|interval| nonce > 0 && nonce.is_multiple_of(interval))
+        };
 
-        let _read_task = smolscale::spawn(async move {
-            let read_aead = ChaCha20Poly1305::new_from_slice(&read_key).unwrap();
-            let fallible = async {
-                for read_nonce in 0u64.. {
-                    let msg = read_prepend_length(&mut pipe_read).await?;
-                    let read_nonce = [0; 12]
-                        .tap_mut(|nonce| nonce[..8].copy_from_slice(&read_nonce.to_le_bytes()));
-                    let plaintext = read_aead
-                        .decrypt(&read_nonce.into(), msg.as_slice())
-                        .ok()
-                        .context("cannot decrypt")?;
-                    write_incoming.write_all(&plaintext).await?;
+        let _read_task = smolscale::spawn({
+            let error = error.clone();
+            async move {
+                let fallible = async {
+                    let mut read_key = read_key;
+                    let mut read_aead = ChaCha20Poly1305::new_from_slice(&read_key).unwrap();
+                    for read_nonce in 0u64.. {
+                        if is_rekey_time(read_nonce) {
+                            read_key = next_key(&read_key);
+                            read_aead = ChaCha20Poly1305::new_from_slice(&read_key).unwrap();
+                        }
+                        // the other side going away between frames is the normal way for the pipe to end, but a partial frame is an error
+                        let mut first_byte = [0u8; 1];
+                        if pipe_read.read(&mut first_byte).await? == 0 {
+                            break;
+                        }
+                        let msg = read_prepend_length(
+                            futures_util::io::Cursor::new(first_byte).chain(&mut pipe_read),
+                        )
+                        .await?;
+                        let nonce = [0; 12]
+                            .tap_mut(|nonce| nonce[..8].copy_from_slice(&read_nonce.to_le_bytes()));
+                        let plaintext =
+                            read_aead
+                                .decrypt(&nonce.into(), msg.as_slice())
+                                .map_err(|_| {
+                                    std::io::Error::new(
+                                        ErrorKind::InvalidData,
+                                        format!("cannot decrypt message {read_nonce}"),
+                                    )
+                                })?;
+                        write_incoming.write_all(&plaintext).await?;
+                    }
+                    std::io::Result::Ok(())
+                };
+                if let Err(err) = fallible.await {
+                    *error.lock().unwrap() = Some((err.kind(), err.to_string()));
                 }
-                anyhow::Ok(())
-            };
-            if let Err(_err) = fallible.await {
-                // todo handle error
             }
         });
 
-        let _write_task = smolscale::spawn(async move {
-            let fallible = async {
-                let write_aead = ChaCha20Poly1305::new_from_slice(&write_key).unwrap();
-                let mut buf = [0; 8192];
-                for write_nonce in 0u64.. {
-                    let write_nonce = [0; 12]
-                        .tap_mut(|nonce| nonce[..8].copy_from_slice(&write_nonce.to_le_bytes()));
-                    let n = read_outgoing.read(&mut buf).await?;
-                    let ciphertext = write_aead.encrypt(&write_nonce.into(), &buf[..n]).unwrap();
-                    write_prepend_length(&ciphertext, &mut pipe_write).await?;
+        let _write_task = smolscale::spawn({
+            let error = error.clone();
+            async move {
+                let fallible = async {
+                    let mut write_key = write_key;
+                    let mut write_aead = ChaCha20Poly1305::new_from_slice(&write_key).unwrap();
+                    let mut buf = [0; 8192];
+                    for write_nonce in 0u64.. {
+                        if is_rekey_time(write_nonce) {
+                            write_key = next_key(&write_key);
+                            write_aead = ChaCha20Poly1305::new_from_slice(&write_key).unwrap();
+                        }
+                        let n = read_outgoing.read(&mut buf).await?;
+                        if n == 0 {
+                            pipe_write.close().await?;
+                            break;
+                        }
+                        let nonce = [0; 12].tap_mut(|nonce| {
+                            nonce[..8].copy_from_slice(&write_nonce.to_le_bytes())
+                        });
+                        let ciphertext = write_aead.encrypt(&nonce.into(), &buf[..n]).unwrap();
+                        write_prepend_length(&ciphertext, &mut pipe_write).await?;
+                    }
+                    std::io::Result::Ok(())
+                };
+                if let Err(err) = fallible.await {
+                    *error.lock().unwrap() = Some((err.kind(), err.to_string()));
                 }
-                anyhow::Ok(())
-            };
-            if let Err(_err) = fallible.await {
-                // todo handle error
             }
         });
         Self {
@@ -158,17 +339,151 @@ impl ClientExitCryptPipe {
             write_outgoing,
             _write_task,
 
+            error,
+            shared_secret,
+            protocol: if rekey_interval.is_some() {
+                "c2e-chacha20poly1305-rekey"
+            } else {
+                "c2e-chacha20poly1305"
+            },
             addr,
         }
     }
 }
 
+/// The key that follows the given one when rekeying. Old keys cannot be recovered from new ones.
+fn next_key(key: &[u8; 32]) -> [u8; 32] {
+    blake3::derive_key("geph5 c2e rekey", key)
+}
+
+/// A secret that both ends derive from their pair of keys, whichever way around they hold them.
+fn shared_secret(read_key: &[u8; 32], write_key: &[u8; 32]) -> [u8; 32] {
+    let (first, second) = if read_key < write_key {
+        (read_key, write_key)
+    } else {
+        (write_key, read_key)
+    };
+    blake3::derive_key(
+        "geph5 c2e shared secret",
+        &[first.as_slice(), second.as_slice()].concat(),
+    )
+}
+
 impl Pipe for ClientExitCryptPipe {
+    fn shared_secret(&self) -> Option<&[u8]> {
+        Some(&self.shared_secret)
+    }
+
     fn protocol(&self) -> &str {
-        "plain"
+        self.protocol
     }
 
     fn remote_addr(&self) -> Option<&str> {
         self.addr.as_deref()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use sillad::{
+        dialer::Dialer as _,
+        listener::Listener as _,
+        tcp::{TcpDialer, TcpListener},
+    };
+
+    use super::*;
+
+    async fn pipe_pair(
+        client_rekey: Option<u64>,
+        exit_rekey: Option<u64>,
+    ) -> (ClientExitCryptPipe, ClientExitCryptPipe) {
+        let mut listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let dialer = TcpDialer {
+            dest_addr: listener.local_addr().await,
+        };
+        let (client, exit) = futures_util::future::join(dialer.dial(), listener.accept()).await;
+        let (c2e, e2c) = ([1; 32], [2; 32]);
+        (
+            ClientExitCryptPipe::new_inner(client.unwrap(), e2c, c2e, client_rekey),
+            ClientExitCryptPipe::new_inner(exit.unwrap(), c2e, e2c, exit_rekey),
+        )
+    }
+
+    #[test]
+    fn test_rekeying_round_trip() {
+        smolscale::block_on(async {
+            let (mut client, mut exit) = pipe_pair(Some(3), Some(3)).await;
+            assert_eq!(client.shared_secret(), exit.shared_secret());
+            for i in 0..10u8 {
+                client.write_all(&[i; 100]).await.unwrap();
+                let mut buf = [0; 100];
+                exit.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, [i; 100]);
+            }
+        });
+    }
+
+    #[test]
+    fn test_hybrid_key_exchange() {
+        let (client_secret, hello) = HybridClientSecret::generate();
+        let ClientCryptHello::X25519MlKem768(client_epk, encapsulation_key) = hello else {
+            panic!("wrong hello")
+        };
+        let (exit_ss, inner) = hybrid_exit_respond(&client_epk, &encapsulation_key).unwrap();
+        let ExitHelloInner::X25519MlKem768(exit_epk, ciphertext) = inner else {
+            panic!("wrong answer")
+        };
+        let client_ss = client_secret.finish(&exit_epk, &ciphertext).unwrap();
+        assert_eq!(client_ss, exit_ss);
+
+        // a truncated key is rejected rather than panicking
+        assert!(hybrid_exit_respond(&client_epk, &encapsulation_key[1..]).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_error_surfaces() {
+        smolscale::block_on(async {
+            // only the client rekeys, so its second message cannot be decrypted
+            let (mut client, mut exit) = pipe_pair(Some(1), None).await;
+            let mut buf = [0; 5];
+            client.write_all(b"hello").await.unwrap();
+            exit.read_exact(&mut buf).await.unwrap();
+            client.write_all(b"world").await.unwrap();
+            let err = exit.read_exact(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        });
+    }
+
+    #[test]
+    fn test_truncated_frame_surfaces() {
+        smolscale::block_on(async {
+            let mut listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dialer = TcpDialer {
+                dest_addr: listener.local_addr().await,
+            };
+            for truncated in [false, true] {
+                let (raw, exit) =
+                    futures_util::future::join(dialer.dial(), listener.accept()).await;
+                let mut exit =
+                    ClientExitCryptPipe::new_inner(exit.unwrap(), [1; 32], [2; 32], None);
+                let mut raw = raw.unwrap();
+                if truncated {
+                    raw.write_all(&100u32.to_be_bytes()).await.unwrap();
+                    raw.write_all(&[0; 10]).await.unwrap();
+                }
+                drop(raw);
+                let mut buf = vec![];
+                let result = exit.read_to_end(&mut buf).await;
+                if truncated {
+                    assert_eq!(result.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+                } else {
+                    assert_eq!(result.unwrap(), 0);
+                }
+            }
+        });
+    }
+}
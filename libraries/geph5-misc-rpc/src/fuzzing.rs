@@ -0,0 +1,20 @@
+//! Entry points for the fuzz targets under `fuzz/`. Not part of the public API.
+
+use futures_util::FutureExt;
+
+use crate::{
+    exit::{ClientHello, ExitHello, ExitHelloInner, SessionResumeRequest},
+    read_prepend_length,
+};
+
+/// Deserializes arbitrary bytes as every message exchanged before a client is authenticated, both bare and behind a length prefix.
+pub fn deserialize_hellos(data: &[u8]) {
+    let _ = stdcode::deserialize::<ClientHello>(data);
+    let _ = stdcode::deserialize::<ExitHello>(data);
+    let _ = stdcode::deserialize::<ExitHelloInner>(data);
+    let _ = stdcode::deserialize::<SessionResumeRequest>(data);
+    if let Some(Ok(value)) = read_prepend_length(data).now_or_never() {
+        let _ = stdcode::deserialize::<ClientHello>(&value);
+        let _ = stdcode::deserialize::<ExitHello>(&value);
+    }
+}
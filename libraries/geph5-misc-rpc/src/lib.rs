@@ -2,6 +2,12 @@ use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 pub mod bridge;
 pub mod exit;
+#[cfg(any(test, fuzzing))]
+#[doc(hidden)]
+pub mod fuzzing;
+
+/// The longest value that [read_prepend_length] accepts. Everything sent this way is a small handshake or metadata message, so anything longer means a corrupt or hostile peer.
+pub const MAX_PREPEND_LENGTH: usize = 1 << 20;
 
 /// A helper function to write a length-prepended value into an AsyncWrite.
 pub async fn write_prepend_length<W: AsyncWrite + Unpin>(
@@ -21,9 +27,44 @@ pub async fn read_prepend_length<R: AsyncRead + Unpin>(mut input: R) -> std::io:
     let mut len_buf = [0u8; 4];
     input.read_exact(&mut len_buf).await?;
     let len = u32::from_be_bytes(len_buf) as usize;
+    if len > MAX_PREPEND_LENGTH {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("length-prepended value of {len} bytes is too long"),
+        ));
+    }
 
     let mut value = vec![0u8; len];
     input.read_exact(&mut value).await?;
 
     Ok(value)
 }
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    #[test]
+    fn prepend_length_rejects_huge_values() {
+        let mut input = u32::MAX.to_be_bytes().to_vec();
+        input.extend_from_slice(b"hello");
+        let res = read_prepend_length(&input[..]).now_or_never().unwrap();
+        assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn hellos_survive_garbage() {
+        for i in 0..10000u32 {
+            let garbage: Vec<u8> = blake3::hash(&i.to_be_bytes())
+                .as_bytes()
+                .iter()
+                .cycle()
+                .take(i as usize % 200)
+                .copied()
+                .collect();
+            fuzzing::deserialize_hellos(&garbage);
+        }
+    }
+}
@@ -1,10 +1,15 @@
+use anyhow::Context as _;
 use blind_rsa_signatures as brs;
 use brs::reexports::rsa::pkcs1::EncodeRsaPublicKey as _;
+use lru::LruCache;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::{
+    num::NonZeroUsize,
     sync::{
-        Arc,
+        Arc, Mutex,
         atomic::{AtomicUsize, Ordering},
     },
     time::SystemTime,
@@ -12,6 +17,10 @@ use std::{
 
 const KEY_COUNT: usize = 65536;
 const KEY_BITS: usize = 2048;
+const SUBKEY_CACHE_SIZE: usize = 16;
+
+/// Prefix of the serialized form of a seed-derived secret key. Legacy keys never start with it, since their serialization starts with the count of their 65536 RSA keys.
+const SEEDED_MAGIC: &[u8] = b"mizaru2-seeded\0";
 
 /// Obtains the current epoch.
 pub fn current_epoch() -> u16 {
@@ -22,13 +31,39 @@ pub fn current_epoch() -> u16 {
         / 86400) as u16
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+/// A Mizaru secret key, which holds one RSA key per epoch. The RSA keys are either all stored, or derived on demand from a master seed.
+#[derive(Clone)]
 pub struct SecretKey {
+    subkeys: Subkeys,
+    merkle_tree: Arc<Vec<Vec<blake3::Hash>>>,
+}
+
+#[derive(Clone)]
+enum Subkeys {
+    Stored(Arc<Vec<Vec<u8>>>),
+    Seeded {
+        seed: [u8; 32],
+        cache: Arc<Mutex<LruCache<u16, brs::SecretKey>>>,
+    },
+}
+
+/// The serialization of a secret key that stores every RSA key, which is what all keys looked like before seed derivation existed.
+#[derive(Serialize, Deserialize)]
+struct LegacySecretKey {
     rsa_keys_der: Arc<Vec<Vec<u8>>>,
     merkle_tree: Arc<Vec<Vec<blake3::Hash>>>,
 }
 
+/// The serialization of a seed-derived secret key. Only the bottom of the merkle tree is kept, since the rest is quick to recompute.
+#[derive(Serialize, Deserialize)]
+struct SeededSecretKey {
+    seed: [u8; 32],
+    merkle_leaves: Vec<blake3::Hash>,
+    merkle_root: blake3::Hash,
+}
+
 impl SecretKey {
+    /// Generates a secret key by eagerly creating and storing every RSA key. This takes a long time and produces a huge key; prefer [SecretKey::generate_seeded].
     pub fn generate(name: &str) -> Self {
         let count = AtomicUsize::new(1);
         let rsa_keys: Vec<brs::SecretKey> = (0..KEY_COUNT)
@@ -41,34 +76,107 @@ impl SecretKey {
                     .sk
             })
             .collect();
-        let merkle_tree_first: Vec<blake3::Hash> = rsa_keys
-            .iter()
-            .map(|v| blake3::hash(v.to_public_key().to_pkcs1_der().unwrap().as_bytes()))
-            .collect();
-        let mut merkle_tree = vec![merkle_tree_first];
-        while merkle_tree.last().unwrap().len() > 1 {
-            // "decimate" the merkle tree level to make the next
-            let last = merkle_tree.last().unwrap();
-            let new = (0..last.len() / 2)
-                .map(|i| blake3::keyed_hash(last[i * 2].as_bytes(), last[i * 2 + 1].as_bytes()))
-                .collect();
-            merkle_tree.push(new)
-        }
+        let merkle_tree_first: Vec<blake3::Hash> = rsa_keys.iter().map(subkey_hash).collect();
         Self {
-            rsa_keys_der: Arc::new(
+            subkeys: Subkeys::Stored(Arc::new(
                 rsa_keys
                     .into_iter()
                     .map(|key| key.to_der().unwrap())
                     .collect(),
-            ),
-            merkle_tree: Arc::new(merkle_tree),
+            )),
+            merkle_tree: Arc::new(build_merkle_tree(merkle_tree_first)),
         }
     }
 
-    /// Blind-signs a message with a given epoch key. The returned struct contains all information required to verify a specific key within the merkle root and an RSA-FDH blind signature using that specific key.
-    pub fn blind_sign(&self, epoch: u16, blinded_token: &BlindedClientToken) -> BlindedSignature {
+    /// Generates a secret key whose RSA keys are derived from a random master seed. Every RSA key still has to be derived once to compute the merkle root, but afterwards the key serializes compactly, loads instantly, and derives RSA keys only when they are used.
+    pub fn generate_seeded(name: &str) -> Self {
+        Self::from_seed(name, rand::random())
+    }
+
+    /// Generates the secret key belonging to the given master seed.
+    pub fn from_seed(name: &str, seed: [u8; 32]) -> Self {
+        Self::from_seed_with_count(name, seed, KEY_COUNT)
+    }
+
+    /// Generates a secret key belonging to the given master seed that covers only the first `key_count` epochs, which is quick enough for tests in other crates. Such keys are useless otherwise, so this is only available with the `testing` feature.
+    #[cfg(feature = "testing")]
+    pub fn truncated_for_testing(name: &str, seed: [u8; 32], key_count: usize) -> Self {
+        Self::from_seed_with_count(name, seed, key_count)
+    }
+
+    fn from_seed_with_count(name: &str, seed: [u8; 32], key_count: usize) -> Self {
+        let count = AtomicUsize::new(1);
+        let merkle_tree_first: Vec<blake3::Hash> = (0..key_count)
+            .into_par_iter()
+            .map(|epoch| {
+                let count = count.fetch_add(1, Ordering::Relaxed);
+                eprintln!("deriving {name} {count}/{key_count}");
+                subkey_hash(&derive_subkey(&seed, epoch as u16))
+            })
+            .collect();
+        Self {
+            subkeys: Subkeys::seeded(seed),
+            merkle_tree: Arc::new(build_merkle_tree(merkle_tree_first)),
+        }
+    }
+
+    /// Serializes the secret key. Legacy keys keep their old format, so that they can still be read by older versions.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match &self.subkeys {
+            Subkeys::Stored(rsa_keys_der) => stdcode::serialize(&LegacySecretKey {
+                rsa_keys_der: rsa_keys_der.clone(),
+                merkle_tree: self.merkle_tree.clone(),
+            })
+            .unwrap(),
+            Subkeys::Seeded { seed, .. } => {
+                let mut toret = SEEDED_MAGIC.to_vec();
+                toret.extend_from_slice(
+                    &stdcode::serialize(&SeededSecretKey {
+                        seed: *seed,
+                        merkle_leaves: self.merkle_tree[0].clone(),
+                        merkle_root: self.to_public_key().0,
+                    })
+                    .unwrap(),
+                );
+                toret
+            }
+        }
+    }
+
+    /// Deserializes a secret key, in either the seeded or the legacy format.
+    pub fn from_bytes(bts: &[u8]) -> anyhow::Result<Self> {
+        if let Some(bts) = bts.strip_prefix(SEEDED_MAGIC) {
+            let seeded: SeededSecretKey = stdcode::deserialize(bts)?;
+            let merkle_tree = build_merkle_tree(seeded.merkle_leaves);
+            if merkle_tree.last().unwrap()[0] != seeded.merkle_root {
+                anyhow::bail!("merkle tree of the seeded key does not match its root")
+            }
+            Ok(Self {
+                subkeys: Subkeys::seeded(seeded.seed),
+                merkle_tree: Arc::new(merkle_tree),
+            })
+        } else {
+            let legacy: LegacySecretKey = stdcode::deserialize(bts)?;
+            Ok(Self {
+                subkeys: Subkeys::Stored(legacy.rsa_keys_der),
+                merkle_tree: legacy.merkle_tree,
+            })
+        }
+    }
+
+    /// Whether the RSA keys are derived from a seed, rather than stored.
+    pub fn is_seeded(&self) -> bool {
+        matches!(self.subkeys, Subkeys::Seeded { .. })
+    }
+
+    /// Blind-signs a message with a given epoch key. The returned struct contains all information required to verify a specific key within the merkle root and an RSA-FDH blind signature using that specific key. Fails if the epoch key cannot be obtained.
+    pub fn blind_sign(
+        &self,
+        epoch: u16,
+        blinded_token: &BlindedClientToken,
+    ) -> anyhow::Result<BlindedSignature> {
         let mut rng = rand::thread_rng();
-        let key_to_use = self.get_subkey(epoch);
+        let key_to_use = self.get_subkey(epoch)?;
         let bare_sig = key_to_use
             .blind_sign(
                 &mut rng,
@@ -76,7 +184,7 @@ impl SecretKey {
                 &brs::Options::new(brs::Hash::Sha256, true, 32),
             )
             .expect("blind signature failed");
-        BlindedSignature {
+        Ok(BlindedSignature {
             epoch,
             used_key: key_to_use
                 .to_public_key()
@@ -85,7 +193,7 @@ impl SecretKey {
                 .into_vec(),
             merkle_branch: self.merkle_branch(epoch),
             blinded_sig: bare_sig.to_vec(),
-        }
+        })
     }
 
     fn merkle_branch(&self, idx: u16) -> Vec<blake3::Hash> {
@@ -110,12 +218,66 @@ impl SecretKey {
         PublicKey(self.merkle_tree.last().unwrap()[0])
     }
 
-    /// Gets an epoch key.
-    pub fn get_subkey(&self, epoch: u16) -> brs::SecretKey {
-        brs::SecretKey::from_der(&self.rsa_keys_der[epoch as usize]).unwrap()
+    /// Gets an epoch key. Seeded keys derive it on demand, caching the most recently used ones.
+    pub fn get_subkey(&self, epoch: u16) -> anyhow::Result<brs::SecretKey> {
+        let committed = self.merkle_tree[0]
+            .get(epoch as usize)
+            .with_context(|| format!("no subkey for epoch {epoch}"))?;
+        match &self.subkeys {
+            Subkeys::Stored(rsa_keys_der) => {
+                Ok(brs::SecretKey::from_der(&rsa_keys_der[epoch as usize])?)
+            }
+            Subkeys::Seeded { seed, cache } => {
+                if let Some(key) = cache.lock().unwrap().get(&epoch) {
+                    return Ok(key.clone());
+                }
+                let key = derive_subkey(seed, epoch);
+                // the derivation depends on the RSA implementation, so never hand out a key that the merkle tree does not commit to
+                if subkey_hash(&key) != *committed {
+                    anyhow::bail!("derived subkey for epoch {epoch} does not match the merkle tree")
+                }
+                cache.lock().unwrap().put(epoch, key.clone());
+                Ok(key)
+            }
+        }
     }
 }
 
+impl Subkeys {
+    fn seeded(seed: [u8; 32]) -> Self {
+        Self::Seeded {
+            seed,
+            cache: Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(SUBKEY_CACHE_SIZE).unwrap(),
+            ))),
+        }
+    }
+}
+
+/// Deterministically derives the RSA key of an epoch from a master seed.
+fn derive_subkey(seed: &[u8; 32], epoch: u16) -> brs::SecretKey {
+    let epoch_seed = blake3::keyed_hash(seed, &epoch.to_be_bytes());
+    let mut rng = ChaCha20Rng::from_seed(*epoch_seed.as_bytes());
+    brs::KeyPair::generate(&mut rng, KEY_BITS).unwrap().sk
+}
+
+fn subkey_hash(key: &brs::SecretKey) -> blake3::Hash {
+    blake3::hash(key.to_public_key().to_pkcs1_der().unwrap().as_bytes())
+}
+
+fn build_merkle_tree(leaves: Vec<blake3::Hash>) -> Vec<Vec<blake3::Hash>> {
+    let mut merkle_tree = vec![leaves];
+    while merkle_tree.last().unwrap().len() > 1 {
+        // "decimate" the merkle tree level to make the next
+        let last = merkle_tree.last().unwrap();
+        let new = (0..last.len() / 2)
+            .map(|i| blake3::keyed_hash(last[i * 2].as_bytes(), last[i * 2 + 1].as_bytes()))
+            .collect();
+        merkle_tree.push(new)
+    }
+    merkle_tree
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
 pub struct BlindedSignature {
     pub epoch: u16,
@@ -255,7 +417,78 @@ mod tests {
     #[test]
     fn test_generate_secret_key() {
         let secret_key = SecretKey::generate("test_generate_secret_key");
-        assert_eq!(secret_key.rsa_keys_der.len(), KEY_COUNT);
+        let Subkeys::Stored(rsa_keys_der) = &secret_key.subkeys else {
+            panic!("generated key should store its subkeys")
+        };
+        assert_eq!(rsa_keys_der.len(), KEY_COUNT);
+        let reloaded = SecretKey::from_bytes(&secret_key.to_bytes()).unwrap();
+        assert!(!reloaded.is_seeded());
+        assert_eq!(
+            reloaded.to_public_key().to_bytes(),
+            secret_key.to_public_key().to_bytes()
+        );
+    }
+
+    #[test]
+    fn test_seeded_secret_key() {
+        let seed = rand::random();
+        let secret_key = SecretKey::from_seed_with_count("test_seeded_secret_key", seed, 4);
+        let reloaded = SecretKey::from_bytes(&secret_key.to_bytes()).unwrap();
+        assert!(reloaded.is_seeded());
+        assert_eq!(
+            reloaded.to_public_key().to_bytes(),
+            secret_key.to_public_key().to_bytes()
+        );
+
+        let token = ClientToken::random();
+        let subkey = reloaded.get_subkey(2).unwrap().public_key().unwrap();
+        let (blinded_digest, secret) = token.blind(&subkey);
+        let unblinded = reloaded
+            .blind_sign(2, &blinded_digest)
+            .unwrap()
+            .unblind(&secret, token)
+            .unwrap();
+        secret_key
+            .to_public_key()
+            .blind_verify(token, &unblinded)
+            .unwrap();
+        // the truncated key has no later epochs
+        assert!(reloaded.get_subkey(4).is_err());
+    }
+
+    #[test]
+    fn test_legacy_secret_key() {
+        let rsa_keys: Vec<brs::SecretKey> = (0..2)
+            .map(|_| {
+                brs::KeyPair::generate(&mut rand::thread_rng(), KEY_BITS)
+                    .unwrap()
+                    .sk
+            })
+            .collect();
+        let legacy = stdcode::serialize(&LegacySecretKey {
+            merkle_tree: Arc::new(build_merkle_tree(
+                rsa_keys.iter().map(subkey_hash).collect(),
+            )),
+            rsa_keys_der: Arc::new(rsa_keys.iter().map(|k| k.to_der().unwrap()).collect()),
+        })
+        .unwrap();
+        let secret_key = SecretKey::from_bytes(&legacy).unwrap();
+        assert!(!secret_key.is_seeded());
+        // legacy keys are written back in their own format
+        assert_eq!(secret_key.to_bytes(), legacy);
+
+        let token = ClientToken::random();
+        let (blinded_digest, secret) =
+            token.blind(&secret_key.get_subkey(1).unwrap().public_key().unwrap());
+        let unblinded = secret_key
+            .blind_sign(1, &blinded_digest)
+            .unwrap()
+            .unblind(&secret, token)
+            .unwrap();
+        secret_key
+            .to_public_key()
+            .blind_verify(token, &unblinded)
+            .unwrap();
     }
 
     #[test]
@@ -263,8 +496,8 @@ mod tests {
         let secret_key = SecretKey::generate("test_blind_sign");
         let token = ClientToken::random();
         let (blinded_digest, _secret) =
-            token.blind(&secret_key.get_subkey(0).public_key().unwrap());
-        let blinded_signature = secret_key.blind_sign(0, &blinded_digest);
+            token.blind(&secret_key.get_subkey(0).unwrap().public_key().unwrap());
+        let blinded_signature = secret_key.blind_sign(0, &blinded_digest).unwrap();
 
         assert_eq!(blinded_signature.epoch, 0);
         assert_eq!(blinded_signature.blinded_sig.len(), KEY_BITS / 8);
@@ -1,21 +1,48 @@
-use async_executor::Executor;
+use std::sync::Arc;
+
 use async_trait::async_trait;
 use futures_util::{
     io::{AsyncWriteExt, BufReader},
-    AsyncBufReadExt, AsyncReadExt,
+    AsyncBufReadExt,
 };
 use nanorpc::{JrpcRequest, JrpcResponse, RpcService, RpcTransport};
 use sillad::{dialer::Dialer, listener::Listener};
-pub struct DialerTransport<D: Dialer>(pub D);
+
+mod pooled;
+pub use pooled::PooledTransport;
+mod server;
+pub use server::{LineAuth, LineMac, PresharedToken, RpcServer};
+
+/// A transport that dials a new connection for every request.
+pub struct DialerTransport<D: Dialer> {
+    dialer: D,
+    auth: Option<Arc<dyn LineAuth>>,
+}
+
+impl<D: Dialer> DialerTransport<D> {
+    pub fn new(dialer: D) -> Self {
+        Self { dialer, auth: None }
+    }
+
+    /// Creates a transport that seals every request with the given authenticator, for servers that require it.
+    pub fn new_authenticated(dialer: D, auth: impl LineAuth) -> Self {
+        Self {
+            dialer,
+            auth: Some(Arc::new(auth)),
+        }
+    }
+}
 
 #[async_trait]
 impl<D: Dialer> RpcTransport for DialerTransport<D> {
     type Error = anyhow::Error;
     async fn call_raw(&self, req: JrpcRequest) -> Result<JrpcResponse, Self::Error> {
-        // TODO some form of connection pooling. right now there's no pooling
-        let mut conn = self.0.dial().await?;
-        conn.write_all(format!("{}\n", serde_json::to_string(&req)?).as_bytes())
-            .await?;
+        let mut conn = self.dialer.dial().await?;
+        let mut line = serde_json::to_string(&req)?;
+        if let Some(auth) = &self.auth {
+            line = auth.seal(&line);
+        }
+        conn.write_all(format!("{line}\n").as_bytes()).await?;
         let mut conn = BufReader::new(conn);
         let mut line = String::new();
         conn.read_line(&mut line).await?;
@@ -23,34 +50,7 @@ impl<D: Dialer> RpcTransport for DialerTransport<D> {
     }
 }
 
-/// Runs a given nanorpc service using the given sillad listener
-pub async fn rpc_serve(
-    mut listener: impl Listener,
-    service: impl RpcService,
-) -> std::io::Result<()> {
-    let lexec = Executor::new();
-    lexec
-        .run(async {
-            loop {
-                let next = listener.accept().await?;
-                lexec
-                    .spawn::<anyhow::Result<()>>(async {
-                        let (read, mut write) = next.split();
-                        let mut read = BufReader::new(read);
-                        loop {
-                            let mut line = String::new();
-                            read.read_line(&mut line).await?;
-                            let req: JrpcRequest = serde_json::from_str(&line)?;
-                            let resp = service.respond_raw(req).await;
-                            write
-                                .write_all(
-                                    format!("{}\n", serde_json::to_string(&resp)?).as_bytes(),
-                                )
-                                .await?;
-                        }
-                    })
-                    .detach();
-            }
-        })
-        .await
+/// Runs a given nanorpc service using the given sillad listener, with the default limits of [RpcServer].
+pub async fn rpc_serve(listener: impl Listener, service: impl RpcService) -> std::io::Result<()> {
+    RpcServer::new(service).serve(listener).await
 }
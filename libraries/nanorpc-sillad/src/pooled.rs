@@ -0,0 +1,336 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use futures_util::{
+    AsyncBufReadExt, AsyncReadExt,
+    io::{AsyncWriteExt, BufReader, ReadHalf, WriteHalf},
+    lock::Mutex as AsyncMutex,
+};
+use nanorpc::{JrpcRequest, JrpcResponse, RpcTransport};
+use sillad::{Pipe, dialer::Dialer};
+use smol_timeout2::TimeoutExt;
+
+use crate::LineAuth;
+
+/// How many requests can be in flight on one connection before another connection is dialed.
+const MAX_INFLIGHT: usize = 16;
+
+/// The most connections that the pool dials. Past this, requests pile onto the least busy connection.
+const MAX_CONNECTIONS: usize = 4;
+
+/// Connections that have not responded for longer than this, and have no requests in flight, are closed instead of reused, since the other side may have silently dropped them.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Requests that get no response for this long fail, and their connection is no longer used.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<serde_json::Value>>>>;
+
+/// A transport that reuses connections, pipelining concurrent requests over them and matching responses to requests by their JSON-RPC ids. Connections that break are evicted from the pool.
+pub struct PooledTransport<D: Dialer> {
+    inner: Arc<PoolInner<D>>,
+}
+
+impl<D: Dialer> Clone for PooledTransport<D> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+struct PoolInner<D: Dialer> {
+    dialer: D,
+    auth: Option<Arc<dyn LineAuth>>,
+    conns: Mutex<Vec<Arc<PooledConn<D::P>>>>,
+    next_id: AtomicU64,
+}
+
+struct PooledConn<P: Pipe> {
+    write: AsyncMutex<WriteHalf<P>>,
+    pending: Pending,
+    alive: Arc<AtomicBool>,
+    /// When the connection was dialed or last responded.
+    last_response: Mutex<Instant>,
+    _reader: async_task::Task<()>,
+}
+
+impl<D: Dialer> PooledTransport<D> {
+    pub fn new(dialer: D) -> Self {
+        Self::build(dialer, None)
+    }
+
+    /// Creates a transport that seals every request with the given authenticator, for servers that require it.
+    pub fn new_authenticated(dialer: D, auth: impl LineAuth) -> Self {
+        Self::build(dialer, Some(Arc::new(auth)))
+    }
+
+    fn build(dialer: D, auth: Option<Arc<dyn LineAuth>>) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                dialer,
+                auth,
+                conns: Mutex::new(vec![]),
+                next_id: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Picks a connection for a new request, dialing a new one if the existing ones are too busy.
+    async fn connection(&self) -> std::io::Result<Arc<PooledConn<D::P>>> {
+        {
+            let mut conns = self.inner.conns.lock().unwrap();
+            conns.retain(|conn| {
+                conn.alive.load(Ordering::Relaxed)
+                    && (conn.inflight() > 0
+                        || conn.last_response.lock().unwrap().elapsed() < IDLE_TIMEOUT)
+            });
+            let usable = conns
+                .iter()
+                .min_by_key(|conn| conn.inflight())
+                .filter(|conn| conn.inflight() < MAX_INFLIGHT || conns.len() >= MAX_CONNECTIONS);
+            if let Some(conn) = usable {
+                return Ok(conn.clone());
+            }
+        }
+        let conn = Arc::new(PooledConn::new(self.inner.dialer.dial().await?));
+        self.inner.conns.lock().unwrap().push(conn.clone());
+        Ok(conn)
+    }
+}
+
+impl<P: Pipe> PooledConn<P> {
+    fn new(pipe: P) -> Self {
+        let (read, write) = pipe.split();
+        let pending: Pending = Default::default();
+        let alive = Arc::new(AtomicBool::new(true));
+        let reader = smolscale::spawn({
+            let pending = pending.clone();
+            let alive = alive.clone();
+            async move {
+                if let Err(err) = read_responses(read, &pending).await {
+                    tracing::debug!(err = debug(err), "pooled RPC connection broke");
+                }
+                alive.store(false, Ordering::Relaxed);
+                // fails every request still waiting on this connection
+                pending.lock().unwrap().clear();
+            }
+        });
+        Self {
+            write: AsyncMutex::new(write),
+            pending,
+            alive,
+            last_response: Mutex::new(Instant::now()),
+            _reader: reader,
+        }
+    }
+
+    fn inflight(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+}
+
+async fn read_responses(read: ReadHalf<impl Pipe>, pending: &Pending) -> anyhow::Result<()> {
+    let mut read = BufReader::new(read);
+    loop {
+        let mut line = String::new();
+        if read.read_line(&mut line).await? == 0 {
+            anyhow::bail!("connection closed")
+        }
+        let resp: serde_json::Value = serde_json::from_str(&line)?;
+        let id = resp
+            .get("id")
+            .and_then(|id| id.as_u64())
+            .context("response has an id we never sent")?;
+        if let Some(back) = pending.lock().unwrap().remove(&id) {
+            let _ = back.send(resp);
+        }
+    }
+}
+
+/// Forgets a request once its caller stops waiting, whether or not a response came.
+struct PendingGuard<'a> {
+    pending: &'a Pending,
+    id: u64,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[async_trait]
+impl<D: Dialer> RpcTransport for PooledTransport<D> {
+    type Error = anyhow::Error;
+    async fn call_raw(&self, req: JrpcRequest) -> Result<JrpcResponse, Self::Error> {
+        let conn = self.connection().await?;
+
+        // callers pick their ids independently, so requests sharing a connection get fresh ids that are unique to the pool
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut req = serde_json::to_value(&req)?;
+        let caller_id = std::mem::replace(&mut req["id"], id.into());
+
+        let (send, recv) = oneshot::channel();
+        conn.pending.lock().unwrap().insert(id, send);
+        let _guard = PendingGuard {
+            pending: &conn.pending,
+            id,
+        };
+        let mut line = serde_json::to_string(&req)?;
+        if let Some(auth) = &self.inner.auth {
+            line = auth.seal(&line);
+        }
+        line.push('\n');
+        if let Err(err) = conn.write.lock().await.write_all(line.as_bytes()).await {
+            conn.alive.store(false, Ordering::Relaxed);
+            return Err(err.into());
+        }
+
+        let Some(resp) = recv.timeout(REQUEST_TIMEOUT).await else {
+            // the other side may be gone without the connection breaking, so stop sending it requests
+            conn.alive.store(false, Ordering::Relaxed);
+            anyhow::bail!("request timed out")
+        };
+        let mut resp = resp.context("connection broke before responding")?;
+        *conn.last_response.lock().unwrap() = Instant::now();
+        resp["id"] = caller_id;
+        Ok(serde_json::from_value(resp)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use sillad::{
+        listener::Listener,
+        tcp::{TcpDialer, TcpListener},
+    };
+
+    use super::*;
+
+    /// Serves requests whose only parameter is how many milliseconds to wait before responding with that same number, concurrently and in whatever order they finish. A negative number gets a response, after which the connection closes.
+    async fn delay_server() -> (SocketAddr, Arc<AtomicU64>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().await;
+        let accepted = Arc::new(AtomicU64::new(0));
+        let accepted2 = accepted.clone();
+        smolscale::spawn(async move {
+            loop {
+                let conn = listener.accept().await.unwrap();
+                accepted2.fetch_add(1, Ordering::Relaxed);
+                smolscale::spawn(serve_delays(conn)).detach();
+            }
+        })
+        .detach();
+        (addr, accepted)
+    }
+
+    async fn serve_delays(conn: impl Pipe) -> anyhow::Result<()> {
+        let (read, write) = conn.split();
+        let write = Arc::new(AsyncMutex::new(write));
+        let mut read = BufReader::new(read);
+        loop {
+            let mut line = String::new();
+            if read.read_line(&mut line).await? == 0 {
+                return Ok(());
+            }
+            let req: serde_json::Value = serde_json::from_str(&line)?;
+            let delay = req["params"][0].as_i64().unwrap();
+            let resp = serde_json::json!({"jsonrpc": "2.0", "result": delay, "id": req["id"]});
+            if delay < 0 {
+                write
+                    .lock()
+                    .await
+                    .write_all(format!("{resp}\n").as_bytes())
+                    .await?;
+                return Ok(());
+            }
+            let write = write.clone();
+            smolscale::spawn(async move {
+                smol::Timer::after(Duration::from_millis(delay as u64)).await;
+                let _ = write
+                    .lock()
+                    .await
+                    .write_all(format!("{resp}\n").as_bytes())
+                    .await;
+            })
+            .detach();
+        }
+    }
+
+    async fn call(transport: &PooledTransport<TcpDialer>, id: i64, delay: i64) -> i64 {
+        let req = serde_json::from_value(serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "delay",
+            "params": [delay],
+            "id": id,
+        }))
+        .unwrap();
+        let resp = serde_json::to_value(transport.call_raw(req).await.unwrap()).unwrap();
+        assert_eq!(resp["id"], id);
+        resp["result"].as_i64().unwrap()
+    }
+
+    #[test]
+    fn test_pipelining() {
+        smolscale::block_on(async {
+            let (addr, accepted) = delay_server().await;
+            let transport = PooledTransport::new(TcpDialer { dest_addr: addr });
+            call(&transport, 1, 0).await;
+            // the slowest request goes first, so responses come back in the opposite order; callers reusing an id still get their own response
+            let results =
+                futures_util::future::join_all([300, 200, 100].map(|d| call(&transport, 7, d)))
+                    .await;
+            assert_eq!(results, [300, 200, 100]);
+            assert_eq!(accepted.load(Ordering::Relaxed), 1);
+        });
+    }
+
+    #[test]
+    fn test_inflight_cap() {
+        smolscale::block_on(async {
+            let (addr, accepted) = delay_server().await;
+            let transport = PooledTransport::new(TcpDialer { dest_addr: addr });
+            call(&transport, 1, 0).await;
+            let results = futures_util::future::join_all(
+                (0..MAX_INFLIGHT as i64 + 1).map(|i| call(&transport, i, 100 + i)),
+            )
+            .await;
+            assert!(
+                results
+                    .iter()
+                    .enumerate()
+                    .all(|(i, r)| *r == 100 + i as i64)
+            );
+            // only the request past the cap needed another connection
+            assert_eq!(accepted.load(Ordering::Relaxed), 2);
+            assert_eq!(transport.inner.conns.lock().unwrap().len(), 2);
+        });
+    }
+
+    #[test]
+    fn test_evict_after_eof() {
+        smolscale::block_on(async {
+            let (addr, accepted) = delay_server().await;
+            let transport = PooledTransport::new(TcpDialer { dest_addr: addr });
+            assert_eq!(call(&transport, 1, -1).await, -1);
+            // give the reader a moment to notice that the server closed the connection
+            smol::Timer::after(Duration::from_millis(100)).await;
+            assert_eq!(call(&transport, 2, 0).await, 0);
+            assert_eq!(accepted.load(Ordering::Relaxed), 2);
+            assert_eq!(transport.inner.conns.lock().unwrap().len(), 1);
+        });
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use async_executor::Executor;
+use futures_util::{
+    AsyncBufReadExt, AsyncReadExt, AsyncWrite,
+    io::{AsyncWriteExt, BufReader},
+};
+use nanorpc::{JrpcRequest, RpcService};
+use sillad::{Pipe, listener::Listener};
+use smol_timeout2::TimeoutExt;
+
+/// Requests longer than this are refused, unless the server is configured otherwise.
+const DEFAULT_MAX_LINE_LENGTH: usize = 1 << 20;
+
+/// Authenticates requests line by line. Clients seal every request line, while servers open every line and refuse those that do not open.
+pub trait LineAuth: Send + Sync + 'static {
+    /// Wraps a serialized request into an authenticated line, without the trailing newline.
+    fn seal(&self, json: &str) -> String;
+
+    /// Unwraps an authenticated line into the serialized request inside, or `None` if the line is not authentic.
+    fn open<'a>(&self, line: &'a str) -> Option<&'a str>;
+}
+
+/// Prefixes every request with a hash of a token that both sides know.
+pub struct PresharedToken(blake3::Hash);
+
+impl PresharedToken {
+    pub fn new(token: &str) -> Self {
+        Self(blake3::hash(token.as_bytes()))
+    }
+}
+
+impl LineAuth for PresharedToken {
+    fn seal(&self, json: &str) -> String {
+        format!("{} {json}", self.0.to_hex())
+    }
+
+    fn open<'a>(&self, line: &'a str) -> Option<&'a str> {
+        let (token, json) = line.split_once(' ')?;
+        // comparing hashes takes constant time
+        (blake3::Hash::from_hex(token).ok()? == self.0).then_some(json)
+    }
+}
+
+/// Prefixes every request with a MAC of the request under a key that both sides know. Unlike [PresharedToken], an eavesdropper cannot reuse what it saw to make requests of its own.
+pub struct LineMac([u8; 32]);
+
+impl LineMac {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+}
+
+impl LineAuth for LineMac {
+    fn seal(&self, json: &str) -> String {
+        format!(
+            "{} {json}",
+            blake3::keyed_hash(&self.0, json.as_bytes()).to_hex()
+        )
+    }
+
+    fn open<'a>(&self, line: &'a str) -> Option<&'a str> {
+        let (mac, json) = line.split_once(' ')?;
+        (blake3::Hash::from_hex(mac).ok()? == blake3::keyed_hash(&self.0, json.as_bytes()))
+            .then_some(json)
+    }
+}
+
+/// A nanorpc server over sillad pipes, with limits on what clients can make it do.
+pub struct RpcServer<S: RpcService> {
+    service: S,
+    max_line_length: usize,
+    idle_timeout: Option<Duration>,
+    max_connections: Option<usize>,
+    auth: Option<Arc<dyn LineAuth>>,
+}
+
+impl<S: RpcService> RpcServer<S> {
+    pub fn new(service: S) -> Self {
+        Self {
+            service,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            idle_timeout: None,
+            max_connections: None,
+            auth: None,
+        }
+    }
+
+    /// Refuses requests longer than this many bytes, closing their connections.
+    pub fn max_line_length(mut self, bytes: usize) -> Self {
+        self.max_line_length = bytes;
+        self
+    }
+
+    /// Closes connections that send no request for this long.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Drops new connections while this many are open.
+    pub fn max_connections(mut self, count: usize) -> Self {
+        self.max_connections = Some(count);
+        self
+    }
+
+    /// Only serves requests that the given authenticator accepts. A connection that sends anything else gets an error and is closed.
+    pub fn authenticator(mut self, auth: impl LineAuth) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// Serves connections from the listener until accepting fails.
+    pub async fn serve(self, mut listener: impl Listener) -> std::io::Result<()> {
+        // the executor's tasks borrow the count, so it must outlive the executor
+        let open_count = AtomicUsize::new(0);
+        let lexec = Executor::new();
+        let this = &self;
+        let open_count = &open_count;
+        lexec
+            .run(async {
+                loop {
+                    let next = listener.accept().await?;
+                    if this
+                        .max_connections
+                        .is_some_and(|max| open_count.load(Ordering::Relaxed) >= max)
+                    {
+                        tracing::debug!("too many RPC connections, dropping a new one");
+                        continue;
+                    }
+                    open_count.fetch_add(1, Ordering::Relaxed);
+                    lexec
+                        .spawn(async move {
+                            if let Err(err) = this.serve_one(next).await {
+                                tracing::debug!(err = debug(err), "RPC connection stopped");
+                            }
+                            open_count.fetch_sub(1, Ordering::Relaxed);
+                        })
+                        .detach();
+                }
+            })
+            .await
+    }
+
+    async fn serve_one(&self, conn: impl Pipe) -> anyhow::Result<()> {
+        let (read, mut write) = conn.split();
+        let mut read = BufReader::new(read);
+        loop {
+            let mut line = String::new();
+            let mut limited = (&mut read).take(self.max_line_length as u64 + 1);
+            let read_line = limited.read_line(&mut line);
+            let n = match self.idle_timeout {
+                Some(timeout) => read_line
+                    .timeout(timeout)
+                    .await
+                    .context("connection idle for too long")??,
+                None => read_line.await?,
+            };
+            if n == 0 {
+                return Ok(());
+            }
+            if !line.ends_with('\n') {
+                write_error(&mut write, -32600, "request too long").await?;
+                anyhow::bail!("request too long");
+            }
+            let line = line.trim_end();
+            let json = match &self.auth {
+                Some(auth) => auth.open(line),
+                None => Some(line),
+            };
+            let Some(json) = json else {
+                write_error(&mut write, -32600, "unauthenticated request").await?;
+                anyhow::bail!("unauthenticated request");
+            };
+            match serde_json::from_str::<JrpcRequest>(json) {
+                Ok(req) => {
+                    let resp = self.service.respond_raw(req).await;
+                    write
+                        .write_all(format!("{}\n", serde_json::to_string(&resp)?).as_bytes())
+                        .await?;
+                }
+                Err(err) => {
+                    write_error(&mut write, -32700, &format!("parse error: {err}")).await?;
+                }
+            }
+        }
+    }
+}
+
+/// Writes a JSON-RPC error that answers no request in particular.
+async fn write_error(
+    write: &mut (impl AsyncWrite + Unpin),
+    code: i64,
+    message: &str,
+) -> std::io::Result<()> {
+    let resp = serde_json::json!({
+        "jsonrpc": "2.0",
+        "error": {"code": code, "message": message},
+        "id": null,
+    });
+    write.write_all(format!("{resp}\n").as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use async_trait::async_trait;
+    use nanorpc::ServerError;
+    use sillad::{
+        dialer::Dialer,
+        tcp::{TcpDialer, TcpListener, TcpPipe},
+    };
+
+    use super::*;
+
+    struct Echo;
+
+    #[async_trait]
+    impl RpcService for Echo {
+        async fn respond(
+            &self,
+            _method: &str,
+            params: Vec<serde_json::Value>,
+        ) -> Option<Result<serde_json::Value, ServerError>> {
+            Some(Ok(params.into()))
+        }
+    }
+
+    async fn start(server: RpcServer<Echo>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().await;
+        smolscale::spawn(server.serve(listener)).detach();
+        addr
+    }
+
+    async fn connect(addr: SocketAddr) -> BufReader<TcpPipe> {
+        BufReader::new(TcpDialer { dest_addr: addr }.dial().await.unwrap())
+    }
+
+    /// Sends a line and reads the response, or `None` if the server closed the connection.
+    async fn send_line(conn: &mut BufReader<TcpPipe>, line: &str) -> Option<serde_json::Value> {
+        conn.write_all(format!("{line}\n").as_bytes()).await.ok()?;
+        read_response(conn).await
+    }
+
+    async fn read_response(conn: &mut BufReader<TcpPipe>) -> Option<serde_json::Value> {
+        let mut resp = String::new();
+        match conn.read_line(&mut resp).await {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(serde_json::from_str(&resp).unwrap()),
+        }
+    }
+
+    const REQUEST: &str = r#"{"jsonrpc":"2.0","method":"echo","params":[1],"id":1}"#;
+
+    #[test]
+    fn test_long_line() {
+        smolscale::block_on(async {
+            let addr = start(RpcServer::new(Echo).max_line_length(REQUEST.len())).await;
+            let mut conn = connect(addr).await;
+            assert_eq!(
+                send_line(&mut conn, REQUEST).await.unwrap()["result"],
+                serde_json::json!([1])
+            );
+            let long = REQUEST.replace("[1]", "[1, 2, 3]");
+            let resp = send_line(&mut conn, &long).await.unwrap();
+            assert_eq!(resp["error"]["code"], -32600);
+            assert!(read_response(&mut conn).await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_malformed_json() {
+        smolscale::block_on(async {
+            let addr = start(RpcServer::new(Echo)).await;
+            let mut conn = connect(addr).await;
+            let resp = send_line(&mut conn, "{not json").await.unwrap();
+            assert_eq!(resp["error"]["code"], -32700);
+            assert!(resp["id"].is_null());
+            // the connection stays usable
+            assert_eq!(
+                send_line(&mut conn, REQUEST).await.unwrap()["result"],
+                serde_json::json!([1])
+            );
+        });
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        smolscale::block_on(async {
+            let addr = start(RpcServer::new(Echo).idle_timeout(Duration::from_millis(100))).await;
+            let mut conn = connect(addr).await;
+            assert!(send_line(&mut conn, REQUEST).await.is_some());
+            smol::Timer::after(Duration::from_millis(300)).await;
+            assert!(send_line(&mut conn, REQUEST).await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_connection_cap() {
+        smolscale::block_on(async {
+            let addr = start(RpcServer::new(Echo).max_connections(1)).await;
+            let mut first = connect(addr).await;
+            assert!(send_line(&mut first, REQUEST).await.is_some());
+            let mut second = connect(addr).await;
+            assert!(send_line(&mut second, REQUEST).await.is_none());
+            // closing the first connection makes room again
+            drop(first);
+            smol::Timer::after(Duration::from_millis(100)).await;
+            let mut third = connect(addr).await;
+            assert!(send_line(&mut third, REQUEST).await.is_some());
+        });
+    }
+
+    #[test]
+    fn test_line_auth() {
+        let json = r#"{"jsonrpc":"2.0","method":"stop","params":[],"id":1}"#;
+
+        let token = PresharedToken::new("hunter2");
+        assert_eq!(token.open(&token.seal(json)), Some(json));
+        assert_eq!(PresharedToken::new("hunter3").open(&token.seal(json)), None);
+        assert_eq!(token.open(json), None);
+
+        let mac = LineMac::new([1; 32]);
+        assert_eq!(mac.open(&mac.seal(json)), Some(json));
+        assert_eq!(LineMac::new([2; 32]).open(&mac.seal(json)), None);
+        // a MAC only covers the request it was made for
+        let sealed = mac.seal(json);
+        let (tag, _) = sealed.split_once(' ').unwrap();
+        assert_eq!(mac.open(&format!("{tag} {{}}")), None);
+    }
+}
@@ -0,0 +1,7 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    picomux::fuzzing::run_mux(&picomux::fuzzing::structured_frames(data));
+});
@@ -36,11 +36,17 @@ impl Frame {
         }
     }
 
-    /// Read a frame from an async reader.
+    /// Read a frame from an async reader. Frames with an unknown command are rejected before their body is read.
     pub async fn read(mut rdr: impl AsyncRead + Unpin) -> std::io::Result<Self> {
         let mut header_buf = [0; std::mem::size_of::<Header>()];
         rdr.read_exact(&mut header_buf).await?;
         let header: Header = bytemuck::cast(header_buf);
+        if !is_known_command(header.command) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid command {}", header.command),
+            ));
+        }
         let len = header.body_len as usize;
         let mut body = vec![0; len];
         rdr.read_exact(&mut body).await?;
@@ -77,7 +83,53 @@ pub const CMD_MORE: u8 = 4;
 pub const CMD_PING: u8 = 0xa0;
 pub const CMD_PONG: u8 = 0xa1;
 
+fn is_known_command(command: u8) -> bool {
+    matches!(
+        command,
+        CMD_SYN | CMD_FIN | CMD_PSH | CMD_NOP | CMD_MORE | CMD_PING | CMD_PONG
+    )
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PingInfo {
     pub next_ping_in_ms: u32,
+    /// The maximum number of concurrent streams the sender lets its peer open. Absent means unlimited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_streams: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_round_trip() {
+        fastrand::seed(0x5eed_0001);
+        for _ in 0..1000 {
+            let body: Vec<u8> = (0..fastrand::usize(..2000))
+                .map(|_| fastrand::u8(..))
+                .collect();
+            let command = [
+                CMD_SYN, CMD_FIN, CMD_PSH, CMD_NOP, CMD_MORE, CMD_PING, CMD_PONG,
+            ][fastrand::usize(..7)];
+            let frame = Frame::new(fastrand::u32(..), command, &body);
+            let parsed = futures_lite::future::block_on(Frame::read(&frame.bytes()[..])).unwrap();
+            assert_eq!(parsed.header, frame.header);
+            assert_eq!(parsed.body, frame.body);
+        }
+    }
+
+    #[test]
+    fn frame_read_garbage() {
+        fastrand::seed(0x5eed_0002);
+        for _ in 0..10000 {
+            let garbage: Vec<u8> = (0..fastrand::usize(..64))
+                .map(|_| fastrand::u8(..))
+                .collect();
+            if let Ok(frame) = futures_lite::future::block_on(Frame::read(&garbage[..])) {
+                assert!(is_known_command(frame.header.command));
+                assert_eq!(frame.body.len(), frame.header.body_len as usize);
+            }
+        }
+    }
 }
@@ -0,0 +1,69 @@
+//! Entry points for the fuzz targets under `fuzz/`. Not part of the public API.
+
+use std::time::Duration;
+
+use futures_lite::FutureExt;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use smol_timeout2::TimeoutExt;
+
+use crate::{
+    PicoMux,
+    frame::{CMD_FIN, CMD_MORE, CMD_NOP, CMD_PING, CMD_PONG, CMD_PSH, CMD_SYN, Frame},
+};
+
+/// Parses frames out of arbitrary bytes until they run out or a frame is rejected.
+pub fn parse_frames(mut data: &[u8]) {
+    futures_lite::future::block_on(async move {
+        while let Ok(frame) = Frame::read(&mut data).await {
+            assert_eq!(frame.body.len(), frame.header.body_len as usize);
+            let _ = frame.bytes();
+        }
+    })
+}
+
+/// Turns arbitrary bytes into a sequence of well-formed frames on a handful of stream IDs, so that the mux state machine gets exercised beyond the frame parser.
+pub fn structured_frames(data: &[u8]) -> Vec<u8> {
+    const COMMANDS: [u8; 7] = [
+        CMD_SYN, CMD_FIN, CMD_PSH, CMD_NOP, CMD_MORE, CMD_PING, CMD_PONG,
+    ];
+    let mut out = vec![];
+    let mut data = data;
+    while let [command, stream_id, body_len, rest @ ..] = data {
+        let command = COMMANDS[*command as usize % COMMANDS.len()];
+        let body_len = (*body_len as usize).min(rest.len());
+        let (body, rest) = rest.split_at(body_len);
+        let body = match command {
+            CMD_PING if body.first().is_some_and(|b| b % 2 == 0) => {
+                format!("{{\"next_ping_in_ms\":{}}}", body.len()).into_bytes()
+            }
+            _ => body.to_vec(),
+        };
+        out.extend_from_slice(&Frame::new(*stream_id as u32 % 4, command, &body).bytes());
+        data = rest;
+    }
+    out
+}
+
+/// Feeds bytes to a mux as if they came from the peer, accepting and draining every stream that the peer opens.
+pub fn run_mux(data: &[u8]) {
+    let data = data.to_vec();
+    smolscale::block_on(async move {
+        let (mut peer_write, read) = bipe::bipe(65536);
+        let mux = PicoMux::new(read, futures_util::io::sink());
+        let feed = async {
+            let _ = peer_write.write_all(&data).await;
+            let _ = peer_write.flush().await;
+            futures_util::future::pending::<()>().await
+        };
+        let accept = async {
+            while let Ok(mut stream) = mux.accept().await {
+                smolscale::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    while stream.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+                })
+                .detach();
+            }
+        };
+        feed.race(accept).timeout(Duration::from_millis(100)).await;
+    })
+}
@@ -1,7 +1,12 @@
 mod bdp;
 mod buffer_table;
 mod frame;
+#[cfg(any(test, fuzzing))]
+#[doc(hidden)]
+pub mod fuzzing;
+mod limit;
 mod outgoing;
+mod resume;
 
 use std::{
     convert::Infallible,
@@ -25,11 +30,15 @@ use atomic_float::AtomicF64;
 use bdp::BwEstimate;
 use buffer_table::BufferTable;
 use bytes::Bytes;
+use dashmap::DashMap;
 use frame::{CMD_FIN, CMD_MORE, CMD_NOP, CMD_PING, CMD_PONG, CMD_PSH, CMD_SYN, Frame};
 use futures_lite::{Future, FutureExt as LiteExt};
 use futures_util::{
     AsyncRead, AsyncWrite, AsyncWriteExt, FutureExt, future::Shared, io::BufReader,
 };
+pub use limit::StreamLimitStats;
+use limit::{StreamLimits, StreamPermit};
+pub use resume::ResumeConfig;
 
 use async_io::Timer;
 use outgoing::Outgoing;
@@ -62,15 +71,19 @@ impl Default for LivenessConfig {
     }
 }
 
+type OpenReq = (Bytes, StreamPermit, oneshot::Sender<Stream>);
+
 pub struct PicoMux {
     task: Shared<Task<Arc<std::io::Result<Infallible>>>>,
-    send_open_req: Sender<(Bytes, oneshot::Sender<Stream>)>,
+    send_open_req: Sender<OpenReq>,
 
     recv_accepted: async_channel::Receiver<Stream>,
     send_liveness: async_channel::Sender<LivenessConfig>,
     liveness: LivenessConfig,
 
     last_ping: Arc<Mutex<Option<Duration>>>,
+    limits: Arc<StreamLimits>,
+    link: Option<resume::Link>,
 }
 
 impl PicoMux {
@@ -85,6 +98,7 @@ impl PicoMux {
         let liveness = LivenessConfig::default();
         send_liveness.try_send(liveness).unwrap();
         let last_ping = Arc::new(Mutex::new(None));
+        let limits = StreamLimits::new();
         let task = smolscale::spawn(
             picomux_inner(
                 read,
@@ -93,6 +107,7 @@ impl PicoMux {
                 recv_open_req,
                 recv_liveness,
                 last_ping.clone(),
+                limits.clone(),
             )
             .map(Arc::new),
         )
@@ -106,6 +121,44 @@ impl PicoMux {
             liveness,
 
             last_ping,
+            limits,
+            link: None,
+        }
+    }
+
+    /// Creates a new picomux whose session can survive the death of the underlying connection. Unacknowledged data is kept, so that a fresh connection given to [PicoMux::reattach] can pick up where the old one left off. The other side must also be resumable.
+    pub fn new_resumable(
+        read: impl AsyncRead + 'static + Send + Unpin,
+        write: impl AsyncWrite + Send + Unpin + 'static,
+        cfg: ResumeConfig,
+    ) -> Self {
+        let (link, link_read, link_write) = resume::Link::new(cfg);
+        link.attach(read, write).expect("fresh link cannot be dead");
+        let mut this = Self::new(link_read, link_write);
+        this.link = Some(link);
+        this
+    }
+
+    /// Moves a resumable session onto a fresh underlying connection, replacing the current one if it's still around.
+    pub fn reattach(
+        &self,
+        read: impl AsyncRead + 'static + Send + Unpin,
+        write: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> std::io::Result<()> {
+        match &self.link {
+            Some(link) => link.attach(read, write),
+            None => Err(std::io::Error::new(
+                ErrorKind::Unsupported,
+                "cannot reattach a non-resumable mux",
+            )),
+        }
+    }
+
+    /// Waits until a resumable session loses its underlying connection. Never returns for non-resumable sessions.
+    pub async fn wait_detached(&self) {
+        match &self.link {
+            Some(link) => link.wait_detached().await,
+            None => futures_util::future::pending().await,
         }
     }
 
@@ -125,6 +178,17 @@ impl PicoMux {
         let _ = self.send_liveness.try_send(liveness);
     }
 
+    /// Sets the maximum number of concurrent streams the peer may open, advertising it to the peer. Streams the peer opens beyond this limit are refused. `None` removes the limit.
+    pub fn set_max_concurrent_streams(&mut self, max: Option<u32>) {
+        self.limits.set_local_max(max);
+        let _ = self.send_liveness.try_send(self.liveness);
+    }
+
+    /// Returns how often the concurrent stream limits were hit in this session.
+    pub fn stream_limit_stats(&self) -> StreamLimitStats {
+        self.limits.stats()
+    }
+
     /// Accepts a new stream from the peer.
     pub async fn accept(&self) -> std::io::Result<Stream> {
         let err = self.wait_error();
@@ -144,8 +208,31 @@ impl PicoMux {
         *self.last_ping.lock()
     }
 
-    /// Opens a new stream to the peer, putting the given metadata in the stream.
+    /// Opens a new stream to the peer, putting the given metadata in the stream. If the peer's concurrent stream limit is reached, waits until one of our streams closes.
     pub async fn open(&self, metadata: &[u8]) -> std::io::Result<Stream> {
+        let permit = async { Ok(self.limits.open().await) }
+            .race(self.wait_error())
+            .await?;
+        self.open_with_permit(metadata, permit).await
+    }
+
+    /// Opens a new stream to the peer like [PicoMux::open], but fails immediately with [ErrorKind::WouldBlock] if the peer's concurrent stream limit is reached.
+    pub async fn try_open(&self, metadata: &[u8]) -> std::io::Result<Stream> {
+        let Some(permit) = self.limits.try_open() else {
+            self.limits.record_rejected();
+            return Err(std::io::Error::new(
+                ErrorKind::WouldBlock,
+                "peer's concurrent stream limit reached",
+            ));
+        };
+        self.open_with_permit(metadata, permit).await
+    }
+
+    async fn open_with_permit(
+        &self,
+        metadata: &[u8],
+        permit: StreamPermit,
+    ) -> std::io::Result<Stream> {
         {
             tracing::debug!("forcing a ping based on open");
             let _ = self.send_liveness.try_send(self.liveness);
@@ -153,7 +240,7 @@ impl PicoMux {
         let (send, recv) = oneshot::channel();
         let _ = self
             .send_open_req
-            .send((Bytes::copy_from_slice(metadata), send))
+            .send((Bytes::copy_from_slice(metadata), permit, send))
             .await;
         async {
             if let Ok(val) = recv.await {
@@ -185,9 +272,10 @@ async fn picomux_inner(
     read: impl AsyncRead + 'static + Send + Unpin,
     write: impl AsyncWrite + Send + Unpin + 'static,
     send_accepted: async_channel::Sender<Stream>,
-    mut recv_open_req: Receiver<(Bytes, oneshot::Sender<Stream>)>,
+    mut recv_open_req: Receiver<OpenReq>,
     recv_liveness: async_channel::Receiver<LivenessConfig>,
     last_ping: Arc<Mutex<Option<Duration>>>,
+    limits: Arc<StreamLimits>,
 ) -> Result<Infallible, std::io::Error> {
     let reaper = TaskReaper::new();
     let mut inner_read = BufReader::with_capacity(MSS * 4, read);
@@ -197,9 +285,12 @@ async fn picomux_inner(
     let buffer_table = BufferTable::new();
 
     let last_bw_estimate = Arc::new(AtomicF64::new(1_000_000.0));
+    // slots in the concurrent stream limits, released as soon as either side's FIN is seen
+    let permits: Arc<DashMap<u32, StreamPermit>> = Arc::new(DashMap::new());
 
-    let create_stream = |stream_id, metadata: Bytes| {
+    let create_stream = |stream_id, metadata: Bytes, permit: StreamPermit| {
         let mut buffer_recv = buffer_table.create_entry(stream_id);
+        permits.insert(stream_id, permit);
         let (mut write_incoming, read_incoming) = bipe::bipe(MSS * 2);
         let (write_outgoing, mut read_outgoing) = bipe::bipe(MSS * 2);
         let stream = Stream {
@@ -238,7 +329,8 @@ async fn picomux_inner(
                         .write_all(&frame.body)
                         .await
                         .context("could not write to incoming")?;
-                    remote_window -= 1;
+                    // a misbehaving peer may send past the window it was given
+                    remote_window = remote_window.saturating_sub(1);
 
                     // assume the delay is 500ms
                     let estimate = bw_estimate.read();
@@ -321,6 +413,7 @@ async fn picomux_inner(
 
         {
             let outgoing = outgoing.clone();
+            let permits = permits.clone();
             reaper.attach(smolscale::spawn(async move {
                 scopeguard::defer!({
                     tracing::debug!(stream_id, "enqueuing FIN to the other side");
@@ -333,6 +426,7 @@ async fn picomux_inner(
                         },
                         body: Bytes::new(),
                     });
+                    permits.remove(&stream_id);
                 });
                 let _: anyhow::Result<()> =
                     incoming_task.race(outgoing_task).await.inspect_err(|e| {
@@ -349,7 +443,7 @@ async fn picomux_inner(
     // receive open requests
     let open_req_loop = async {
         loop {
-            let (metadata, request) = recv_open_req.recv().await.map_err(|_e| {
+            let (metadata, permit, request) = recv_open_req.recv().await.map_err(|_e| {
                 std::io::Error::new(ErrorKind::BrokenPipe, "open request channel died")
             })?;
             let stream_id = {
@@ -362,7 +456,7 @@ async fn picomux_inner(
                 f.body = metadata.clone();
                 f.header.body_len = metadata.len() as _;
             }));
-            let stream = create_stream(stream_id, metadata);
+            let stream = create_stream(stream_id, metadata, permit);
 
             let _ = request.send(stream);
         }
@@ -386,6 +480,7 @@ async fn picomux_inner(
                 lc = Some(info);
                 let ping_body = serde_json::to_vec(&PingInfo {
                     next_ping_in_ms: info.ping_interval.as_millis() as _,
+                    max_streams: limits.local_max(),
                 })
                 .unwrap();
                 outgoing.enqueue(Frame {
@@ -432,7 +527,15 @@ async fn picomux_inner(
                                 "duplicate SYN",
                             ));
                         }
-                        let stream = create_stream(stream_id, frame.body.clone());
+                        let Some(permit) = limits.try_accept() else {
+                            tracing::debug!(
+                                stream_id,
+                                "refusing SYN over the concurrent stream limit"
+                            );
+                            outgoing.enqueue(Frame::new_empty(stream_id, CMD_FIN));
+                            continue;
+                        };
+                        let stream = create_stream(stream_id, frame.body.clone(), permit);
                         if let Err(err) = send_accepted.try_send(stream) {
                             match err {
                                 async_channel::TrySendError::Full(_) => {
@@ -461,6 +564,7 @@ async fn picomux_inner(
                     CMD_PSH | CMD_FIN => {
                         if frame.header.command == CMD_FIN {
                             tracing::debug!(stream_id, "FIN received");
+                            permits.remove(&stream_id);
                         }
                         buffer_table.send_to(stream_id, frame);
                     }
@@ -476,8 +580,10 @@ async fn picomux_inner(
                             })?;
                         tracing::debug!(
                             next_ping_in_ms = ping_info.next_ping_in_ms,
+                            max_streams = debug(ping_info.max_streams),
                             "responding to a PING"
                         );
+                        limits.set_remote_max(ping_info.max_streams);
 
                         outgoing.enqueue(Frame::new_empty(0, CMD_PONG))
                     }
@@ -633,4 +739,79 @@ mod tests {
             a_proc.race(b_proc).await
         })
     }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_stream_limit() {
+        smolscale::block_on(async move {
+            let (picomux_a, mut picomux_b) = setup_picomux_pair().await;
+            picomux_b.set_max_concurrent_streams(Some(1));
+            // let the advertisement reach the other side
+            Timer::after(Duration::from_millis(100)).await;
+
+            let first = picomux_a.open(b"").await.unwrap();
+            let _accepted = picomux_b.accept().await.unwrap();
+            let err = picomux_a.try_open(b"").await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::WouldBlock);
+            assert!(
+                picomux_a
+                    .open(b"")
+                    .timeout(Duration::from_millis(100))
+                    .await
+                    .is_none()
+            );
+
+            drop(first);
+            picomux_a
+                .open(b"")
+                .timeout(Duration::from_secs(1))
+                .await
+                .expect("open should proceed after a stream closes")
+                .unwrap();
+            let stats = picomux_a.stream_limit_stats();
+            assert_eq!(stats.open_rejected, 1);
+            assert!(stats.open_waited >= 1);
+        })
+    }
+
+    #[traced_test]
+    #[test]
+    fn test_picomux_resume() {
+        smolscale::block_on(async move {
+            let (a_write, b_read) = bipe::bipe(65536);
+            let (b_write, a_read) = bipe::bipe(65536);
+            let picomux_a = PicoMux::new_resumable(a_read, a_write, ResumeConfig::default());
+            let picomux_b = PicoMux::new_resumable(b_read, b_write, ResumeConfig::default());
+
+            let mut stream_a = picomux_a.open(b"").await.unwrap();
+            let mut stream_b = picomux_b.accept().await.unwrap();
+            stream_a.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            stream_b.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            // move the whole session onto a fresh pair of pipes
+            let (a_write, b_read) = bipe::bipe(65536);
+            let (b_write, a_read) = bipe::bipe(65536);
+            picomux_a.reattach(a_read, a_write).unwrap();
+            picomux_b.reattach(b_read, b_write).unwrap();
+
+            stream_a.write_all(b"world").await.unwrap();
+            stream_b.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+            assert!(picomux_a.is_alive() && picomux_b.is_alive());
+        })
+    }
+
+    #[test]
+    fn test_picomux_random_frames() {
+        fastrand::seed(0x5eed_0003);
+        for _ in 0..20 {
+            let data: Vec<u8> = (0..fastrand::usize(..4096))
+                .map(|_| fastrand::u8(..))
+                .collect();
+            fuzzing::run_mux(&fuzzing::structured_frames(&data));
+            fuzzing::run_mux(&data);
+        }
+    }
 }
@@ -0,0 +1,135 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+/// Bookkeeping for the concurrent stream limits of a mux. Each side advertises how many streams it lets the peer open at once, and respects the limit that the peer advertises.
+#[derive(Default)]
+pub struct StreamLimits {
+    local_max: AtomicU32,
+    remote_max: AtomicU32,
+
+    local_opened: AtomicU32,
+    remote_opened: AtomicU32,
+
+    open_waited: AtomicU64,
+    open_rejected: AtomicU64,
+    syn_refused: AtomicU64,
+
+    released: async_event::Event,
+}
+
+/// A snapshot of how often the concurrent stream limits of a mux were hit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StreamLimitStats {
+    /// How many times `open` had to wait for the peer's limit.
+    pub open_waited: u64,
+    /// How many times `try_open` failed because of the peer's limit.
+    pub open_rejected: u64,
+    /// How many incoming streams were refused because of our own limit.
+    pub syn_refused: u64,
+}
+
+impl StreamLimits {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            local_max: AtomicU32::new(u32::MAX),
+            remote_max: AtomicU32::new(u32::MAX),
+            ..Default::default()
+        })
+    }
+
+    /// The limit we advertise to the peer, if any.
+    pub fn local_max(&self) -> Option<u32> {
+        let max = self.local_max.load(Ordering::Relaxed);
+        (max != u32::MAX).then_some(max)
+    }
+
+    pub fn set_local_max(&self, max: Option<u32>) {
+        self.local_max
+            .store(max.unwrap_or(u32::MAX), Ordering::Relaxed);
+    }
+
+    /// Records the limit that the peer advertised to us.
+    pub fn set_remote_max(&self, max: Option<u32>) {
+        self.remote_max
+            .store(max.unwrap_or(u32::MAX), Ordering::Relaxed);
+        self.released.notify_all();
+    }
+
+    /// Reserves a slot for a locally opened stream, failing if the peer's limit is reached.
+    pub fn try_open(self: &Arc<Self>) -> Option<StreamPermit> {
+        let remote_max = self.remote_max.load(Ordering::Relaxed);
+        self.local_opened
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < remote_max).then_some(n + 1)
+            })
+            .ok()?;
+        Some(StreamPermit {
+            limits: self.clone(),
+            local: true,
+        })
+    }
+
+    /// Reserves a slot for a locally opened stream, waiting until the peer's limit allows it.
+    pub async fn open(self: &Arc<Self>) -> StreamPermit {
+        if let Some(permit) = self.try_open() {
+            return permit;
+        }
+        self.open_waited.fetch_add(1, Ordering::Relaxed);
+        tracing::debug!(
+            remote_max = self.remote_max.load(Ordering::Relaxed),
+            "waiting for the peer's concurrent stream limit"
+        );
+        self.released.wait_until(|| self.try_open()).await
+    }
+
+    /// Counts a `try_open` that failed because of the peer's limit.
+    pub fn record_rejected(&self) {
+        self.open_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Reserves a slot for a stream that the peer opened, failing if our own limit is reached.
+    pub fn try_accept(self: &Arc<Self>) -> Option<StreamPermit> {
+        let local_max = self.local_max.load(Ordering::Relaxed);
+        if self
+            .remote_opened
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < local_max).then_some(n + 1)
+            })
+            .is_err()
+        {
+            self.syn_refused.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(StreamPermit {
+            limits: self.clone(),
+            local: false,
+        })
+    }
+
+    pub fn stats(&self) -> StreamLimitStats {
+        StreamLimitStats {
+            open_waited: self.open_waited.load(Ordering::Relaxed),
+            open_rejected: self.open_rejected.load(Ordering::Relaxed),
+            syn_refused: self.syn_refused.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A slot in the concurrent stream limit, released when the stream dies.
+pub struct StreamPermit {
+    limits: Arc<StreamLimits>,
+    local: bool,
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        if self.local {
+            self.limits.local_opened.fetch_sub(1, Ordering::AcqRel);
+            self.limits.released.notify_all();
+        } else {
+            self.limits.remote_opened.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
@@ -0,0 +1,392 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use async_io::Timer;
+use async_task::Task;
+use bytes::Bytes;
+use futures_lite::FutureExt;
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use parking_lot::Mutex;
+use smol_timeout2::TimeoutExt;
+
+const RECORD_DATA: u8 = 0;
+const RECORD_ACK: u8 = 1;
+
+const MAX_RECORD: usize = 65536;
+const MAX_UNACKED: u64 = 4 * 1024 * 1024;
+
+type BoxRead = Box<dyn AsyncRead + Send + Unpin + 'static>;
+type BoxWrite = Box<dyn AsyncWrite + Send + Unpin + 'static>;
+
+/// Configuration for a resumable mux session.
+#[derive(Clone, Copy, Debug)]
+pub struct ResumeConfig {
+    /// How long the session survives without an underlying pipe before it dies.
+    pub resume_timeout: Duration,
+    /// How long an underlying pipe may stay silent before it's considered dead and detached.
+    pub idle_timeout: Duration,
+    /// How often acknowledgements are sent when nothing new was received, doubling as a heartbeat.
+    pub heartbeat_interval: Duration,
+}
+
+impl Default for ResumeConfig {
+    fn default() -> Self {
+        Self {
+            resume_timeout: Duration::from_secs(60),
+            idle_timeout: Duration::from_secs(15),
+            heartbeat_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A link that carries the bytes of a mux over a sequence of underlying pipes. Every byte written is kept until the other side acknowledges it, so that it can be replayed over the next pipe after the current one dies.
+pub struct Link {
+    send_attach: async_channel::Sender<(BoxRead, BoxWrite)>,
+    attached: Arc<AtomicBool>,
+    attach_changed: Arc<async_event::Event>,
+    _task: Task<()>,
+}
+
+impl Link {
+    /// Creates a new link, returning the link itself and the read/write halves that the mux should use.
+    pub fn new(cfg: ResumeConfig) -> (Self, bipe::BipeReader, bipe::BipeWriter) {
+        let (mux_write, local_read) = bipe::bipe(MAX_RECORD);
+        let (local_write, mux_read) = bipe::bipe(MAX_RECORD);
+        let (send_attach, recv_attach) = async_channel::unbounded();
+        let attached = Arc::new(AtomicBool::new(false));
+        let attach_changed = Arc::new(async_event::Event::new());
+        let task = smolscale::spawn({
+            let attached = attached.clone();
+            let attach_changed = attach_changed.clone();
+            async move {
+                if let Err(err) = link_loop(
+                    cfg,
+                    local_read,
+                    local_write,
+                    recv_attach,
+                    &attached,
+                    &attach_changed,
+                )
+                .await
+                {
+                    tracing::debug!(err = debug(err), "resumable link died");
+                }
+                attached.store(false, Ordering::SeqCst);
+                attach_changed.notify_all();
+            }
+        });
+        (
+            Self {
+                send_attach,
+                attached,
+                attach_changed,
+                _task: task,
+            },
+            mux_read,
+            mux_write,
+        )
+    }
+
+    /// Attaches a fresh underlying pipe, replacing the current one if there is any.
+    pub fn attach(
+        &self,
+        read: impl AsyncRead + Send + Unpin + 'static,
+        write: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> std::io::Result<()> {
+        self.send_attach
+            .try_send((Box::new(read), Box::new(write)))
+            .map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::BrokenPipe, "resumable link is dead")
+            })?;
+        // count as attached right away, so that waiting for detachment right after this does not return early
+        self.attached.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Waits until the link has no underlying pipe.
+    pub async fn wait_detached(&self) {
+        self.attach_changed
+            .wait_until(|| (!self.attached.load(Ordering::SeqCst)).then_some(()))
+            .await
+    }
+}
+
+async fn link_loop(
+    cfg: ResumeConfig,
+    mut local_read: bipe::BipeReader,
+    mut local_write: bipe::BipeWriter,
+    recv_attach: async_channel::Receiver<(BoxRead, BoxWrite)>,
+    attached: &AtomicBool,
+    attach_changed: &async_event::Event,
+) -> anyhow::Result<()> {
+    let state = Mutex::new(LinkState::default());
+    let acked = async_event::Event::new();
+    let mut next = None;
+    loop {
+        let (read, write) = match next.take() {
+            Some(pipe) => pipe,
+            None => recv_attach
+                .recv()
+                .timeout(cfg.resume_timeout)
+                .await
+                .context("timed out waiting for a new pipe")??,
+        };
+        attached.store(true, Ordering::SeqCst);
+        attach_changed.notify_all();
+        next = async {
+            run_attached(
+                cfg,
+                &state,
+                &acked,
+                &mut local_read,
+                &mut local_write,
+                read,
+                write,
+            )
+            .await?;
+            anyhow::Ok(None)
+        }
+        .race(async { anyhow::Ok(recv_attach.recv().await.ok()) })
+        .await?;
+        if next.is_none() {
+            attached.store(false, Ordering::SeqCst);
+            attach_changed.notify_all();
+        }
+        tracing::debug!(
+            replacement = next.is_some(),
+            "resumable link detached from its pipe"
+        );
+    }
+}
+
+#[derive(Default)]
+struct LinkState {
+    replay: ReplayBuffer,
+    recv_total: u64,
+}
+
+/// Runs the link over one underlying pipe. Returns `Ok` when the pipe dies, and an error only when the session itself cannot continue.
+async fn run_attached(
+    cfg: ResumeConfig,
+    state: &Mutex<LinkState>,
+    acked: &async_event::Event,
+    local_read: &mut bipe::BipeReader,
+    local_write: &mut bipe::BipeWriter,
+    mut read: BoxRead,
+    write: BoxWrite,
+) -> anyhow::Result<()> {
+    let write = async_lock::Mutex::new(write);
+
+    // exchange how much each side received, then replay what the other side missed
+    let my_recv = state.lock().recv_total;
+    let peer_recv = match async {
+        let mut write = write.lock().await;
+        write.write_all(&my_recv.to_be_bytes()).await?;
+        write.flush().await?;
+        drop(write);
+        let mut buf = [0u8; 8];
+        read.read_exact(&mut buf).await?;
+        std::io::Result::Ok(u64::from_be_bytes(buf))
+    }
+    .timeout(cfg.idle_timeout)
+    .await
+    {
+        Some(Ok(peer_recv)) => peer_recv,
+        _ => return Ok(()),
+    };
+    let to_replay = {
+        let mut state = state.lock();
+        state.replay.ack(peer_recv)?;
+        state.replay.chunks.iter().cloned().collect::<Vec<_>>()
+    };
+    acked.notify_all();
+    tracing::debug!(
+        my_recv,
+        peer_recv,
+        replayed = to_replay.len(),
+        "resumable link attached"
+    );
+    for chunk in to_replay {
+        if write_record(&write, RECORD_DATA, &chunk).await.is_err() {
+            return Ok(());
+        }
+    }
+
+    let up = async {
+        let mut buf = vec![0u8; MAX_RECORD];
+        loop {
+            acked
+                .wait_until(|| (state.lock().replay.unacked() < MAX_UNACKED).then_some(()))
+                .await;
+            let n = local_read.read(&mut buf).await?;
+            if n == 0 {
+                anyhow::bail!("mux closed its end of the link")
+            }
+            let chunk = Bytes::copy_from_slice(&buf[..n]);
+            state.lock().replay.push(chunk.clone());
+            if write_record(&write, RECORD_DATA, &chunk).await.is_err() {
+                return anyhow::Ok(());
+            }
+        }
+    };
+
+    let heartbeat = async {
+        let mut last_sent = (0, Instant::now());
+        loop {
+            Timer::after(Duration::from_secs(1)).await;
+            let recv_total = state.lock().recv_total;
+            if recv_total == last_sent.0 && last_sent.1.elapsed() < cfg.heartbeat_interval {
+                continue;
+            }
+            if write_record(&write, RECORD_ACK, &recv_total.to_be_bytes())
+                .await
+                .is_err()
+            {
+                return anyhow::Ok(());
+            }
+            last_sent = (recv_total, Instant::now());
+        }
+    };
+
+    let down = async {
+        loop {
+            let (kind, body) = match read_record(&mut read).timeout(cfg.idle_timeout).await {
+                Some(Ok(record)) => record,
+                Some(Err(err)) => {
+                    tracing::debug!(err = debug(err), "underlying pipe of resumable link failed");
+                    return anyhow::Ok(());
+                }
+                None => {
+                    tracing::debug!("underlying pipe of resumable link timed out");
+                    return Ok(());
+                }
+            };
+            match kind {
+                RECORD_DATA => {
+                    // count bytes as they are delivered, so that a cancelled write never double-counts
+                    let mut body = &body[..];
+                    while !body.is_empty() {
+                        let n = local_write.write(body).await?;
+                        state.lock().recv_total += n as u64;
+                        body = &body[n..];
+                    }
+                }
+                RECORD_ACK => {
+                    let acked_total = u64::from_be_bytes(
+                        (&body[..])
+                            .try_into()
+                            .ok()
+                            .context("corrupt acknowledgement")?,
+                    );
+                    state.lock().replay.ack(acked_total)?;
+                    acked.notify_all();
+                }
+                other => anyhow::bail!("invalid link record type {other}"),
+            }
+        }
+    };
+
+    up.race(heartbeat).race(down).await
+}
+
+async fn write_record(
+    write: &async_lock::Mutex<BoxWrite>,
+    kind: u8,
+    body: &[u8],
+) -> std::io::Result<()> {
+    let mut write = write.lock().await;
+    let mut header = [0u8; 5];
+    header[0] = kind;
+    header[1..].copy_from_slice(&(body.len() as u32).to_be_bytes());
+    write.write_all(&header).await?;
+    write.write_all(body).await?;
+    write.flush().await
+}
+
+async fn read_record(read: &mut BoxRead) -> std::io::Result<(u8, Bytes)> {
+    let mut header = [0u8; 5];
+    read.read_exact(&mut header).await?;
+    let len = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+    if len > MAX_RECORD {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "link record too long",
+        ));
+    }
+    let mut body = vec![0u8; len];
+    read.read_exact(&mut body).await?;
+    Ok((header[0], body.into()))
+}
+
+/// Bytes that were sent but not yet acknowledged by the other side.
+#[derive(Default)]
+struct ReplayBuffer {
+    chunks: VecDeque<Bytes>,
+    base: u64,
+    total: u64,
+}
+
+impl ReplayBuffer {
+    fn push(&mut self, chunk: Bytes) {
+        self.total += chunk.len() as u64;
+        self.chunks.push_back(chunk);
+    }
+
+    fn unacked(&self) -> u64 {
+        self.total - self.base
+    }
+
+    /// Discards everything up to the given cumulative acknowledgement.
+    fn ack(&mut self, acked: u64) -> anyhow::Result<()> {
+        if acked > self.total {
+            anyhow::bail!(
+                "peer acknowledged {acked} bytes, but only {} were sent",
+                self.total
+            )
+        }
+        if acked < self.base {
+            anyhow::bail!(
+                "peer needs data from byte {acked}, but it was already discarded up to {}",
+                self.base
+            )
+        }
+        while let Some(front) = self.chunks.front_mut() {
+            let end = self.base + front.len() as u64;
+            if end <= acked {
+                self.base = end;
+                self.chunks.pop_front();
+            } else {
+                let _ = front.split_to((acked - self.base) as usize);
+                self.base = acked;
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_buffer_acks() {
+        let mut buf = ReplayBuffer::default();
+        buf.push(Bytes::from_static(b"hello"));
+        buf.push(Bytes::from_static(b"world"));
+        buf.ack(3).unwrap();
+        assert_eq!(buf.unacked(), 7);
+        assert_eq!(&buf.chunks[0][..], b"lo");
+        buf.ack(10).unwrap();
+        assert!(buf.chunks.is_empty());
+        assert!(buf.ack(2).is_err());
+        assert!(buf.ack(11).is_err());
+    }
+}
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    sillad_sosistab3::fuzzing::decrypt_records(data);
+    sillad_sosistab3::fuzzing::round_trip_records(data);
+});
@@ -0,0 +1,69 @@
+//! Entry points for the fuzz targets under `fuzz/`. Not part of the public API.
+
+use std::collections::VecDeque;
+
+use crate::{
+    Cookie, ObfsParams,
+    handshake::{Handshake, MAX_PADDING_LEN},
+    state::State,
+};
+
+const SECRET: &[u8] = b"fuzzing shared secret";
+
+/// Decrypts records out of arbitrary bytes the way a `SosistabPipe` does, until more data would be needed or the stream turns out to be corrupt.
+pub fn decrypt_records(data: &[u8]) {
+    let mut state = State::new(SECRET, true, ObfsParams::default());
+    let mut raw = data.to_vec();
+    let mut plain = VecDeque::new();
+    while let Ok(n) = state.decrypt(&raw, &mut plain) {
+        raw.drain(..n);
+    }
+}
+
+/// Encrypts arbitrary bytes as records of varying sizes, then decrypts them from uneven pieces of ciphertext, checking that the plaintext survives.
+pub fn round_trip_records(data: &[u8]) {
+    let Some((&selector, data)) = data.split_first() else {
+        return;
+    };
+    let params = ObfsParams {
+        obfs_lengths: selector & 1 == 1,
+        obfs_timing: false,
+    };
+    let mut send = State::new(SECRET, false, params);
+    let mut recv = State::new(SECRET, true, params);
+
+    let mut ciphertext = vec![];
+    let mut rest = data;
+    while !rest.is_empty() {
+        let n = (rest[0] as usize + 1).min(rest.len());
+        send.encrypt(&rest[..n], &mut ciphertext);
+        rest = &rest[n..];
+    }
+
+    // feed the ciphertext in pieces, as the lower pipe would deliver it
+    let mut raw = vec![];
+    let mut plain = VecDeque::new();
+    for piece in ciphertext.chunks(selector as usize / 2 + 1) {
+        raw.extend_from_slice(piece);
+        while let Ok(n) = recv.decrypt(&raw, &mut plain) {
+            raw.drain(..n);
+        }
+    }
+    assert!(raw.is_empty());
+    assert_eq!(plain.make_contiguous(), data);
+}
+
+/// Decrypts an arbitrary handshake, and checks that any handshake survives an encryption round trip unless its padding is too long.
+pub fn decrypt_handshake(data: &[u8]) {
+    let mut buf = [0u8; 140];
+    let n = data.len().min(buf.len());
+    buf[..n].copy_from_slice(&data[..n]);
+    let cookie = Cookie::new("fuzzing");
+    let _ = Handshake::decrypt(buf, cookie, false);
+
+    let handshake = Handshake::from_bytes(arrayref::array_ref![buf, 0, 112]);
+    match Handshake::decrypt(handshake.encrypt(cookie, true), cookie, true) {
+        Ok(decrypted) => assert_eq!(decrypted, handshake),
+        Err(_) => assert!(handshake.padding_len > MAX_PADDING_LEN),
+    }
+}
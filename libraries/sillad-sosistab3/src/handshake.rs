@@ -7,6 +7,9 @@ use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
 
 use crate::Cookie;
 
+/// The largest padding a handshake may announce. Honest peers never send more than 1024 bytes.
+pub const MAX_PADDING_LEN: u64 = 65536;
+
 /// A initial handshake message, which must be encrypted with the cookie before being sent.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct Handshake {
@@ -32,7 +35,7 @@ impl Handshake {
         toret
     }
 
-    /// Decrypts a handshake, given the cookie.
+    /// Decrypts a handshake, given the cookie. Handshakes announcing an unreasonable amount of padding are rejected.
     pub fn decrypt(
         encrypted_handshake: [u8; 140],
         cookie: Cookie,
@@ -48,7 +51,14 @@ impl Handshake {
                 std::io::Error::new(std::io::ErrorKind::InvalidData, "Decryption failed")
             })?;
 
-        Ok(Handshake::from_bytes(array_ref![encrypted_data, 0, 112]))
+        let handshake = Handshake::from_bytes(array_ref![encrypted_data, 0, 112]);
+        if handshake.padding_len > MAX_PADDING_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "handshake padding too long",
+            ));
+        }
+        Ok(handshake)
     }
 
     /// Generates the bytes representation.
@@ -63,7 +73,7 @@ impl Handshake {
     }
 
     /// Creates a Handshake from bytes.
-    fn from_bytes(bytes: &[u8; 112]) -> Self {
+    pub(crate) fn from_bytes(bytes: &[u8; 112]) -> Self {
         let eph_pk = x25519_dalek::PublicKey::from(*array_ref![bytes, 0, 32]);
         let timestamp = u64::from_be_bytes(bytes[32..40].try_into().unwrap());
         let padding_len = u64::from_be_bytes(bytes[40..48].try_into().unwrap());
@@ -137,4 +147,26 @@ mod tests {
         assert_eq!(handshake.padding_len, handshake_from_bytes.padding_len);
         assert_eq!(handshake.padding_hash, handshake_from_bytes.padding_hash);
     }
+
+    #[test]
+    fn test_handshake_rejects_huge_padding() {
+        let handshake = Handshake {
+            eph_pk: x25519_dalek::PublicKey::from(&EphemeralSecret::random_from_rng(OsRng)),
+            timestamp: 123456789,
+            padding_len: u64::MAX,
+            padding_hash: blake3::hash(b""),
+            responding_to: blake3::hash(b""),
+        };
+        let cookie = Cookie::random();
+        assert!(Handshake::decrypt(handshake.encrypt(cookie, false), cookie, false).is_err());
+    }
+
+    #[test]
+    fn test_handshake_decrypt_garbage() {
+        let cookie = Cookie::random();
+        for _ in 0..1000 {
+            let garbage: [u8; 140] = std::array::from_fn(|_| rand::random());
+            assert!(Handshake::decrypt(garbage, cookie, true).is_err());
+        }
+    }
 }
@@ -14,6 +14,9 @@ use state::State;
 
 mod dedup;
 pub mod dialer;
+#[cfg(any(test, fuzzing))]
+#[doc(hidden)]
+pub mod fuzzing;
 mod handshake;
 pub mod listener;
 mod state;
@@ -204,7 +204,7 @@ impl State {
 
         // Append the decrypted body to the output
         if length > 0 {
-            output.write_all(&enc_body).unwrap();
+            output.write_all(&enc_body)?;
         }
         self.recv_nonce += 2;
         Ok(enc_length.len() + tag_length.len() + tag_body.len() + enc_body.len())
@@ -214,7 +214,10 @@ impl State {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rand::rngs::OsRng;
+    use rand::{
+        SeedableRng,
+        rngs::{OsRng, StdRng},
+    };
     use x25519_dalek::EphemeralSecret;
 
     #[test]
@@ -278,4 +281,14 @@ mod tests {
         assert_eq!(data1, decrypted_data1.as_slice());
         assert_eq!(data2, decrypted_data2.as_slice());
     }
+
+    #[test]
+    fn test_state_random_records() {
+        let mut rng = StdRng::seed_from_u64(0x5eed_0004);
+        for _ in 0..100 {
+            let data: Vec<u8> = (0..rng.gen_range(0..8192)).map(|_| rng.r#gen()).collect();
+            crate::fuzzing::round_trip_records(&data);
+            crate::fuzzing::decrypt_records(&data);
+        }
+    }
 }